@@ -0,0 +1,137 @@
+use crate::error::Result;
+use crate::node::leaf;
+use crate::node::Node;
+use crate::pager::Pager;
+use crate::storage::Storage;
+use crate::table::Table;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+/// How the pages on one level of the tree are used, the root being level 0.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct LevelReport {
+    pub internal_pages: u32,
+    pub leaf_pages: u32,
+    /// Keys in the internal nodes.
+    pub keys: u64,
+    /// Cells in the leaves.
+    pub cells: u64,
+    /// Bytes taken up by neither headers, cells nor checksums.
+    pub unused_bytes: u64,
+}
+
+impl LevelReport {
+    fn pages(&self) -> u32 {
+        self.internal_pages + self.leaf_pages
+    }
+}
+
+/// How full the pages of a database are.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SpaceReport {
+    pub page_size: usize,
+    pub page_count: u32,
+    pub file_size: u64,
+    pub free_pages: u32,
+    pub levels: Vec<LevelReport>,
+    /// Cells that fit in a leaf.
+    pub max_cells: u32,
+    /// Cells in the leaves as a fraction of how many would fit in them.
+    pub leaf_fill: f64,
+    /// Children per internal node, on average.
+    pub fan_out: f64,
+    /// Unused bytes per page of the tree, on average.
+    pub unused_bytes_per_page: f64,
+}
+
+impl Display for SpaceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "File: {} bytes, {} pages of {} bytes, {} free",
+            self.file_size, self.page_count, self.page_size, self.free_pages
+        )?;
+        for (level, report) in self.levels.iter().enumerate() {
+            writeln!(
+                f,
+                "Level {level}: {} internal ({} keys), {} leaf ({} cells), {:.1} unused bytes per page",
+                report.internal_pages,
+                report.keys,
+                report.leaf_pages,
+                report.cells,
+                report.unused_bytes as f64 / report.pages() as f64
+            )?;
+        }
+        writeln!(
+            f,
+            "Leaves: {:.1}% full on average, out of {} cells each",
+            self.leaf_fill * 100.0,
+            self.max_cells
+        )?;
+        writeln!(f, "Internal nodes: {:.1} children on average", self.fan_out)?;
+        write!(
+            f,
+            "Unused: {:.1} bytes per page on average",
+            self.unused_bytes_per_page
+        )
+    }
+}
+
+/// Walks the whole tree to find out how full its pages are.
+pub fn analyze<T: Storage>(table: &mut Table<T>) -> Result<SpaceReport> {
+    let pager = &mut table.pager;
+    let mut levels = Vec::new();
+    analyze_subtree(pager, table.root_page_num, 0, &mut levels)?;
+
+    let header = pager.header()?;
+    let max_cells = leaf::max_cells(pager.usable_size());
+    let sum = |field: fn(&LevelReport) -> u64| levels.iter().map(field).sum::<u64>();
+    let leaf_pages = sum(|level| level.leaf_pages as u64);
+    let internal_pages = sum(|level| level.internal_pages as u64);
+    let cells = sum(|level| level.cells);
+    let unused_bytes = sum(|level| level.unused_bytes);
+    Ok(SpaceReport {
+        page_size: header.page_size as usize,
+        page_count: pager.num_pages,
+        file_size: pager.file_size()?,
+        free_pages: header.freelist_count,
+        max_cells,
+        leaf_fill: cells as f64 / (leaf_pages * max_cells as u64) as f64,
+        // Every page but the root is the child of an internal node.
+        fan_out: match internal_pages {
+            0 => 0.0,
+            internal_pages => (leaf_pages + internal_pages - 1) as f64 / internal_pages as f64,
+        },
+        unused_bytes_per_page: unused_bytes as f64 / (leaf_pages + internal_pages) as f64,
+        levels,
+    })
+}
+
+fn analyze_subtree<T: Storage>(
+    pager: &mut Pager<T>,
+    page_num: u32,
+    level: usize,
+    levels: &mut Vec<LevelReport>,
+) -> Result<()> {
+    if levels.len() <= level {
+        levels.push(LevelReport::default());
+    }
+    match pager.page(page_num)? {
+        Node::Leaf(leaf) => {
+            let report = &mut levels[level];
+            report.leaf_pages += 1;
+            report.cells += leaf.num_cells() as u64;
+            report.unused_bytes += leaf.unused_bytes() as u64;
+        }
+        Node::Internal(internal) => {
+            let report = &mut levels[level];
+            report.internal_pages += 1;
+            report.keys += internal.num_keys() as u64;
+            report.unused_bytes += internal.unused_bytes() as u64;
+            for i in 0..=internal.num_keys() {
+                analyze_subtree(pager, internal.child(i)?, level + 1, levels)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Prints the pages of a database file as they're stored, decoded where they
+//! hold a header or a node, along with their raw bytes.
+//!
+//! Usage: `cstack-inspect <filename> [page num...]`, which prints every page
+//! if no page numbers are given.
+
+use cstack::error::{Error, Result};
+use cstack::node::Node;
+use cstack::pager::{
+    page_checksum, usable_size, DatabaseHeader, HEADER_PAGE_NUM, MAGIC, MIN_PAGE_SIZE,
+};
+use cstack::serialization::Row;
+use cstack::storage::{FileStorageFactory, Lock, Storage, StorageFactory};
+use libc::EXIT_FAILURE;
+use std::process::exit;
+use std::str::FromStr;
+
+/// Bytes shown on each line of the hex view.
+const HEX_LINE_LENGTH: usize = 16;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let filename = args
+        .next()
+        .expect("Must supply a database filename and optionally page numbers");
+    let page_nums: Vec<_> = args
+        .map(|arg| u32::from_str(&arg).expect("Page numbers must be non-negative integers"))
+        .collect();
+    if let Err(error) = inspect(&filename, &page_nums) {
+        println!("Error: {error}.");
+        exit(EXIT_FAILURE);
+    }
+}
+
+fn inspect(filename: &str, page_nums: &[u32]) -> Result<()> {
+    // Opening the storage would create the file.
+    std::fs::metadata(filename)?;
+    let mut storage = FileStorageFactory.open(filename)?;
+    // Keep writers from changing pages halfway through.
+    if !storage.lock(Lock::Shared)? {
+        return Err(Error::Busy);
+    }
+
+    if storage.size()? < MIN_PAGE_SIZE as u64 {
+        return Err(Error::NotADatabase);
+    }
+    let mut page = vec![0u8; MIN_PAGE_SIZE];
+    storage.read(HEADER_PAGE_NUM as usize, &mut page)?;
+    let header: DatabaseHeader = bincode::deserialize(&page).map_err(|_| Error::NotADatabase)?;
+    if header.magic != MAGIC {
+        return Err(Error::NotADatabase);
+    }
+    let page_size = header.page_size as usize;
+    if !page_size.is_power_of_two() || page_size < MIN_PAGE_SIZE {
+        return Err(Error::UnsupportedPageSize(header.page_size));
+    }
+
+    let num_pages = storage.size()?.div_ceil(page_size as u64) as u32;
+    let page_nums = match page_nums {
+        [] => (0..num_pages).collect(),
+        page_nums => page_nums.to_vec(),
+    };
+    for page_num in page_nums {
+        if page_num >= num_pages {
+            println!("Page {page_num} is past the end of the file, which has {num_pages} pages.");
+            continue;
+        }
+        let mut page = vec![0u8; page_size].into_boxed_slice();
+        storage.read(page_num as usize, &mut page)?;
+        print_page(page_num, page, header.page_count);
+        println!();
+    }
+    Ok(())
+}
+
+/// Prints a page of a database with `page_count` pages as of its last commit.
+fn print_page(page_num: u32, page: Box<[u8]>, page_count: u32) {
+    println!("Page {page_num}");
+    let usable_size = usable_size(page.len());
+    let stored = u32::from_le_bytes(page[usable_size..].try_into().unwrap());
+    let expected = page_checksum(page_num, &page);
+    if page.iter().all(|&byte| byte == 0) {
+        if page_num < page_count {
+            println!(
+                "  all zeros, though the header counts it as a page (expected {expected:#010x})"
+            );
+        } else {
+            println!("  all zeros, never written");
+        }
+        return;
+    } else if stored == expected {
+        println!("  checksum: {stored:#010x} (ok)");
+    } else {
+        println!("  checksum: {stored:#010x} (expected {expected:#010x})");
+    }
+
+    if page_num == HEADER_PAGE_NUM {
+        print_database_header(&page);
+    } else {
+        print_node(page_num, page.clone());
+    }
+    print_hex(&page);
+}
+
+fn print_database_header(page: &[u8]) {
+    let header: DatabaseHeader = match bincode::deserialize(page) {
+        Ok(header) => header,
+        Err(error) => {
+            println!("  header can't be decoded: {error}");
+            return;
+        }
+    };
+    let magic = String::from_utf8_lossy(&header.magic);
+    println!("  magic: {:?}", magic.trim_end_matches('\0'));
+    println!("  version: {}", header.version);
+    println!("  page size: {}", header.page_size);
+    println!("  page count: {}", header.page_count);
+    println!("  freelist trunk: {}", header.freelist_trunk);
+    println!("  freelist count: {}", header.freelist_count);
+    println!("  schema cookie: {}", header.schema_cookie);
+    println!("  change counter: {}", header.change_counter);
+}
+
+fn print_node(page_num: u32, page: Box<[u8]>) {
+    let node = match Node::from_page(page, page_num) {
+        Ok(node) => node,
+        Err(error) => {
+            println!("  not a node: {error}");
+            return;
+        }
+    };
+    let common = match &node {
+        Node::Internal(node) => &node.node,
+        Node::Leaf(node) => &node.node,
+    };
+    println!(
+        "  common header: type {:?}, root {}, parent {}",
+        common.node_type(),
+        common.is_root(),
+        common.parent()
+    );
+
+    match node {
+        Node::Leaf(leaf) => {
+            let num_cells = leaf.num_cells();
+            println!(
+                "  leaf header: {num_cells} of {} cells, next leaf {}",
+                leaf.max_cells(),
+                leaf.next_leaf()
+            );
+            // Cells past the ones that fit would be read from outside the page.
+            for cell_num in 0..num_cells.min(leaf.max_cells()) {
+                let key = leaf.key(cell_num);
+                match bincode::deserialize::<Row>(leaf.value(cell_num)) {
+                    Ok(row) => println!("  cell {cell_num}: key {key}, row {row}"),
+                    Err(error) => println!("  cell {cell_num}: key {key}, not a row: {error}"),
+                }
+            }
+        }
+        Node::Internal(internal) => {
+            let num_keys = internal.num_keys();
+            println!(
+                "  internal header: {num_keys} of {} keys, right child {}",
+                internal.max_keys(),
+                internal.right_child()
+            );
+            for key_num in 0..num_keys.min(internal.max_keys()) {
+                let key = internal.key(key_num);
+                match internal.child(key_num) {
+                    Ok(child) => println!("  cell {key_num}: child {child}, key {key}"),
+                    Err(error) => println!("  cell {key_num}: key {key}, bad child: {error}"),
+                }
+            }
+        }
+    }
+}
+
+/// Prints `page` as offsets, bytes in hex and printable bytes as text, with
+/// runs of identical lines shown as `*` the way `hexdump` does.
+fn print_hex(page: &[u8]) {
+    let mut previous_line = None;
+    let mut skipping = false;
+    for (i, line) in page.chunks(HEX_LINE_LENGTH).enumerate() {
+        if previous_line == Some(line) {
+            if !skipping {
+                println!("  *");
+                skipping = true;
+            }
+            continue;
+        }
+        previous_line = Some(line);
+        skipping = false;
+
+        let hex: Vec<_> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+        let text: String = line
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7e => byte as char,
+                _ => '.',
+            })
+            .collect();
+        println!(
+            "  {:04x}  {}  {}  |{text}|",
+            i * HEX_LINE_LENGTH,
+            hex[..HEX_LINE_LENGTH / 2].join(" "),
+            hex[HEX_LINE_LENGTH / 2..].join(" ")
+        );
+    }
+    // Show where the page ends after a run of identical lines.
+    if skipping {
+        println!("  {:04x}", page.len());
+    }
+}
@@ -0,0 +1,197 @@
+use std::cell::{Cell, UnsafeCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+/// A page held in memory by the buffer pool.
+///
+/// Every node handed out by the pager holds a reference to its frame, which pins
+/// the page in the pool until the node is dropped.
+pub struct Frame {
+    data: UnsafeCell<Box<[u8]>>,
+    dirty: Cell<bool>,
+    last_used: Cell<u64>,
+}
+
+impl Frame {
+    /// Holds `data`, which isn't modified yet.
+    pub fn new(data: Box<[u8]>) -> Frame {
+        Frame {
+            data: UnsafeCell::new(data),
+            dirty: Cell::new(false),
+            last_used: Cell::new(0),
+        }
+    }
+
+    /// Takes the page data back, so its memory can hold another page.
+    pub fn into_data(self) -> Box<[u8]> {
+        self.data.into_inner()
+    }
+
+    /// Gets a pointer to the page data.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        unsafe { (*self.data.get()).as_mut_ptr() }
+    }
+
+    /// Gets the page data as a slice.
+    pub fn data(&self) -> &[u8] {
+        unsafe { &*self.data.get() }
+    }
+
+    /// Gets whether the page was modified since it was last written to storage.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.get()
+    }
+
+    /// Sets whether the page was modified since it was last written to storage.
+    pub fn set_dirty(&self, dirty: bool) {
+        self.dirty.set(dirty);
+    }
+}
+
+impl Debug for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Frame")
+            .field("dirty", &self.dirty.get())
+            .field("last_used", &self.last_used.get())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Bounded cache of pages with least recently used eviction.
+pub struct BufferPool {
+    capacity: usize,
+    frames: HashMap<u32, Rc<Frame>>,
+    /// Page numbers ordered from least to most recently used.
+    recently_used: BTreeMap<u64, u32>,
+    clock: u64,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> BufferPool {
+        BufferPool {
+            capacity,
+            frames: HashMap::new(),
+            recently_used: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Gets the number of pages in the pool.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Gets whether another page can be added without evicting one first.
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Gets a cached page, marking it as the most recently used.
+    pub fn get(&mut self, page_num: u32) -> Option<Rc<Frame>> {
+        let frame = self.frames.get(&page_num)?.clone();
+        self.touch(page_num, &frame);
+        Some(frame)
+    }
+
+    /// Adds a page to the pool as the most recently used.
+    pub fn insert(&mut self, page_num: u32, data: Box<[u8]>) -> Rc<Frame> {
+        let frame = Rc::new(Frame::new(data));
+        self.touch(page_num, &frame);
+        if let Some(old) = self.frames.insert(page_num, frame.clone()) {
+            self.recently_used.remove(&old.last_used.get());
+        }
+        frame
+    }
+
+    fn touch(&mut self, page_num: u32, frame: &Frame) {
+        self.recently_used.remove(&frame.last_used.get());
+        self.clock += 1;
+        frame.last_used.set(self.clock);
+        self.recently_used.insert(self.clock, page_num);
+    }
+
+    /// Gets how many nodes currently reference the page.
+    pub fn pin_count(&self, page_num: u32) -> usize {
+        self.frames
+            .get(&page_num)
+            .map_or(0, |frame| Rc::strong_count(frame) - 1)
+    }
+
+    /// Removes the least recently used page that isn't pinned, returning it so a
+    /// dirty page can be written back. Returns `None` if every page is pinned.
+    pub fn evict(&mut self) -> Option<(u32, Rc<Frame>)> {
+        let (&last_used, &page_num) = self
+            .recently_used
+            .iter()
+            .find(|(_, &page_num)| self.pin_count(page_num) == 0)?;
+        self.recently_used.remove(&last_used);
+        let frame = self.frames.remove(&page_num).unwrap();
+        Some((page_num, frame))
+    }
+
+    /// Gets every dirty page in the pool.
+    pub fn dirty_pages(&self) -> impl Iterator<Item = (u32, &Rc<Frame>)> {
+        self.frames
+            .iter()
+            .filter(|(_, frame)| frame.is_dirty())
+            .map(|(&page_num, frame)| (page_num, frame))
+    }
+
+    /// Removes every page from the pool, dirty or not.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.recently_used.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(byte: u8) -> Box<[u8]> {
+        vec![byte; 8].into_boxed_slice()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut pool = BufferPool::new(3);
+        pool.insert(1, page(1));
+        pool.insert(2, page(2));
+        pool.insert(3, page(3));
+        assert!(pool.is_full());
+
+        pool.get(1);
+        let (page_num, frame) = pool.evict().unwrap();
+        assert_eq!(page_num, 2);
+        assert_eq!(frame.data(), page(2).as_ref());
+
+        let (page_num, _) = pool.evict().unwrap();
+        assert_eq!(page_num, 3);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn pinned_pages_are_not_evicted() {
+        let mut pool = BufferPool::new(2);
+        let pinned = pool.insert(1, page(1));
+        pool.insert(2, page(2));
+        assert_eq!(pool.pin_count(1), 1);
+        assert_eq!(pool.pin_count(2), 0);
+
+        assert_eq!(pool.evict().unwrap().0, 2);
+        assert!(pool.evict().is_none());
+
+        drop(pinned);
+        assert_eq!(pool.evict().unwrap().0, 1);
+    }
+
+    #[test]
+    fn dirty_pages() {
+        let mut pool = BufferPool::new(3);
+        pool.insert(1, page(1));
+        pool.insert(2, page(2)).set_dirty(true);
+        let dirty: Vec<_> = pool.dirty_pages().map(|(page_num, _)| page_num).collect();
+        assert_eq!(dirty, vec![2]);
+    }
+}
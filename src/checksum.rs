@@ -0,0 +1,10 @@
+/// Checksum of no bytes, which every checksum starts from.
+pub const INITIAL: u32 = 0x811c9dc5;
+
+/// Continues `checksum` over `bytes` using FNV-1a, so data split into several
+/// pieces can be checksummed one piece at a time.
+pub fn update(checksum: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(checksum, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
@@ -0,0 +1,766 @@
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::storage::{split_pages, Lock, Storage, StorageFactory};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Container Layout
+//
+// | header slot | header slot | extent | extent | ... |
+//
+// Every page is compressed on its own into an extent, which is a power of two
+// bytes long and aligned to its length so it can be read from the inner storage
+// as a single page. The index of which extent holds each page is kept in an
+// extent too, and the header says where it is.
+//
+// Free extents are handed out buddy style. A bigger one is split in halves
+// until it fits, and a freed extent is merged with its other half whenever that's
+// free too.
+//
+// Pages are never overwritten in place. A changed page goes into a new extent,
+// and the extent it replaces is only reused once a newer header has been synced,
+// so a crash leaves the container as it was at the last sync. Headers alternate
+// between the two slots, and the newest one whose checksum matches wins, unless
+// it was written without syncing and its index didn't make it to disk.
+const HEADER_SLOT_SIZE: usize = 64;
+const MIN_EXTENT_SIZE: usize = 64;
+
+/// Every container header starts with these bytes.
+const MAGIC: [u8; 8] = *b"cstackz\0";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ContainerHeader {
+    magic: [u8; 8],
+    /// Counts up with every header written, so the newer slot can be told apart.
+    sequence: u64,
+    page_size: u32,
+    /// Length of the uncompressed contents.
+    length: u64,
+    index: Extent,
+    index_checksum: u32,
+    /// Checksum of the header up to here.
+    checksum: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Extent {
+    offset: u64,
+    /// Number of compressed bytes stored in the extent.
+    length: u32,
+}
+
+impl Extent {
+    /// Gets how many bytes the extent takes up in the container.
+    fn size(&self) -> usize {
+        extent_size(self.length as usize)
+    }
+}
+
+fn extent_size(length: usize) -> usize {
+    length.next_power_of_two().max(MIN_EXTENT_SIZE)
+}
+
+/// Compresses `page` with PackBits. A control byte `n` is followed by `n + 1`
+/// bytes copied as they are if `n < 128`, or else by one byte that's repeated
+/// `257 - n` times.
+///
+/// Rows are padded with zeros to a fixed size, so pages are mostly long runs of
+/// zeros, which this shrinks to two bytes every 128.
+pub fn compress(page: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < page.len() {
+        let run = page[i..]
+            .iter()
+            .take(128)
+            .take_while(|&&byte| byte == page[i])
+            .count();
+        if run >= 3 {
+            push_literals(&mut compressed, &page[literal_start..i]);
+            compressed.push((257 - run) as u8);
+            compressed.push(page[i]);
+            literal_start = i + run;
+        }
+        i += run;
+    }
+    push_literals(&mut compressed, &page[literal_start..]);
+    compressed
+}
+
+fn push_literals(compressed: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(128) {
+        compressed.push((chunk.len() - 1) as u8);
+        compressed.extend_from_slice(chunk);
+    }
+}
+
+/// Decompresses what `compress` made of a page into `page`, which must be
+/// exactly filled.
+pub fn decompress(compressed: &[u8], page: &mut [u8]) -> Result<()> {
+    let mut i = 0;
+    let mut end = 0;
+    while i < compressed.len() {
+        let control = compressed[i] as usize;
+        i += 1;
+        let length = if control < 128 {
+            control + 1
+        } else {
+            257 - control
+        };
+        let target = page.get_mut(end..end + length).ok_or(Error::Corrupt)?;
+        if control < 128 {
+            let literals = compressed.get(i..i + length).ok_or(Error::Corrupt)?;
+            target.copy_from_slice(literals);
+            i += length;
+        } else {
+            target.fill(*compressed.get(i).ok_or(Error::Corrupt)?);
+            i += 1;
+        }
+        end += length;
+    }
+    if end != page.len() {
+        return Err(Error::Corrupt);
+    }
+    Ok(())
+}
+
+/// Opens storages from `inner` that keep every page compressed.
+pub struct CompressedStorageFactory<F> {
+    inner: F,
+}
+
+impl<F> CompressedStorageFactory<F> {
+    pub fn new(inner: F) -> Self {
+        CompressedStorageFactory { inner }
+    }
+}
+
+impl<S: Storage, F: StorageFactory<S>> StorageFactory<CompressedStorage<S>>
+    for CompressedStorageFactory<F>
+{
+    fn open(&mut self, filename: &str) -> Result<CompressedStorage<S>> {
+        let mut inner = self.inner.open(filename)?;
+        let container = Container::load(&mut inner)?;
+        Ok(CompressedStorage {
+            inner: Some(inner),
+            container: Some(container),
+            lock: Lock::Unlocked,
+        })
+    }
+
+    /// Journals and logs are looked at by every connection without a lock of
+    /// their own, so they're left as they are for all of them to see the same.
+    fn open_journal(&mut self, filename: &str) -> Result<CompressedStorage<S>> {
+        Ok(CompressedStorage {
+            inner: Some(self.inner.open_journal(filename)?),
+            container: None,
+            lock: Lock::Unlocked,
+        })
+    }
+}
+
+/// Storage that compresses each page on its own and keeps them in a container
+/// in `inner`, so the zeros that rows are padded with take up hardly any space.
+///
+/// Changes are only written to the container's index when synced or when the
+/// write lock is released, which is also when other connections can see them.
+pub struct CompressedStorage<S: Storage> {
+    /// Only `None` once the storage is being removed.
+    inner: Option<S>,
+    /// `None` for storages that are passed through without compressing.
+    container: Option<Container>,
+    lock: Lock,
+}
+
+impl<S: Storage> CompressedStorage<S> {
+    /// Gets the inner storage and the container, if there is one.
+    fn parts(&mut self) -> (&mut S, Option<&mut Container>) {
+        (self.inner.as_mut().unwrap(), self.container.as_mut())
+    }
+}
+
+impl<S: Storage> Storage for CompressedStorage<S> {
+    fn size(&mut self) -> Result<u64> {
+        match self.parts() {
+            (_, Some(container)) => Ok(container.length),
+            (inner, None) => inner.size(),
+        }
+    }
+
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+        match self.parts() {
+            (inner, Some(container)) => container.read(inner, (page_num * buf.len()) as u64, buf),
+            (inner, None) => inner.read(page_num, buf),
+        }
+    }
+
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+        match self.parts() {
+            (inner, Some(container)) => container.write(inner, (page_num * buf.len()) as u64, buf),
+            (inner, None) => inner.write(page_num, buf),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        match self.parts() {
+            (inner, Some(container)) => container.truncate(inner, size),
+            (inner, None) => inner.truncate(size),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        match self.parts() {
+            (inner, Some(container)) => container.flush(inner, true),
+            (inner, None) => inner.sync(),
+        }
+    }
+
+    fn remove(mut self) -> Result<()> {
+        // There's nothing left to flush once the storage is gone.
+        self.inner.take().unwrap().remove()
+    }
+
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        let held = self.lock;
+        let (inner, mut container) = self.parts();
+        if let Some(container) = &mut container {
+            // Other connections see the changes once they can take the lock.
+            if lock < Lock::Reserved && held >= Lock::Reserved {
+                container.flush(inner, false)?;
+            }
+        }
+        if !inner.lock(lock)? {
+            return Ok(false);
+        }
+        if let Some(container) = &mut container {
+            // Other connections may have changed the container while it was unlocked.
+            if held == Lock::Unlocked && lock > Lock::Unlocked {
+                container.refresh(inner)?;
+            }
+        }
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        self.parts().0.is_open_elsewhere()
+    }
+
+    fn create_temporary(&self) -> Result<CompressedStorage<S>> {
+        Ok(CompressedStorage {
+            inner: Some(self.inner.as_ref().unwrap().create_temporary()?),
+            container: self.container.as_ref().map(|_| Container::new()),
+            lock: Lock::Unlocked,
+        })
+    }
+}
+
+impl<S: Storage> Drop for CompressedStorage<S> {
+    fn drop(&mut self) {
+        if let (Some(inner), Some(container)) = (&mut self.inner, &mut self.container) {
+            let _ = container.flush(inner, false);
+        }
+    }
+}
+
+/// What's in a container, as of the last header read or written plus the
+/// changes since.
+#[derive(Default)]
+struct Container {
+    /// Size of the pages compressed on their own, taken from the first write.
+    page_size: usize,
+    length: u64,
+    /// Extent holding each page, or `None` for pages of zeros.
+    index: Vec<Option<Extent>>,
+    /// Extent holding the index as of the last header.
+    index_extent: Option<Extent>,
+    sequence: u64,
+    /// End of the last extent, where new extents are added.
+    end: u64,
+    /// Offsets of the extents free for reuse, by extent size.
+    free: BTreeMap<usize, BTreeSet<u64>>,
+    /// Extents replaced since the last synced header, which it may still point to.
+    replaced: Vec<Extent>,
+    dirty: bool,
+}
+
+impl Container {
+    fn new() -> Container {
+        Container {
+            end: 2 * HEADER_SLOT_SIZE as u64,
+            ..Container::default()
+        }
+    }
+
+    /// Loads the container from the newest header in `inner` whose index checks
+    /// out. A header written without syncing may have made it to disk before its
+    /// index did, which leaves the header before it in use.
+    fn load<S: Storage>(inner: &mut S) -> Result<Container> {
+        let mut container = Container::new();
+        let mut headers = headers(inner)?.into_iter();
+        let Some(mut header) = headers.next() else {
+            return Ok(container);
+        };
+        container.index = loop {
+            match read_index(inner, &header) {
+                Err(Error::Corrupt) => match headers.next() {
+                    Some(older) => header = older,
+                    // Before the first header, the container was empty.
+                    None if header.sequence == 1 => return Ok(container),
+                    None => return Err(Error::Corrupt),
+                },
+                result => break result?,
+            }
+        };
+        container.page_size = header.page_size as usize;
+        container.length = header.length;
+        container.index_extent = Some(header.index);
+        container.sequence = header.sequence;
+
+        // Everything between the extents in use is free.
+        let mut extents: Vec<_> = container.index.iter().flatten().copied().collect();
+        extents.push(header.index);
+        extents.sort_by_key(|extent| extent.offset);
+        for extent in extents {
+            container.grow_to(extent.offset);
+            container.end = container.end.max(extent.offset + extent.size() as u64);
+        }
+        Ok(container)
+    }
+
+    /// Reloads the container if another connection wrote a newer header.
+    fn refresh<S: Storage>(&mut self, inner: &mut S) -> Result<()> {
+        let sequence = headers(inner)?.first().map_or(0, |header| header.sequence);
+        if sequence != self.sequence {
+            *self = Container::load(inner)?;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes of the uncompressed contents from `offset`.
+    fn read<S: Storage>(&mut self, inner: &mut S, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if offset + buf.len() as u64 > self.length {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if self.page_size == 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+        let mut page = vec![0u8; self.page_size];
+        for (page_num, range, part) in split_pages(offset, buf.len(), self.page_size) {
+            self.read_page(inner, page_num, &mut page)?;
+            buf[part].copy_from_slice(&page[range]);
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` into the uncompressed contents at `offset`.
+    fn write<S: Storage>(&mut self, inner: &mut S, offset: u64, buf: &[u8]) -> Result<()> {
+        if self.page_size == 0 {
+            self.page_size = buf.len();
+        }
+        let mut page = vec![0u8; self.page_size];
+        for (page_num, range, part) in split_pages(offset, buf.len(), self.page_size) {
+            if range.len() < self.page_size {
+                self.read_page(inner, page_num, &mut page)?;
+            }
+            page[range].copy_from_slice(&buf[part]);
+            self.write_page(inner, page_num, &page)?;
+        }
+        self.length = self.length.max(offset + buf.len() as u64);
+        Ok(())
+    }
+
+    fn truncate<S: Storage>(&mut self, inner: &mut S, size: u64) -> Result<()> {
+        if size < self.length && self.page_size > 0 {
+            let num_pages = size.div_ceil(self.page_size as u64) as usize;
+            while self.index.len() > num_pages {
+                if let Some(extent) = self.index.pop().flatten() {
+                    self.replaced.push(extent);
+                }
+            }
+            // Whatever's past the end has to be zeros if the contents grow again.
+            let tail = size as usize % self.page_size;
+            if tail > 0 {
+                let mut page = vec![0u8; self.page_size];
+                self.read_page(inner, num_pages - 1, &mut page)?;
+                page[tail..].fill(0);
+                self.write_page(inner, num_pages - 1, &page)?;
+            }
+        }
+        self.length = size;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn read_page<S: Storage>(&self, inner: &mut S, page_num: usize, page: &mut [u8]) -> Result<()> {
+        let Some(extent) = self.index.get(page_num).copied().flatten() else {
+            page.fill(0);
+            return Ok(());
+        };
+        let mut buf = vec![0u8; extent.size()];
+        let compressed = inner.page(extent.offset as usize / buf.len(), &mut buf)?;
+        decompress(&compressed[..extent.length as usize], page)
+    }
+
+    fn write_page<S: Storage>(
+        &mut self,
+        inner: &mut S,
+        page_num: usize,
+        page: &[u8],
+    ) -> Result<()> {
+        let extent = if page.iter().all(|&byte| byte == 0) {
+            None
+        } else {
+            let compressed = compress(page);
+            Some(self.write_extent(inner, &compressed)?)
+        };
+        if self.index.len() <= page_num {
+            self.index.resize(page_num + 1, None);
+        }
+        if let Some(replaced) = std::mem::replace(&mut self.index[page_num], extent) {
+            self.replaced.push(replaced);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Writes `bytes` into a newly allocated extent.
+    fn write_extent<S: Storage>(&mut self, inner: &mut S, bytes: &[u8]) -> Result<Extent> {
+        let size = extent_size(bytes.len());
+        let free = self
+            .free
+            .range_mut(size..)
+            .find_map(|(&free_size, offsets)| Some((free_size, offsets.pop_first()?)));
+        let offset = match free {
+            Some((mut free_size, offset)) => {
+                while free_size > size {
+                    free_size /= 2;
+                    self.release(offset + free_size as u64, free_size);
+                }
+                offset
+            }
+            None => {
+                self.grow_to(self.end.next_multiple_of(size as u64));
+                self.end += size as u64;
+                self.end - size as u64
+            }
+        };
+        let mut buf = vec![0u8; size];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        inner.write(offset as usize / size, &buf)?;
+        Ok(Extent {
+            offset,
+            length: bytes.len() as u32,
+        })
+    }
+
+    /// Moves the end up to `offset`, freeing the gap in the largest aligned
+    /// extents that fit.
+    fn grow_to(&mut self, offset: u64) {
+        while self.end < offset {
+            let mut size = 1 << self.end.trailing_zeros();
+            while self.end + size > offset {
+                size /= 2;
+            }
+            self.release(self.end, size as usize);
+            self.end += size;
+        }
+    }
+
+    /// Frees the extent of `size` bytes at `offset`, merged with its buddies.
+    fn release(&mut self, mut offset: u64, mut size: usize) {
+        while self
+            .free
+            .get_mut(&size)
+            .is_some_and(|offsets| offsets.remove(&(offset ^ size as u64)))
+        {
+            offset &= !(size as u64);
+            size *= 2;
+        }
+        self.free.entry(size).or_default().insert(offset);
+    }
+
+    /// Writes the index and a header pointing to it. Syncing the index before
+    /// the header, and the header before replaced extents are reused, is what
+    /// makes changes atomic.
+    fn flush<S: Storage>(&mut self, inner: &mut S, sync: bool) -> Result<()> {
+        if !self.dirty {
+            if sync {
+                inner.sync()?;
+                self.release_replaced();
+            }
+            return Ok(());
+        }
+
+        let index = bincode::serialize(&self.index).unwrap();
+        let index_extent = self.write_extent(inner, &index)?;
+        if sync {
+            inner.sync()?;
+        }
+        let mut header = ContainerHeader {
+            magic: MAGIC,
+            sequence: self.sequence + 1,
+            page_size: self.page_size as u32,
+            length: self.length,
+            index: index_extent,
+            index_checksum: checksum::update(checksum::INITIAL, &index),
+            checksum: 0,
+        };
+        header.checksum = header_checksum(&header);
+        let mut slot = [0u8; HEADER_SLOT_SIZE];
+        bincode::serialize_into(slot.as_mut_slice(), &header).unwrap();
+        inner.write(header.sequence as usize % 2, &slot)?;
+        if sync {
+            inner.sync()?;
+        }
+
+        self.sequence = header.sequence;
+        self.replaced
+            .extend(self.index_extent.replace(index_extent));
+        if sync {
+            self.release_replaced();
+        }
+        self.dirty = false;
+        self.shrink(inner)
+    }
+
+    /// Frees the replaced extents once the newest header is synced, since until
+    /// then a crash can leave an older header that points to them.
+    fn release_replaced(&mut self) {
+        for extent in std::mem::take(&mut self.replaced) {
+            self.release(extent.offset, extent.size());
+        }
+    }
+
+    /// Gives back the free extents at the end of the container.
+    fn shrink<S: Storage>(&mut self, inner: &mut S) -> Result<()> {
+        let end = &mut self.end;
+        while let Some(size) = self.free.iter_mut().find_map(|(&size, offsets)| {
+            let offset = end.checked_sub(size as u64)?;
+            offsets.remove(&offset).then_some(size)
+        }) {
+            *end -= size as u64;
+        }
+        if inner.size()? > self.end {
+            inner.truncate(self.end)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the headers whose checksum matches, newest first.
+fn headers<S: Storage>(inner: &mut S) -> Result<Vec<ContainerHeader>> {
+    let num_slots = (inner.size()? / HEADER_SLOT_SIZE as u64).min(2) as usize;
+    let mut headers = Vec::new();
+    for slot_num in 0..num_slots {
+        let mut slot = [0u8; HEADER_SLOT_SIZE];
+        inner.read(slot_num, &mut slot)?;
+        // A slot of zeros, or with a header that was torn, was never synced.
+        if slot.iter().all(|&byte| byte == 0) || slot[..MAGIC.len()] == MAGIC {
+            let header: ContainerHeader = bincode::deserialize(&slot).unwrap();
+            if header.checksum == header_checksum(&header) && header.magic == MAGIC {
+                headers.push(header);
+            }
+        } else {
+            return Err(Error::NotADatabase);
+        }
+    }
+    headers.sort_by_key(|header| std::cmp::Reverse(header.sequence));
+    Ok(headers)
+}
+
+/// Reads the index that `header` points to.
+fn read_index<S: Storage>(inner: &mut S, header: &ContainerHeader) -> Result<Vec<Option<Extent>>> {
+    let mut buf = vec![0u8; header.index.size()];
+    let index = inner.page(header.index.offset as usize / buf.len(), &mut buf)?;
+    let index = index
+        .get(..header.index.length as usize)
+        .ok_or(Error::Corrupt)?;
+    if checksum::update(checksum::INITIAL, index) != header.index_checksum {
+        return Err(Error::Corrupt);
+    }
+    bincode::deserialize(index).map_err(|_| Error::Corrupt)
+}
+
+fn header_checksum(header: &ContainerHeader) -> u32 {
+    let bytes = bincode::serialize(header).unwrap();
+    let length = bytes.len() - std::mem::size_of::<u32>();
+    checksum::update(checksum::INITIAL, &bytes[..length])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Fault, FaultyStorage, FaultyStorageFactory, SharedMemoryStorageFactory};
+
+    const PAGE_SIZE: usize = 4096;
+
+    /// A page that's mostly zeros, like one holding a few rows.
+    fn sparse_page(seed: u8) -> Vec<u8> {
+        let mut page = vec![0u8; PAGE_SIZE];
+        for (i, byte) in page.iter_mut().enumerate().step_by(300) {
+            *byte = seed.wrapping_add(i as u8);
+        }
+        page[1000..1010].copy_from_slice(b"cstack1234");
+        page
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let pages = [
+            vec![0u8; PAGE_SIZE],
+            sparse_page(1),
+            (0..PAGE_SIZE).map(|i| (i * 7 % 251) as u8).collect(),
+            [vec![5u8; 129], vec![1, 2, 2, 3, 3, 3], vec![9u8; 300]].concat(),
+            vec![],
+        ];
+        for page in pages {
+            let compressed = compress(&page);
+            let mut decompressed = vec![1u8; page.len()];
+            decompress(&compressed, &mut decompressed).unwrap();
+            assert_eq!(decompressed, page);
+        }
+        assert_eq!(compress(&[0u8; PAGE_SIZE]).len(), 2 * PAGE_SIZE / 128);
+        assert!(compress(&sparse_page(1)).len() < PAGE_SIZE / 8);
+    }
+
+    #[test]
+    fn decompress_rejects_damaged_pages() {
+        let compressed = compress(&sparse_page(1));
+        let mut page = vec![0u8; PAGE_SIZE];
+        for damaged in [&compressed[..compressed.len() - 1], &[200, 0], &[3, 1]] {
+            assert!(matches!(
+                decompress(damaged, &mut page),
+                Err(Error::Corrupt)
+            ));
+        }
+    }
+
+    #[test]
+    fn container_survives_reopen_and_shrinks() {
+        let mut factory = CompressedStorageFactory::new(SharedMemoryStorageFactory::new());
+        {
+            let mut storage = factory.open("foobar").unwrap();
+            for page_num in 0..100 {
+                storage
+                    .write(page_num, &sparse_page(page_num as u8))
+                    .unwrap();
+            }
+            storage.write(3, &sparse_page(200)).unwrap();
+            storage.sync().unwrap();
+            // Reads that don't line up with pages
+            let mut buf = [0u8; 512];
+            storage.read(3 * 8 + 3, &mut buf).unwrap();
+            assert_eq!(buf, sparse_page(200)[1536..2048]);
+
+            let mut buf = [0u8; 64];
+            storage.write(81 * 64 + 1, &[7u8; 64]).unwrap();
+            storage.read(81 * 64 + 1, &mut buf).unwrap();
+            assert_eq!(buf, [7u8; 64]);
+            storage.truncate(50 * PAGE_SIZE as u64).unwrap();
+        }
+
+        let mut storage = factory.open("foobar").unwrap();
+        assert_eq!(storage.size().unwrap(), 50 * PAGE_SIZE as u64);
+        let mut page = vec![0u8; PAGE_SIZE];
+        for page_num in 0..50 {
+            storage.read(page_num, &mut page).unwrap();
+            let seed = if page_num == 3 { 200 } else { page_num as u8 };
+            assert_eq!(page, sparse_page(seed), "page {page_num}");
+        }
+        assert!(storage.read(50, &mut page).is_err());
+        assert!(storage.parts().0.size().unwrap() < 50 * PAGE_SIZE as u64 / 8);
+
+        // Cutting a page short zeros the rest of it.
+        storage.truncate(100).unwrap();
+        storage.truncate(PAGE_SIZE as u64).unwrap();
+        storage.read(0, &mut page).unwrap();
+        assert_eq!(page[..100], sparse_page(0)[..100]);
+        assert!(page[100..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn journals_are_passed_through() {
+        let mut factory = CompressedStorageFactory::new(SharedMemoryStorageFactory::new());
+        let mut journal = factory.open_journal("foobar-journal").unwrap();
+        journal.write(0, &sparse_page(1)).unwrap();
+        let mut inner = factory.inner.open("foobar-journal").unwrap();
+        let mut page = vec![0u8; PAGE_SIZE];
+        inner.read(0, &mut page).unwrap();
+        assert_eq!(page, sparse_page(1));
+
+        // Going by the name alone, this would be taken for a journal.
+        let mut storage = factory.open("foobar-wal").unwrap();
+        storage.write(0, &sparse_page(1)).unwrap();
+        storage.sync().unwrap();
+        let mut inner = factory.inner.open("foobar-wal").unwrap();
+        inner.read(0, &mut page).unwrap();
+        assert_ne!(page, sparse_page(1));
+    }
+
+    #[test]
+    fn removed_storage_isnt_flushed() {
+        let mut factory = CompressedStorageFactory::new(SharedMemoryStorageFactory::new());
+        let mut storage = factory.open("foobar").unwrap();
+        storage.write(0, &sparse_page(1)).unwrap();
+        storage.remove().unwrap();
+        assert_eq!(factory.inner.open("foobar").unwrap().size().unwrap(), 0);
+    }
+
+    #[test]
+    fn crash_keeps_last_synced_contents() {
+        fn contents(storage: &mut CompressedStorage<FaultyStorage>) -> Result<Vec<u8>> {
+            let mut contents = vec![0u8; storage.size()? as usize];
+            storage.read(0, &mut contents)?;
+            Ok(contents)
+        }
+
+        // Writes, overwrites and truncations, with what the contents can be after
+        // a crash: those of the last sync, or those flushed since by releasing
+        // the write lock.
+        let workload = |storage: &mut CompressedStorage<_>, flushed: &mut Vec<Vec<u8>>| {
+            for round in 0..4u8 {
+                for page_num in 0..6 {
+                    let page = sparse_page(round * 10 + page_num as u8);
+                    storage.write(page_num, &page)?;
+                }
+                storage.lock(Lock::Reserved)?;
+                storage.lock(Lock::Unlocked)?;
+                flushed.push(contents(storage)?);
+                // Overwrites that would reuse the extents the flush replaced
+                for page_num in 0..3 {
+                    let page = sparse_page(round * 10 + 5 + page_num as u8);
+                    storage.write(page_num, &page)?;
+                }
+                storage.truncate((5 - round as u64) * PAGE_SIZE as u64)?;
+                storage.sync()?;
+                *flushed = vec![contents(storage)?];
+            }
+            Result::Ok(())
+        };
+
+        let mut factory = CompressedStorageFactory::new(FaultyStorageFactory::new(None));
+        let mut storage = factory.open("foobar").unwrap();
+        workload(&mut storage, &mut Vec::new()).unwrap();
+        drop(storage);
+        let num_writes = factory.inner.num_writes();
+
+        for index in 0..num_writes {
+            for fault in [Fault::Drop, Fault::Tear, Fault::Reorder] {
+                let mut factory =
+                    CompressedStorageFactory::new(FaultyStorageFactory::new(Some((index, fault))));
+                let mut storage = factory.open("foobar").unwrap();
+                let mut flushed = vec![Vec::new()];
+                let _ = workload(&mut storage, &mut flushed);
+                drop(storage);
+
+                factory.inner.restart();
+                let mut storage = factory.open("foobar").unwrap();
+                let contents = contents(&mut storage).unwrap();
+                assert!(flushed.contains(&contents), "{fault:?} at write {index}");
+            }
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use crate::error::{Error, Result};
+use crate::storage::{split_pages, Lock, Storage, StorageFactory};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::mem::size_of;
+
+// Encrypted File Layout
+//
+// | header | page | page | ... |
+//
+// The header and every page take up one slot of `SLOT_OVERHEAD + page_size`
+// bytes, so the file can be read and written one slot at a time.
+//
+// Page Layout
+//
+// | nonce | encrypted page contents | tag |
+//
+// A page's nonce is its page number followed by random bytes stored with it.
+// The page number ties the page to where it is, and the random bytes keep a
+// nonce from ever being used twice when the page is rewritten.
+const NONCE_OFFSET: usize = 0;
+const CONTENTS_OFFSET: usize = NONCE_OFFSET + RANDOM_NONCE_SIZE;
+const RANDOM_NONCE_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+const SLOT_OVERHEAD: usize = CONTENTS_OFFSET + TAG_SIZE;
+
+/// Every encrypted file starts with these bytes.
+const MAGIC: [u8; 8] = *b"cstacke\0";
+
+const SALT_SIZE: usize = 16;
+
+/// Rounds of PBKDF2-HMAC-SHA256 to turn a passphrase into a key, as recommended
+/// by OWASP.
+#[cfg(not(test))]
+const KDF_ROUNDS: u32 = 600_000;
+/// Tests open far too many databases to afford the real number of rounds.
+#[cfg(test)]
+const KDF_ROUNDS: u32 = 1_000;
+
+/// File header stored in the first slot.
+#[derive(Debug, Serialize, Deserialize)]
+struct FileHeader {
+    magic: [u8; 8],
+    /// Salt the key is derived from, which every file of a database shares.
+    salt: [u8; SALT_SIZE],
+    page_size: u32,
+    nonce: [u8; RANDOM_NONCE_SIZE],
+    /// Tag authenticating the rest of the header, which only checks out with
+    /// the key the file was written with.
+    key_check: [u8; TAG_SIZE],
+}
+
+const FILE_HEADER_SIZE: usize = size_of::<FileHeader>();
+
+/// Opens storages from `inner` that encrypt every page with a key derived from
+/// a passphrase.
+///
+/// The passphrase is given to the factory rather than to `Table::open`, since
+/// the table only sees the outermost factory, which may be another wrapper like
+/// `CompressedStorageFactory` on top of this one. Opening a file encrypted with
+/// another passphrase fails with `Error::WrongKey`.
+pub struct EncryptedStorageFactory<F> {
+    inner: F,
+    passphrase: String,
+    /// Salt and key of the files opened so far, so deriving the key, which is
+    /// slow on purpose, is done once per database.
+    key: Option<([u8; SALT_SIZE], Key)>,
+}
+
+impl<F> EncryptedStorageFactory<F> {
+    pub fn new(inner: F, passphrase: &str) -> Self {
+        EncryptedStorageFactory {
+            inner,
+            passphrase: passphrase.to_string(),
+            key: None,
+        }
+    }
+
+    /// Gets the key for `salt`, or for a new salt if there isn't one yet.
+    fn key(&mut self, salt: Option<[u8; SALT_SIZE]>) -> ([u8; SALT_SIZE], Key) {
+        match (salt, self.key) {
+            (None, Some(key)) => key,
+            (Some(salt), Some(key)) if key.0 == salt => key,
+            (salt, _) => {
+                let salt = salt.unwrap_or_else(|| {
+                    let mut salt = [0u8; SALT_SIZE];
+                    OsRng.fill_bytes(&mut salt);
+                    salt
+                });
+                let mut key = Key::default();
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    self.passphrase.as_bytes(),
+                    &salt,
+                    KDF_ROUNDS,
+                    &mut key,
+                );
+                self.key = Some((salt, key));
+                (salt, key)
+            }
+        }
+    }
+
+    /// Encrypts `inner`, checking the key against its header if it has one.
+    fn wrap<S: Storage>(&mut self, mut inner: S) -> Result<EncryptedStorage<S>> {
+        let header = read_header(&mut inner)?;
+        let (salt, key) = self.key(header.as_ref().map(|header| header.salt));
+        let storage = EncryptedStorage {
+            inner,
+            cipher: ChaCha20Poly1305::new(&key),
+            salt,
+            page_size: header
+                .as_ref()
+                .map_or(0, |header| header.page_size as usize),
+        };
+        if let Some(header) = header {
+            if storage.key_check(&header) != header.key_check {
+                return Err(Error::WrongKey);
+            }
+        }
+        Ok(storage)
+    }
+}
+
+impl<S: Storage, F: StorageFactory<S>> StorageFactory<EncryptedStorage<S>>
+    for EncryptedStorageFactory<F>
+{
+    fn open(&mut self, filename: &str) -> Result<EncryptedStorage<S>> {
+        let inner = self.inner.open(filename)?;
+        self.wrap(inner)
+    }
+
+    fn open_journal(&mut self, filename: &str) -> Result<EncryptedStorage<S>> {
+        let inner = self.inner.open_journal(filename)?;
+        self.wrap(inner)
+    }
+}
+
+/// Storage that encrypts and authenticates every page with ChaCha20-Poly1305
+/// before it's written to `inner`.
+///
+/// Pages are encrypted whole, so the size is always a whole number of the pages
+/// first written. A page that fails authentication, because it was torn or
+/// tampered with, reads as `Error::Corrupt`.
+pub struct EncryptedStorage<S> {
+    inner: S,
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    /// Size of the pages encrypted on their own, or 0 until the header is written.
+    page_size: usize,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    fn slot_size(&self) -> usize {
+        SLOT_OVERHEAD + self.page_size
+    }
+
+    fn num_pages(&mut self) -> Result<usize> {
+        if self.page_size == 0 {
+            return Ok(0);
+        }
+        // A slot left incomplete by a crash doesn't count.
+        let num_slots = self.inner.size()? / self.slot_size() as u64;
+        Ok(num_slots.saturating_sub(1) as usize)
+    }
+
+    /// Authenticates the header without encrypting anything, with a page number
+    /// no page has.
+    fn key_check(&self, header: &FileHeader) -> [u8; TAG_SIZE] {
+        let mut associated_data = header.salt.to_vec();
+        associated_data.extend_from_slice(&header.page_size.to_le_bytes());
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce(u32::MAX, &header.nonce), &associated_data, &mut [])
+            .unwrap();
+        tag.into()
+    }
+
+    /// Writes the header for pages of `page_size` bytes and makes sure it's
+    /// durable before any page is written, so a crash can't leave pages behind
+    /// without it.
+    fn write_header(&mut self, page_size: usize) -> Result<()> {
+        self.page_size = page_size;
+        let mut header = FileHeader {
+            magic: MAGIC,
+            salt: self.salt,
+            page_size: page_size as u32,
+            nonce: [0; RANDOM_NONCE_SIZE],
+            key_check: [0; TAG_SIZE],
+        };
+        OsRng.fill_bytes(&mut header.nonce);
+        header.key_check = self.key_check(&header);
+        let mut slot = vec![0u8; self.slot_size()];
+        bincode::serialize_into(slot.as_mut_slice(), &header).unwrap();
+        self.inner.write(0, &slot)?;
+        self.inner.sync()
+    }
+
+    fn read_page(&mut self, page_num: usize, page: &mut [u8]) -> Result<()> {
+        let mut slot = vec![0u8; self.slot_size()];
+        let slot = self.inner.page(page_num + 1, &mut slot)?;
+        page.copy_from_slice(&slot[CONTENTS_OFFSET..CONTENTS_OFFSET + self.page_size]);
+        let nonce = nonce(page_num as u32, &slot[NONCE_OFFSET..CONTENTS_OFFSET]);
+        let tag = Tag::from_slice(&slot[CONTENTS_OFFSET + self.page_size..]);
+        self.cipher
+            .decrypt_in_place_detached(&nonce, &[], page, tag)
+            .map_err(|_| Error::Corrupt)
+    }
+
+    fn write_page(&mut self, page_num: usize, page: &[u8]) -> Result<()> {
+        let mut slot = vec![0u8; self.slot_size()];
+        OsRng.fill_bytes(&mut slot[NONCE_OFFSET..CONTENTS_OFFSET]);
+        let nonce = nonce(page_num as u32, &slot[NONCE_OFFSET..CONTENTS_OFFSET]);
+        let contents = &mut slot[CONTENTS_OFFSET..CONTENTS_OFFSET + self.page_size];
+        contents.copy_from_slice(page);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, &[], contents)
+            .unwrap();
+        slot[CONTENTS_OFFSET + self.page_size..].copy_from_slice(&tag);
+        self.inner.write(page_num + 1, &slot)
+    }
+
+    /// Fills the file with pages of zeros up to `num_pages`, the way a file
+    /// reads as zeros past where it was written.
+    fn extend(&mut self, num_pages: usize) -> Result<()> {
+        let zeros = vec![0u8; self.page_size];
+        for page_num in self.num_pages()?..num_pages {
+            self.write_page(page_num, &zeros)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn size(&mut self) -> Result<u64> {
+        Ok((self.num_pages()? * self.page_size) as u64)
+    }
+
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+        let offset = (page_num * buf.len()) as u64;
+        if offset + buf.len() as u64 > self.size()? {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut page = vec![0u8; self.page_size];
+        for (page_num, range, part) in split_pages(offset, buf.len(), self.page_size) {
+            self.read_page(page_num, &mut page)?;
+            buf[part].copy_from_slice(&page[range]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+        if self.page_size == 0 {
+            self.write_header(buf.len())?;
+        }
+        let offset = (page_num * buf.len()) as u64;
+        let num_pages = self.num_pages()?;
+        let mut page = vec![0u8; self.page_size];
+        for (page_num, range, part) in split_pages(offset, buf.len(), self.page_size) {
+            self.extend(page_num)?;
+            if range.len() < self.page_size && page_num < num_pages {
+                self.read_page(page_num, &mut page)?;
+            } else {
+                page.fill(0);
+            }
+            page[range].copy_from_slice(&buf[part]);
+            self.write_page(page_num, &page)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        if self.page_size == 0 {
+            return Ok(());
+        }
+        let num_pages = size.div_ceil(self.page_size as u64) as usize;
+        if num_pages > self.num_pages()? {
+            return self.extend(num_pages);
+        }
+        // The header stays, so an empty file keeps its page size and salt.
+        self.inner
+            .truncate(((num_pages + 1) * self.slot_size()) as u64)?;
+        // Whatever's past the end has to be zeros if the file grows again.
+        let tail = size as usize % self.page_size;
+        if tail > 0 {
+            let mut page = vec![0u8; self.page_size];
+            self.read_page(num_pages - 1, &mut page)?;
+            page[tail..].fill(0);
+            self.write_page(num_pages - 1, &page)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn remove(self) -> Result<()> {
+        self.inner.remove()
+    }
+
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        self.inner.lock(lock)
+    }
+
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        self.inner.is_open_elsewhere()
+    }
+
+    fn create_temporary(&self) -> Result<EncryptedStorage<S>> {
+        Ok(EncryptedStorage {
+            inner: self.inner.create_temporary()?,
+            cipher: self.cipher.clone(),
+            salt: self.salt,
+            page_size: 0,
+        })
+    }
+}
+
+fn nonce(page_num: u32, random: &[u8]) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..size_of::<u32>()].copy_from_slice(&page_num.to_le_bytes());
+    nonce[size_of::<u32>()..].copy_from_slice(random);
+    nonce
+}
+
+/// Reads the header of an encrypted file, if it has one.
+fn read_header<S: Storage>(inner: &mut S) -> Result<Option<FileHeader>> {
+    if inner.size()? < FILE_HEADER_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    inner.read(0, &mut header)?;
+    // A header of zeros was never written in full before a crash.
+    if header.iter().all(|&byte| byte == 0) {
+        return Ok(None);
+    }
+    let header: FileHeader = bincode::deserialize(&header).unwrap();
+    if header.magic != MAGIC {
+        return Err(Error::NotADatabase);
+    }
+    Ok(Some(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SharedMemoryStorageFactory;
+
+    const PAGE_SIZE: usize = 4096;
+    const SLOT_SIZE: usize = SLOT_OVERHEAD + PAGE_SIZE;
+
+    fn page(seed: u8) -> Vec<u8> {
+        let mut page = vec![seed; PAGE_SIZE];
+        page[1000..1010].copy_from_slice(b"cstack1234");
+        page
+    }
+
+    #[test]
+    fn encrypted_storage_survives_reopen() {
+        let mut factory = EncryptedStorageFactory::new(SharedMemoryStorageFactory::new(), "secret");
+        {
+            let mut storage = factory.open("foobar").unwrap();
+            for page_num in 0..10 {
+                storage.write(page_num, &page(page_num as u8)).unwrap();
+            }
+            // Writes and reads that don't line up with pages
+            let mut buf = [0u8; 64];
+            storage.write(63, &[7u8; 64]).unwrap();
+            storage.read(63, &mut buf).unwrap();
+            assert_eq!(buf, [7u8; 64]);
+            storage.truncate(8 * PAGE_SIZE as u64).unwrap();
+
+            let mut raw = vec![0u8; storage.inner.size().unwrap() as usize];
+            storage.inner.read(0, &mut raw).unwrap();
+            assert!(!raw.windows(10).any(|window| window == b"cstack1234"));
+            assert_eq!(raw.len(), 9 * SLOT_SIZE);
+        }
+
+        let mut storage = factory.open("foobar").unwrap();
+        assert_eq!(storage.size().unwrap(), 8 * PAGE_SIZE as u64);
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for page_num in 0..8 {
+            storage.read(page_num, &mut buf).unwrap();
+            let mut expected = page(page_num as u8);
+            if page_num == 0 {
+                expected[PAGE_SIZE - 64..].fill(7);
+            }
+            assert_eq!(buf, expected, "page {page_num}");
+        }
+        assert!(storage.read(8, &mut buf).is_err());
+
+        // Growing the file reads as zeros past the old end.
+        storage.truncate(100).unwrap();
+        storage.write(2, &page(2)).unwrap();
+        storage.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..100], page(0)[..100]);
+        assert!(buf[100..].iter().all(|&byte| byte == 0));
+        storage.read(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let mut factory = EncryptedStorageFactory::new(SharedMemoryStorageFactory::new(), "secret");
+        factory.open("foobar").unwrap().write(0, &page(1)).unwrap();
+
+        let mut factory = EncryptedStorageFactory::new(factory.inner, "guess");
+        assert!(matches!(factory.open("foobar"), Err(Error::WrongKey)));
+
+        let mut plain = factory.inner.open("plain").unwrap();
+        plain.write(0, &page(1)).unwrap();
+        assert!(matches!(factory.open("plain"), Err(Error::NotADatabase)));
+        // Nothing was written before the first page, so there's nothing to check.
+        factory.inner.open("empty").unwrap();
+        assert!(factory.open("empty").is_ok());
+    }
+
+    #[test]
+    fn tampered_pages_read_as_corrupt() {
+        let mut factory = EncryptedStorageFactory::new(SharedMemoryStorageFactory::new(), "secret");
+        let mut storage = factory.open("foobar").unwrap();
+        for page_num in 0..3 {
+            storage.write(page_num, &page(page_num as u8)).unwrap();
+        }
+
+        // Flipping a bit
+        let mut slot = vec![0u8; SLOT_SIZE];
+        storage.inner.read(1, &mut slot).unwrap();
+        slot[CONTENTS_OFFSET + 1000] ^= 1;
+        storage.inner.write(1, &slot).unwrap();
+        let mut buf = vec![0u8; PAGE_SIZE];
+        assert!(matches!(storage.read(0, &mut buf), Err(Error::Corrupt)));
+
+        // Moving a page to where another one belongs
+        storage.inner.read(2, &mut slot).unwrap();
+        storage.inner.write(3, &slot).unwrap();
+        assert!(matches!(storage.read(2, &mut buf), Err(Error::Corrupt)));
+        storage.read(1, &mut buf).unwrap();
+        assert_eq!(buf, page(1));
+    }
+}
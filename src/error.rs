@@ -0,0 +1,71 @@
+use std::fmt::{Display, Formatter};
+
+/// Errors from reading or writing the database.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The file doesn't start with the database header.
+    NotADatabase,
+    /// The file was written before databases had a header page, with the root
+    /// node on page 0.
+    OldLayout,
+    UnsupportedVersion(u32),
+    UnsupportedPageSize(u32),
+    /// The file is damaged, e.g. shorter than its header says or with a node
+    /// that can't be read.
+    Corrupt,
+    /// A page doesn't match its checksum, e.g. after a bit flipped on disk.
+    CorruptPage(u32),
+    /// Another connection held a lock in the way for longer than the busy timeout.
+    Busy,
+    /// The file is encrypted with a key other than the one it was opened with.
+    WrongKey,
+    /// Every page in the buffer pool is pinned, so there's no room to load another.
+    CacheFull,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::NotADatabase => write!(f, "file is not a database"),
+            Error::OldLayout => write!(
+                f,
+                "file has the old layout with the root on page 0, which is no longer supported"
+            ),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported file format version {version}")
+            }
+            Error::UnsupportedPageSize(page_size) => {
+                write!(f, "unsupported page size {page_size}")
+            }
+            Error::Corrupt => write!(f, "database disk image is malformed"),
+            Error::CorruptPage(page_num) => {
+                write!(
+                    f,
+                    "database disk image is malformed: page {page_num} failed its checksum"
+                )
+            }
+            Error::Busy => write!(f, "database is locked"),
+            Error::WrongKey => write!(f, "wrong encryption key"),
+            Error::CacheFull => write!(f, "every page in the cache is in use"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use crate::error::{Error, Result};
+use crate::node::internal::InternalNode;
+use crate::node::leaf::LeafNode;
+use crate::node::Node;
+use crate::pager::{Pager, HEADER_PAGE_NUM};
+use crate::serialization::Row;
+use crate::storage::Storage;
+use crate::table::Table;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+/// Something wrong with the database found by `check_integrity`.
+#[derive(Debug)]
+pub enum Problem {
+    /// The page can't be read as a node, e.g. because it fails its checksum.
+    Unreadable { page_num: u32, error: Error },
+    /// A node points to a page that's the header page or past the end of the database.
+    OutOfRange { page_num: u32, referenced_by: u32 },
+    /// A page is used twice in the tree, or both in the tree and on the freelist.
+    ReferencedTwice { page_num: u32 },
+    /// A page that's neither in the tree nor on the freelist.
+    Orphan { page_num: u32 },
+    WrongParent {
+        page_num: u32,
+        expected: Option<u32>,
+        found: Option<u32>,
+    },
+    /// A node with more cells or keys than fit on its page.
+    Overfull { page_num: u32, count: u32, max: u32 },
+    /// A leaf other than the root without any cells.
+    EmptyLeaf { page_num: u32 },
+    /// A key in a leaf that isn't greater than the key before it.
+    UnsortedKey { page_num: u32, cell_num: u32 },
+    /// The first key in a leaf isn't greater than the last key in the leaf before it.
+    UnsortedLeaf { page_num: u32 },
+    /// A leaf whose next leaf isn't the one after it in the tree.
+    WrongNextLeaf {
+        page_num: u32,
+        expected: u32,
+        found: u32,
+    },
+    /// A key in an internal node that isn't the max key of its child.
+    WrongKey {
+        page_num: u32,
+        key_num: u32,
+        key: u32,
+        max_key: u32,
+    },
+    /// A cell that doesn't hold a row.
+    BadRow { page_num: u32, cell_num: u32 },
+    /// A cell holding a row under another row's key.
+    WrongRowId {
+        page_num: u32,
+        cell_num: u32,
+        key: u32,
+        id: u32,
+    },
+    /// The header's count of free pages doesn't match the freelist.
+    WrongFreelistCount { expected: u32, found: u32 },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Unreadable { page_num, error } => {
+                write!(f, "page {page_num} can't be read: {error}")
+            }
+            Problem::OutOfRange {
+                page_num,
+                referenced_by,
+            } => write!(
+                f,
+                "page {referenced_by} refers to page {page_num}, which is out of range"
+            ),
+            Problem::ReferencedTwice { page_num } => {
+                write!(f, "page {page_num} is referenced more than once")
+            }
+            Problem::Orphan { page_num } => {
+                write!(f, "page {page_num} is neither in the tree nor on the freelist")
+            }
+            Problem::WrongParent {
+                page_num,
+                expected,
+                found,
+            } => match (expected, found) {
+                (Some(expected), Some(found)) => {
+                    write!(f, "page {page_num} has parent {found} instead of {expected}")
+                }
+                (Some(expected), None) => write!(
+                    f,
+                    "page {page_num} is marked as the root instead of having parent {expected}"
+                ),
+                (None, _) => write!(f, "page {page_num} isn't marked as the root"),
+            },
+            Problem::Overfull {
+                page_num,
+                count,
+                max,
+            } => write!(
+                f,
+                "page {page_num} holds {count} entries, more than the {max} that fit"
+            ),
+            Problem::EmptyLeaf { page_num } => write!(f, "leaf {page_num} is empty"),
+            Problem::UnsortedKey { page_num, cell_num } => write!(
+                f,
+                "leaf {page_num} has key {cell_num} out of order with the key before it"
+            ),
+            Problem::UnsortedLeaf { page_num } => write!(
+                f,
+                "leaf {page_num} has keys out of order with the leaf before it"
+            ),
+            Problem::WrongNextLeaf {
+                page_num,
+                expected,
+                found,
+            } => write!(
+                f,
+                "leaf {page_num} has next leaf {found} instead of {expected}"
+            ),
+            Problem::WrongKey {
+                page_num,
+                key_num,
+                key,
+                max_key,
+            } => write!(
+                f,
+                "page {page_num} has key {key_num} set to {key} instead of {max_key}, the max key of its child"
+            ),
+            Problem::BadRow { page_num, cell_num } => {
+                write!(f, "leaf {page_num} has cell {cell_num} that isn't a row")
+            }
+            Problem::WrongRowId {
+                page_num,
+                cell_num,
+                key,
+                id,
+            } => write!(
+                f,
+                "leaf {page_num} has cell {cell_num} holding row {id} under key {key}"
+            ),
+            Problem::WrongFreelistCount { expected, found } => write!(
+                f,
+                "freelist has {found} pages, but the header says {expected}"
+            ),
+        }
+    }
+}
+
+/// Checks the whole tree, and that every page is used exactly once, returning
+/// every problem found rather than stopping at the first one.
+///
+/// Problems with the database file are reported, while other errors, like
+/// failing to read the file at all, end the check.
+pub fn check_integrity<T: Storage>(table: &mut Table<T>) -> Result<Vec<Problem>> {
+    let mut checker = Checker {
+        seen: vec![false; table.pager.num_pages as usize],
+        pager: &mut table.pager,
+        problems: Vec::new(),
+        leaves: Vec::new(),
+    };
+    checker.seen[HEADER_PAGE_NUM as usize] = true;
+    if checker.reference(table.root_page_num, HEADER_PAGE_NUM) {
+        checker.check_node(table.root_page_num, None)?;
+    }
+    checker.check_leaves();
+    checker.check_freelist()?;
+    Ok(checker.problems)
+}
+
+/// What's needed of a leaf to check it against the leaves next to it.
+struct Leaf {
+    page_num: u32,
+    next_leaf: u32,
+    /// First and last keys, unless the leaf is empty or its cells can't be read.
+    keys: Option<(u32, u32)>,
+}
+
+struct Checker<'a, T> {
+    pager: &'a mut Pager<T>,
+    problems: Vec<Problem>,
+    /// Whether each page was found in the tree or on the freelist yet.
+    seen: Vec<bool>,
+    /// Leaves in the order they're found in the tree.
+    leaves: Vec<Leaf>,
+}
+
+impl<T: Storage> Checker<'_, T> {
+    /// Records that `referenced_by` refers to `page_num`, returning whether the
+    /// page is one to look at.
+    fn reference(&mut self, page_num: u32, referenced_by: u32) -> bool {
+        if page_num == HEADER_PAGE_NUM || page_num >= self.pager.num_pages {
+            self.problems.push(Problem::OutOfRange {
+                page_num,
+                referenced_by,
+            });
+            return false;
+        }
+        if std::mem::replace(&mut self.seen[page_num as usize], true) {
+            self.problems.push(Problem::ReferencedTwice { page_num });
+            return false;
+        }
+        true
+    }
+
+    /// Checks the subtree at `page_num`, returning its max key if it has one.
+    fn check_node(&mut self, page_num: u32, parent: Option<u32>) -> Result<Option<u32>> {
+        let node = match self.pager.page(page_num) {
+            Ok(node) => node,
+            Err(error @ (Error::Corrupt | Error::CorruptPage(_))) => {
+                self.problems.push(Problem::Unreadable { page_num, error });
+                return Ok(None);
+            }
+            Err(error) => return Err(error),
+        };
+        if node.parent() != parent {
+            self.problems.push(Problem::WrongParent {
+                page_num,
+                expected: parent,
+                found: node.parent(),
+            });
+        }
+        match node {
+            Node::Internal(node) => self.check_internal(page_num, node),
+            Node::Leaf(node) => Ok(self.check_leaf(page_num, node, parent.is_none())),
+        }
+    }
+
+    fn check_internal(&mut self, page_num: u32, node: InternalNode) -> Result<Option<u32>> {
+        let num_keys = node.num_keys();
+        if num_keys > node.max_keys() {
+            self.problems.push(Problem::Overfull {
+                page_num,
+                count: num_keys,
+                max: node.max_keys(),
+            });
+            return Ok(None);
+        }
+
+        let mut max_key = None;
+        for child_num in 0..=num_keys {
+            let child = node.child(child_num)?;
+            max_key = None;
+            if self.reference(child, page_num) {
+                max_key = self.check_node(child, Some(page_num))?;
+            }
+            // A child without a max key already has a problem of its own.
+            if let (true, Some(max_key)) = (child_num < num_keys, max_key) {
+                let key = node.key(child_num);
+                if key != max_key {
+                    self.problems.push(Problem::WrongKey {
+                        page_num,
+                        key_num: child_num,
+                        key,
+                        max_key,
+                    });
+                }
+            }
+        }
+        Ok(max_key)
+    }
+
+    fn check_leaf(&mut self, page_num: u32, node: LeafNode, is_root: bool) -> Option<u32> {
+        let num_cells = node.num_cells();
+        let mut leaf = Leaf {
+            page_num,
+            next_leaf: node.next_leaf(),
+            keys: None,
+        };
+        if num_cells > node.max_cells() {
+            self.problems.push(Problem::Overfull {
+                page_num,
+                count: num_cells,
+                max: node.max_cells(),
+            });
+        } else if num_cells == 0 {
+            if !is_root {
+                self.problems.push(Problem::EmptyLeaf { page_num });
+            }
+        } else {
+            for cell_num in 0..num_cells {
+                let key = node.key(cell_num);
+                if cell_num > 0 && key <= node.key(cell_num - 1) {
+                    self.problems
+                        .push(Problem::UnsortedKey { page_num, cell_num });
+                }
+                match bincode::deserialize::<Row>(node.value(cell_num)) {
+                    Ok(row) if row.id != key => self.problems.push(Problem::WrongRowId {
+                        page_num,
+                        cell_num,
+                        key,
+                        id: row.id,
+                    }),
+                    Ok(_) => {}
+                    Err(_) => self.problems.push(Problem::BadRow { page_num, cell_num }),
+                }
+            }
+            leaf.keys = Some((node.key(0), node.get_max_key()));
+        }
+        let max_key = leaf.keys.map(|(_, last)| last);
+        self.leaves.push(leaf);
+        max_key
+    }
+
+    /// Checks that the leaves are chained together in the order they're in the
+    /// tree, with keys increasing from one to the next.
+    fn check_leaves(&mut self) {
+        let unreadable: HashSet<_> = self
+            .problems
+            .iter()
+            .filter_map(|problem| match problem {
+                Problem::Unreadable { page_num, .. } => Some(*page_num),
+                _ => None,
+            })
+            .collect();
+        let mut last_key = None;
+        for (i, leaf) in self.leaves.iter().enumerate() {
+            let expected = self.leaves.get(i + 1).map_or(0, |next| next.page_num);
+            // A leaf that can't be read is left out of the leaves, but not the chain.
+            if leaf.next_leaf != expected && !unreadable.contains(&leaf.next_leaf) {
+                self.problems.push(Problem::WrongNextLeaf {
+                    page_num: leaf.page_num,
+                    expected,
+                    found: leaf.next_leaf,
+                });
+            }
+            if let Some((first, last)) = leaf.keys {
+                if last_key.is_some_and(|last_key| first <= last_key) {
+                    self.problems.push(Problem::UnsortedLeaf {
+                        page_num: leaf.page_num,
+                    });
+                }
+                last_key = Some(last);
+            }
+        }
+    }
+
+    /// Checks that the freelist accounts for every page that isn't in the tree.
+    fn check_freelist(&mut self) -> Result<()> {
+        let free_pages = match self.pager.freelist() {
+            Ok(free_pages) => free_pages,
+            Err(error @ (Error::Corrupt | Error::CorruptPage(_))) => {
+                // Without the whole freelist, there's no telling which pages are orphans.
+                let page_num = match error {
+                    Error::CorruptPage(page_num) => page_num,
+                    _ => HEADER_PAGE_NUM,
+                };
+                self.problems.push(Problem::Unreadable { page_num, error });
+                return Ok(());
+            }
+            Err(error) => return Err(error),
+        };
+
+        let expected = self.pager.header()?.freelist_count;
+        if free_pages.len() != expected as usize {
+            self.problems.push(Problem::WrongFreelistCount {
+                expected,
+                found: free_pages.len() as u32,
+            });
+        }
+        for (page_num, listed_by) in free_pages {
+            self.reference(page_num, listed_by);
+        }
+        for page_num in 0..self.pager.num_pages {
+            if !self.seen[page_num as usize] {
+                self.problems.push(Problem::Orphan { page_num });
+            }
+        }
+        Ok(())
+    }
+}
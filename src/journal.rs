@@ -0,0 +1,227 @@
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::mem::size_of;
+
+// Rollback Journal Layout
+//
+// | header | record | record | ... |
+//
+// The header and every record take up one slot of `RECORD_HEADER_SIZE + page_size`
+// bytes, so the journal can be read and written one slot at a time.
+//
+// Journal Record Layout
+//
+// | page num | checksum | original page contents |
+const PAGE_NUM_OFFSET: usize = 0;
+const CHECKSUM_OFFSET: usize = PAGE_NUM_OFFSET + size_of::<u32>();
+const RECORD_HEADER_SIZE: usize = CHECKSUM_OFFSET + size_of::<u32>();
+
+/// Every journal starts with these bytes.
+const MAGIC: [u8; 8] = *b"cstackj\0";
+
+/// Journal header stored in the first slot.
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalHeader {
+    magic: [u8; 8],
+    page_size: u32,
+    /// Length of the database file when the transaction began.
+    original_length: u64,
+}
+
+const JOURNAL_HEADER_SIZE: usize = size_of::<JournalHeader>();
+
+/// Gets the name of the journal that goes with the database `filename`.
+pub fn journal_filename(filename: &str) -> String {
+    format!("{filename}-journal")
+}
+
+/// Rollback journal holding the original contents of every page a transaction
+/// overwrites in the database file.
+///
+/// Before a page is written to the database, its original contents are appended
+/// to the journal and the journal is synced. Once every page is written and the
+/// database is synced, emptying the journal commits the transaction. A journal
+/// that still holds a header when the database is opened is hot, and is played
+/// back to undo a transaction that didn't finish.
+pub struct Journal<T> {
+    storage: T,
+    page_size: usize,
+    /// Length of the database file when the transaction began, or `None` until
+    /// the transaction first writes to the database.
+    original_length: Option<u64>,
+    /// Pages whose original contents are in the journal.
+    saved_pages: HashSet<u32>,
+    num_records: usize,
+    synced: bool,
+}
+
+impl<T: Storage> Journal<T> {
+    pub fn new(storage: T, page_size: usize) -> Journal<T> {
+        Journal {
+            storage,
+            page_size,
+            original_length: None,
+            saved_pages: HashSet::new(),
+            num_records: 0,
+            synced: true,
+        }
+    }
+
+    /// Gets whether the transaction has started writing to the database.
+    pub fn is_active(&self) -> bool {
+        self.original_length.is_some()
+    }
+
+    /// Saves the contents of `page_num` in `database` before it's first overwritten
+    /// by the transaction. `database_length` is the current length of the database,
+    /// which is taken as the original length if the transaction hasn't begun yet.
+    pub fn save(&mut self, database: &mut T, page_num: u32, database_length: u64) -> Result<()> {
+        let original_length = match self.original_length {
+            Some(original_length) => original_length,
+            None => {
+                self.begin(database_length)?;
+                database_length
+            }
+        };
+
+        let offset = page_num as u64 * self.page_size as u64;
+        if offset >= original_length || self.saved_pages.contains(&page_num) {
+            // Pages past the original end are removed by truncating on rollback.
+            return Ok(());
+        }
+
+        let mut record = vec![0u8; RECORD_HEADER_SIZE + self.page_size];
+        database.read(page_num as usize, &mut record[RECORD_HEADER_SIZE..])?;
+        let checksum = checksum(page_num, &record[RECORD_HEADER_SIZE..]);
+        record[PAGE_NUM_OFFSET..CHECKSUM_OFFSET].copy_from_slice(&page_num.to_le_bytes());
+        record[CHECKSUM_OFFSET..RECORD_HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+        self.num_records += 1;
+        self.storage.write(self.num_records, &record)?;
+        self.saved_pages.insert(page_num);
+        self.synced = false;
+        Ok(())
+    }
+
+    /// Writes the header that makes the journal hot.
+    fn begin(&mut self, original_length: u64) -> Result<()> {
+        let header = JournalHeader {
+            magic: MAGIC,
+            page_size: self.page_size as u32,
+            original_length,
+        };
+        let mut slot = vec![0u8; RECORD_HEADER_SIZE + self.page_size];
+        bincode::serialize_into(slot.as_mut_slice(), &header).unwrap();
+        self.storage.write(0, &slot)?;
+        self.original_length = Some(original_length);
+        self.synced = false;
+        Ok(())
+    }
+
+    /// Gets whether the journal holds a transaction that has to be rolled back,
+    /// which, when no other connection is writing, means that one crashed.
+    pub fn is_hot(&mut self) -> Result<bool> {
+        Ok(self.storage.size()? > 0)
+    }
+
+    /// Makes sure everything saved so far is durable before the database is written.
+    pub fn sync(&mut self) -> Result<()> {
+        if !self.synced {
+            self.storage.sync()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /// Empties the journal, which commits the transaction once synced.
+    pub fn finish(&mut self) -> Result<()> {
+        self.storage.truncate(0)?;
+        self.reset();
+        self.synced = false;
+        Ok(())
+    }
+
+    /// Restores every page the transaction wrote in `database`, then empties the
+    /// journal.
+    pub fn rollback(&mut self, database: &mut T) -> Result<()> {
+        recover(&mut self.storage, database)?;
+        self.reset();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.original_length = None;
+        self.saved_pages.clear();
+        self.num_records = 0;
+        self.synced = true;
+    }
+
+    /// Deletes the journal, which must be empty.
+    pub fn remove(self) -> Result<()> {
+        self.storage.remove()
+    }
+}
+
+/// Plays back a hot journal into `database`, restoring it to how it was before
+/// the unfinished transaction began, then empties the journal.
+///
+/// Records are checked against their checksum, and playback stops at the first
+/// one that doesn't match, or that the storage can't read back, since it was torn
+/// before the database was written.
+pub fn recover<T: Storage>(journal: &mut T, database: &mut T) -> Result<()> {
+    let journal_length = journal.size()?;
+    if journal_length == 0 {
+        return Ok(());
+    }
+
+    let mut header = [0u8; JOURNAL_HEADER_SIZE];
+    if journal_length >= JOURNAL_HEADER_SIZE as u64 {
+        // A header that can't be read back was torn, the same as a bad checksum.
+        match journal.read(0, &mut header) {
+            Err(Error::Corrupt) => header.fill(0),
+            result => result?,
+        }
+    }
+    // Without a complete header, the database wasn't written yet.
+    if let Ok(header) = bincode::deserialize::<JournalHeader>(&header) {
+        if header.magic == MAGIC {
+            let page_size = header.page_size as usize;
+            let slot_size = (RECORD_HEADER_SIZE + page_size) as u64;
+            let mut buf = vec![0u8; RECORD_HEADER_SIZE + page_size];
+            for slot in 1..journal_length / slot_size {
+                let record = match journal.page(slot as usize, &mut buf) {
+                    Err(Error::Corrupt) => break,
+                    record => record?,
+                };
+                let page_num = read_u32(record, PAGE_NUM_OFFSET);
+                let page = &record[RECORD_HEADER_SIZE..];
+                if read_u32(record, CHECKSUM_OFFSET) != checksum(page_num, page) {
+                    break;
+                }
+                database.write(page_num as usize, page)?;
+            }
+            database.truncate(header.original_length)?;
+            database.sync()?;
+        }
+    }
+
+    journal.truncate(0)?;
+    journal.sync()
+}
+
+/// Checksum of the page number and contents of a record.
+fn checksum(page_num: u32, page: &[u8]) -> u32 {
+    let checksum = checksum::update(checksum::INITIAL, &page_num.to_le_bytes());
+    checksum::update(checksum, page)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(
+        buffer[offset..offset + size_of::<u32>()]
+            .try_into()
+            .unwrap(),
+    )
+}
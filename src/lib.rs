@@ -0,0 +1,24 @@
+pub mod analyzer;
+mod buffer_pool;
+mod checksum;
+pub mod compression;
+pub mod encryption;
+pub mod error;
+pub mod integrity;
+mod journal;
+pub mod node;
+pub mod pager;
+pub mod repl;
+pub mod serialization;
+pub mod storage;
+pub mod table;
+pub mod vm;
+mod wal;
+
+use crate::serialization::Row;
+use crate::table::Table;
+
+pub trait Logger {
+    fn print_row(&self, row: &Row);
+    fn print_value(&self, value: &str);
+}
@@ -1,29 +1,113 @@
-mod node;
-mod pager;
-mod repl;
-mod serialization;
-mod storage;
-mod table;
-mod vm;
-
-use crate::serialization::Row;
-use crate::storage::{FileStorage, FileStorageFactory};
-use crate::vm::Statement;
-use repl::{print_prompt, read_input};
-use table::Table;
-use vm::{do_meta_command, execute_statement, ExecuteError, MetaCommandError, PrepareError};
+use cstack::compression::CompressedStorageFactory;
+use cstack::encryption::EncryptedStorageFactory;
+use cstack::pager::{JournalMode, PagerConfig};
+use cstack::repl::{print_prompt, read_input};
+use cstack::serialization::Row;
+use cstack::storage::{
+    FileStorageFactory, InMemoryStorageFactory, MmapStorageFactory, Storage, StorageFactory,
+};
+use cstack::table::Table;
+use cstack::vm::{
+    do_meta_command, execute_statement, ExecuteError, MetaCommandError, PrepareError, Statement,
+};
+use cstack::Logger;
+use libc::EXIT_FAILURE;
+use std::process::exit;
 
 fn main() {
-    let filename = std::env::args()
-        .skip(1)
-        .next()
-        .expect("Must supply a database filename");
-    let mut storage_factory = FileStorageFactory;
-    let mut table: Table<FileStorage> = Table::open(&mut storage_factory, &filename);
+    let mut config = PagerConfig::default();
+    let mut filename = None;
+    let mut mmap = false;
+    let mut compress = false;
+    let mut encrypt = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wal" => config.journal_mode = JournalMode::Wal,
+            // Only used when creating a database, which checks that it's supported.
+            "--page-size" => {
+                config.page_size = match args.next().map(|size| size.parse()) {
+                    Some(Ok(page_size)) => page_size,
+                    _ => {
+                        println!("Error: --page-size must be followed by a number of bytes.");
+                        exit(EXIT_FAILURE);
+                    }
+                }
+            }
+            "--mmap" => mmap = true,
+            "--compress" => compress = true,
+            "--encrypt" => encrypt = true,
+            _ => filename = Some(arg),
+        }
+    }
+    let filename = filename.expect("Must supply a database filename");
+    if filename == ":memory:" {
+        if mmap || compress || encrypt {
+            println!("Error: --mmap, --compress and --encrypt only apply to database files.");
+            exit(EXIT_FAILURE);
+        }
+        run(open(&mut InMemoryStorageFactory, &filename, config));
+    } else if mmap {
+        run_encrypted(MmapStorageFactory, &filename, config, encrypt, compress);
+    } else {
+        run_encrypted(FileStorageFactory, &filename, config, encrypt, compress);
+    }
+}
+
+/// Runs the database on `storage_factory`, encrypting it if asked to. Pages are
+/// compressed before they're encrypted, since encrypted bytes don't compress.
+fn run_encrypted<T: Storage, F: StorageFactory<T>>(
+    storage_factory: F,
+    filename: &str,
+    config: PagerConfig,
+    encrypt: bool,
+    compress: bool,
+) {
+    if encrypt {
+        // The passphrase is taken from the environment to keep it out of the process list.
+        let passphrase = std::env::var("CSTACK_KEY").expect("Must set CSTACK_KEY to encrypt");
+        let storage_factory = EncryptedStorageFactory::new(storage_factory, &passphrase);
+        run_compressed(storage_factory, filename, config, compress);
+    } else {
+        run_compressed(storage_factory, filename, config, compress);
+    }
+}
+
+/// Runs the database on `storage_factory`, compressing it if asked to.
+fn run_compressed<T: Storage, F: StorageFactory<T>>(
+    mut storage_factory: F,
+    filename: &str,
+    config: PagerConfig,
+    compress: bool,
+) {
+    if compress {
+        let mut storage_factory = CompressedStorageFactory::new(storage_factory);
+        run(open(&mut storage_factory, filename, config));
+    } else {
+        run(open(&mut storage_factory, filename, config));
+    }
+}
+
+fn open<T: Storage, F: StorageFactory<T>>(
+    storage_factory: &mut F,
+    filename: &str,
+    config: PagerConfig,
+) -> Table<T> {
+    match Table::open_with_config(storage_factory, filename, config) {
+        Ok(table) => table,
+        Err(error) => {
+            println!("Error: {error}.");
+            exit(EXIT_FAILURE);
+        }
+    }
+}
+
+/// Reads and runs statements until the session exits.
+fn run<T: Storage>(mut table: Table<T>) {
     let logger = ConsoleLogger;
 
     loop {
-        print_prompt();
+        print_prompt(table.pager.in_transaction());
         let input = read_input();
 
         if input.starts_with(".") {
@@ -37,6 +121,11 @@ fn main() {
                     println!("Unrecognized command '{}'", input);
                     continue;
                 }
+                Err((t, MetaCommandError::Database(error))) => {
+                    table = t;
+                    println!("Error: {error}.");
+                    continue;
+                }
             }
         }
 
@@ -66,15 +155,36 @@ fn main() {
             Ok(_) => {
                 println!("Executed.");
             }
-            Err(ExecuteError::DuplicateKey) => {
-                println!("Error: Duplicate key.");
-            }
+            Err(error) => print_execute_error(&error),
         }
     }
 }
 
-pub trait Logger {
-    fn print_row(&self, row: &Row);
+fn print_execute_error(error: &ExecuteError) {
+    match error {
+        ExecuteError::DuplicateKey => {
+            println!("Error: Duplicate key.");
+        }
+        ExecuteError::RowNotFound => {
+            println!("Error: Row not found.");
+        }
+        ExecuteError::TransactionActive => {
+            println!("Error: Cannot start a transaction within a transaction.");
+        }
+        ExecuteError::NoTransaction => {
+            println!("Error: No transaction is active.");
+        }
+        ExecuteError::NoSuchSavepoint => {
+            println!("Error: No such savepoint.");
+        }
+        ExecuteError::Database(error) => {
+            println!("Error: {error}.");
+        }
+        ExecuteError::RollbackFailed { error, rollback } => {
+            print_execute_error(error);
+            println!("Error: Rolling back failed too: {rollback}.");
+        }
+    }
 }
 
 struct ConsoleLogger;
@@ -82,4 +192,8 @@ impl Logger for ConsoleLogger {
     fn print_row(&self, row: &Row) {
         println!("{}", row);
     }
+
+    fn print_value(&self, value: &str) {
+        println!("{}", value);
+    }
 }
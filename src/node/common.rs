@@ -1,8 +1,11 @@
+use crate::buffer_pool::Frame;
+use crate::error::{Error, Result};
 use crate::node::NodeType;
-use crate::pager::PAGE_SIZE;
+use crate::pager::usable_size;
 use serde::de::Unexpected;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::mem::size_of;
+use std::rc::Rc;
 
 // Common Node Header Layout
 pub const HEADER_SIZE: usize = size_of::<Header>();
@@ -15,7 +18,7 @@ pub struct Header {
     pub parent: u32,
 }
 
-fn bool_from_int<'de, D>(deserializer: D) -> Result<bool, D::Error>
+fn bool_from_int<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -32,68 +35,108 @@ where
 #[derive(Debug)]
 pub struct CommonNode {
     pub buffer: *mut u8,
-    header: Header,
+    page_num: u32,
+    page_size: usize,
+    /// Keeps the page pinned in the buffer pool while the node is alive.
+    frame: Rc<Frame>,
 }
 
 impl CommonNode {
-    /// Creates a CommonNode.
-    pub fn new(buffer: *mut u8) -> CommonNode {
-        let slice = unsafe { std::slice::from_raw_parts(buffer, PAGE_SIZE) };
-        let header = bincode::deserialize(slice).unwrap();
-        CommonNode { buffer, header }
+    /// Creates a CommonNode for the page held in `frame`, failing if the page
+    /// doesn't start with a valid node header. The checksum at the end of the
+    /// page is left out of the node's buffer.
+    pub fn new(frame: Rc<Frame>, page_num: u32) -> Result<CommonNode> {
+        let node = CommonNode {
+            buffer: frame.as_mut_ptr(),
+            page_num,
+            page_size: usable_size(frame.data().len()),
+            frame,
+        };
+        bincode::deserialize::<Header>(node.get_buffer()).map_err(|_| Error::Corrupt)?;
+        Ok(node)
+    }
+
+    /// Gets the page number this node lives on.
+    pub fn page_num(&self) -> u32 {
+        self.page_num
+    }
+
+    /// Gets the size of the page in bytes, leaving out its checksum.
+    pub fn page_size(&self) -> usize {
+        self.page_size
     }
 
     /// Gets the buffer as a slice.
     pub fn get_buffer(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(self.buffer, PAGE_SIZE) }
+        unsafe { std::slice::from_raw_parts(self.buffer, self.page_size) }
     }
 
-    /// Gets the buffer as a mut slice.
+    /// Gets the buffer as a mut slice, marking the page as modified.
+    #[allow(clippy::mut_from_ref)]
     pub fn get_buffer_mut(&self) -> &mut [u8] {
-        unsafe { std::slice::from_raw_parts_mut(self.buffer, PAGE_SIZE) }
+        self.mark_dirty();
+        unsafe { std::slice::from_raw_parts_mut(self.buffer, self.page_size) }
+    }
+
+    /// Marks the page as modified so it's written back to storage.
+    pub(crate) fn mark_dirty(&self) {
+        self.frame.set_dirty(true);
+    }
+
+    /// Reads a little-endian `u32` stored at `offset` in the page.
+    pub(crate) fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le(unsafe { (self.buffer.add(offset) as *const u32).read_unaligned() })
+    }
+
+    /// Writes a `u32` at `offset` in the page as little-endian.
+    pub(crate) fn write_u32(&mut self, offset: usize, value: u32) {
+        self.mark_dirty();
+        unsafe { (self.buffer.add(offset) as *mut u32).write_unaligned(value.to_le()) }
+    }
+
+    /// Deserializes the header from the page.
+    fn header(&self) -> Header {
+        bincode::deserialize(self.get_buffer()).unwrap()
+    }
+
+    /// Serializes the header into the page.
+    fn set_header(&mut self, header: &Header) {
+        bincode::serialize_into(self.get_buffer_mut(), header).unwrap();
     }
 
     /// Gets the node type.
     pub fn node_type(&self) -> NodeType {
-        self.header.r#type
+        self.header().r#type
     }
 
     /// Sets the node type.
     pub fn set_node_type(&mut self, node_type: NodeType) {
-        self.header.r#type = node_type;
-        let buffer = self.get_buffer_mut();
-        bincode::serialize_into(buffer, &self.header).unwrap();
+        let mut header = self.header();
+        header.r#type = node_type;
+        self.set_header(&header);
     }
 
     /// Gets whether this node is the root.
     pub fn is_root(&self) -> bool {
-        self.header.is_root
+        self.header().is_root
     }
 
     /// Sets whether this node is the root.
     pub fn set_root(&mut self, is_root: bool) {
-        let buffer = self.get_buffer_mut();
-
-        // TODO: Tests fail if it's not deserialized here. Not sure why yet.
-        let mut header: Header = bincode::deserialize(buffer).unwrap();
+        let mut header = self.header();
         header.is_root = is_root;
-        bincode::serialize_into(buffer, &header).unwrap();
-        self.header.is_root = is_root;
+        self.set_header(&header);
     }
 
     /// Gets the location for the parent node.
     pub fn parent(&self) -> u32 {
-        self.header.parent
+        self.header().parent
     }
 
     /// Sets the location for the parent node.
     pub(crate) fn set_parent(&mut self, parent: u32) {
-        let buffer = self.get_buffer_mut();
-
-        // TODO: Tests fail if it's not deserialized here. Not sure why yet.
-        let mut header: Header = bincode::deserialize(buffer).unwrap();
+        let mut header = self.header();
         header.parent = parent;
-        bincode::serialize_into(buffer, &header).unwrap();
-        self.header.parent = parent
+        self.set_header(&header);
     }
 }
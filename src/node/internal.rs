@@ -1,13 +1,12 @@
+use crate::error::{Error, Result};
 use crate::node::common;
 use crate::node::common::CommonNode;
 use crate::node::{Node, NodeType};
+use crate::pager::Pager;
 use crate::storage::Storage;
 use crate::table::Cursor;
 use crate::Table;
-use libc::{memcpy, EXIT_FAILURE};
-use std::ffi::c_void;
 use std::mem::size_of;
-use std::process::exit;
 
 // Internal Node Header Layout
 //
@@ -23,25 +22,20 @@ const KEY_SIZE: usize = size_of::<u32>();
 const CHILD_SIZE: usize = size_of::<u32>();
 const CELL_SIZE: usize = CHILD_SIZE + KEY_SIZE;
 
-// Internal Node Body Layout
-const MAX_CELLS: u32 = 3;
+/// Gets how many keys fit in an internal node on a page with `usable_size`
+/// bytes left for its contents.
+pub(crate) fn max_keys(usable_size: usize) -> u32 {
+    ((usable_size - HEADER_SIZE) / CELL_SIZE) as u32
+}
 
 #[derive(Debug)]
 pub struct InternalNode {
     pub node: CommonNode,
-    num_keys: u32,
-    right_child: u32,
 }
 
 impl From<CommonNode> for InternalNode {
     fn from(node: CommonNode) -> Self {
-        let num_keys = unsafe { *(node.buffer.add(NUM_KEYS_OFFSET) as *mut u32) };
-        let right_child = unsafe { *(node.buffer.add(RIGHT_CHILD_OFFSET) as *mut u32) };
-        InternalNode {
-            node,
-            num_keys,
-            right_child,
-        }
+        InternalNode { node }
     }
 }
 
@@ -50,82 +44,82 @@ impl InternalNode {
     pub fn new(mut node: CommonNode) -> Self {
         node.set_node_type(NodeType::Internal);
         node.set_root(false);
-        let mut internal = InternalNode {
-            node,
-            num_keys: 0,
-            right_child: 0,
-        };
+        let mut internal = InternalNode { node };
         internal.set_num_keys(0);
+        internal.set_right_child(0);
         internal
     }
 
+    /// Gets the number of keys that fit in the node.
+    pub fn max_keys(&self) -> u32 {
+        max_keys(self.node.page_size())
+    }
+
+    /// Internal nodes other than the root are rebalanced once they hold fewer keys than this.
+    fn min_keys(&self) -> u32 {
+        self.max_keys() / 2
+    }
+
+    /// Gets the bytes of the page that aren't taken up by the header or cells,
+    /// which is none if the node is damaged and claims more keys than fit.
+    pub fn unused_bytes(&self) -> usize {
+        (self.node.page_size() - HEADER_SIZE).saturating_sub(self.num_keys() as usize * CELL_SIZE)
+    }
+
     /// Gets the number of keys in the node.
     pub fn num_keys(&self) -> u32 {
-        self.num_keys
+        self.node.read_u32(NUM_KEYS_OFFSET)
     }
 
     /// Sets the number of keys in the node;
     pub fn set_num_keys(&mut self, num_keys: u32) {
-        unsafe {
-            *(self.node.buffer.add(NUM_KEYS_OFFSET) as *mut u32) = num_keys;
-        }
-        self.num_keys = num_keys;
+        self.node.write_u32(NUM_KEYS_OFFSET, num_keys);
     }
 
     /// Gets the location of the right child.
     pub fn right_child(&self) -> u32 {
-        self.right_child
+        self.node.read_u32(RIGHT_CHILD_OFFSET)
     }
 
     /// Sets the location of the right child.
     pub fn set_right_child(&mut self, right_child: u32) {
-        unsafe {
-            *(self.node.buffer.add(RIGHT_CHILD_OFFSET) as *mut u32) = right_child;
-        }
-        self.right_child = right_child;
+        self.node.write_u32(RIGHT_CHILD_OFFSET, right_child);
+    }
+
+    /// Gets the offset of the specific node cell within the page.
+    fn cell_offset(cell_num: u32) -> usize {
+        HEADER_SIZE + cell_num as usize * CELL_SIZE
     }
 
-    /// Gets the location of the specific node cell.
+    /// Gets the location of the child stored in the specific node cell.
     fn cell(&self, cell_num: u32) -> u32 {
-        unsafe {
-            *(self
-                .node
-                .buffer
-                .add(HEADER_SIZE + cell_num as usize * CELL_SIZE) as *mut u32)
-        }
+        self.node.read_u32(Self::cell_offset(cell_num))
     }
 
-    /// Sets the location of the specific node cell.
+    /// Sets the location of the child stored in the specific node cell.
     fn set_cell(&mut self, cell_num: u32, cell: u32) {
-        unsafe {
-            *(self
-                .node
-                .buffer
-                .add(HEADER_SIZE + cell_num as usize * CELL_SIZE) as *mut u32) = cell;
-        }
+        self.node.write_u32(Self::cell_offset(cell_num), cell);
     }
 
     /// Gets the location of the specific child.
-    pub fn child(&self, child_num: u32) -> u32 {
+    pub fn child(&self, child_num: u32) -> Result<u32> {
         let num_keys = self.num_keys();
         if child_num > num_keys {
-            println!("Tried to access child_num {child_num} > num_keys {num_keys}");
-            exit(EXIT_FAILURE);
+            return Err(Error::Corrupt);
         }
 
         if child_num == num_keys {
-            self.right_child()
+            Ok(self.right_child())
         } else {
-            self.cell(child_num)
+            Ok(self.cell(child_num))
         }
     }
 
     /// Sets the location of the specific child.
-    pub fn set_child(&mut self, child_num: u32, child: u32) {
+    pub fn set_child(&mut self, child_num: u32, child: u32) -> Result<()> {
         let num_keys = self.num_keys();
         if child_num > num_keys {
-            println!("Tried to access child_num {child_num} > num_keys {num_keys}");
-            exit(EXIT_FAILURE);
+            return Err(Error::Corrupt);
         }
 
         if child_num == num_keys {
@@ -133,26 +127,16 @@ impl InternalNode {
         } else {
             self.set_cell(child_num, child);
         }
+        Ok(())
     }
 
     pub fn key(&self, key_num: u32) -> u32 {
-        unsafe {
-            let internal_node_cell =
-                self.node
-                    .buffer
-                    .add(HEADER_SIZE + key_num as usize * CELL_SIZE) as *mut u32;
-            *(internal_node_cell.add(CHILD_SIZE))
-        }
+        self.node.read_u32(Self::cell_offset(key_num) + CHILD_SIZE)
     }
 
     pub fn set_key(&mut self, key_num: u32, key: u32) {
-        unsafe {
-            let internal_node_cell =
-                self.node
-                    .buffer
-                    .add(HEADER_SIZE + key_num as usize * CELL_SIZE) as *mut u32;
-            *(internal_node_cell.add(CHILD_SIZE)) = key;
-        }
+        self.node
+            .write_u32(Self::cell_offset(key_num) + CHILD_SIZE, key);
     }
 
     /// Returns the index of the child which should contain the given key.
@@ -178,56 +162,219 @@ impl InternalNode {
 
     pub fn update_key(&mut self, old_key: u32, new_key: u32) {
         let old_child_index = self.find_child(old_key);
-        self.set_key(old_child_index, new_key);
+        // The right child has no key of its own.
+        if old_child_index < self.num_keys() {
+            self.set_key(old_child_index, new_key);
+        }
     }
 
-    /// Gets the max key in the node.
-    pub fn get_max_key(&self) -> u32 {
-        self.key(self.num_keys() - 1)
+    /// Gets the max key in the subtree rooted at this node.
+    pub fn get_max_key<T: Storage>(&self, pager: &mut Pager<T>) -> Result<u32> {
+        pager.page(self.right_child())?.get_max_key(pager)
     }
 
-    pub fn find<T: Storage>(&self, table: &mut Table<T>, key: u32) -> Cursor<T> {
+    pub fn find<T: Storage>(&self, table: &mut Table<T>, key: u32) -> Result<Cursor<T>> {
         let child_index = self.find_child(key);
-        let child_num = self.child(child_index);
-        let child = table.pager.page(child_num);
+        let child_num = self.child(child_index)?;
+        let child = table.pager.page(child_num)?;
         match child {
-            Node::Leaf(leaf) => leaf.find(table, key),
+            Node::Leaf(leaf) => Ok(leaf.find(table, key)),
             Node::Internal(internal) => internal.find(table, key),
         }
     }
 
     /// Add a child/key pair to node.
-    pub fn insert<T: Storage>(&mut self, table: &mut Table<T>, child_page_num: u32) {
-        let pager = &mut table.pager;
-        let child = pager.page(child_page_num);
-        let child_max_key = child.get_max_key();
-
-        let index = self.find_child(child_max_key);
+    pub fn insert<T: Storage>(&mut self, table: &mut Table<T>, child_page_num: u32) -> Result<()> {
         let original_num_keys = self.num_keys();
-        self.set_num_keys(original_num_keys + 1);
-
-        if original_num_keys >= MAX_CELLS {
-            panic!("Need to implement splitting internal node");
+        if original_num_keys >= self.max_keys() {
+            return internal_node_split_and_insert(table, self.node.page_num(), child_page_num);
         }
 
+        let pager = &mut table.pager;
+        let child_max_key = pager.page(child_page_num)?.get_max_key(pager)?;
+        let index = self.find_child(child_max_key);
+
         let right_child_page_num = self.right_child();
-        let right_child = pager.page(right_child_page_num);
-        if child_max_key > right_child.get_max_key() {
+        let right_child_max_key = pager.page(right_child_page_num)?.get_max_key(pager)?;
+        self.set_num_keys(original_num_keys + 1);
+
+        if child_max_key > right_child_max_key {
             // Replace right child
-            self.set_child(original_num_keys, right_child_page_num);
-            self.set_key(original_num_keys, right_child.get_max_key());
+            self.set_cell(original_num_keys, right_child_page_num);
+            self.set_key(original_num_keys, right_child_max_key);
             self.set_right_child(child_page_num);
         } else {
             // Make room for the new cell
-            for i in ((index + 1)..=original_num_keys).rev() {
-                let destination = self.cell(i);
-                let source = self.cell(i - 1);
-                unsafe {
-                    memcpy(destination as *mut c_void, source as *mut c_void, CELL_SIZE);
-                }
-            }
-            self.set_child(index, child_page_num);
+            let buffer = self.node.get_buffer_mut();
+            buffer.copy_within(
+                Self::cell_offset(index)..Self::cell_offset(original_num_keys),
+                Self::cell_offset(index + 1),
+            );
+            self.set_cell(index, child_page_num);
             self.set_key(index, child_max_key);
         }
+        Ok(())
+    }
+
+    /// Gets the index of the given child page within the node.
+    pub fn child_index(&self, child_page_num: u32) -> Result<u32> {
+        // A page missing from its parent means the parent pointer is wrong.
+        (0..=self.num_keys())
+            .find(|&i| self.child(i).is_ok_and(|child| child == child_page_num))
+            .ok_or(Error::Corrupt)
     }
+
+    /// Removes the child at `child_num` after its contents were merged into the
+    /// child before it, which now has `merged_max_key` as its max key.
+    pub fn remove_child(&mut self, child_num: u32, merged_max_key: u32) {
+        let num_keys = self.num_keys();
+        if child_num == num_keys {
+            // The merged child takes the place of the right child
+            self.set_right_child(self.cell(num_keys - 1));
+        } else {
+            self.set_key(child_num - 1, merged_max_key);
+            let buffer = self.node.get_buffer_mut();
+            buffer.copy_within(
+                Self::cell_offset(child_num + 1)..Self::cell_offset(num_keys),
+                Self::cell_offset(child_num),
+            );
+        }
+        self.set_num_keys(num_keys - 1);
+    }
+
+    /// Gets every child of the node paired with the max key of its subtree,
+    /// including the right child.
+    fn children<T: Storage>(&self, pager: &mut Pager<T>) -> Result<Vec<(u32, u32)>> {
+        let num_keys = self.num_keys();
+        let mut children: Vec<_> = (0..num_keys).map(|i| (self.cell(i), self.key(i))).collect();
+        let right_child = self.right_child();
+        children.push((right_child, pager.page(right_child)?.get_max_key(pager)?));
+        Ok(children)
+    }
+
+    /// Replaces every child of the node, the last one becoming the right child.
+    /// Each child gets its parent pointer updated to this node.
+    pub(crate) fn set_children<T: Storage>(
+        &mut self,
+        pager: &mut Pager<T>,
+        children: &[(u32, u32)],
+    ) -> Result<()> {
+        let page_num = self.node.page_num();
+        let (&(right_child, _), cells) = children.split_last().unwrap();
+        self.set_num_keys(cells.len() as u32);
+        for (i, &(child, key)) in cells.iter().enumerate() {
+            self.set_cell(i as u32, child);
+            self.set_key(i as u32, key);
+        }
+        self.set_right_child(right_child);
+
+        for &(child, _) in children {
+            pager.page(child)?.set_parent(page_num);
+        }
+        Ok(())
+    }
+}
+
+/// Split a full internal node and add a child to whichever half it belongs in.
+/// The new sibling is added to the parent, which may split in turn; splitting the
+/// root grows the tree by one level.
+fn internal_node_split_and_insert<T: Storage>(
+    table: &mut Table<T>,
+    old_page_num: u32,
+    child_page_num: u32,
+) -> Result<()> {
+    let pager = &mut table.pager;
+    let old_node = pager.page(old_page_num)?.unwrap_internal();
+    let old_max = old_node.get_max_key(pager)?;
+    let child_max = pager.page(child_page_num)?.get_max_key(pager)?;
+
+    // Gather all existing children plus the new one, in key order.
+    let mut children = old_node.children(pager)?;
+    let index = children.partition_point(|&(_, max_key)| max_key < child_max);
+    children.insert(index, (child_page_num, child_max));
+
+    let new_page_num = pager.get_unused_page_num()?;
+    pager.new_internal_page(new_page_num)?;
+
+    let splitting_root = old_node.node.is_root();
+    let (old_page_num, parent_page_num) = if splitting_root {
+        // The old root's contents move to a new left child underneath a new root.
+        table.create_new_root(new_page_num)?;
+        let root = table.pager.page(table.root_page_num)?.unwrap_internal();
+        (root.child(0)?, table.root_page_num)
+    } else {
+        (old_page_num, old_node.node.parent())
+    };
+
+    // Divide the children evenly between the old (left) and new (right) nodes.
+    let pager = &mut table.pager;
+    let (left, right) = children.split_at(children.len().div_ceil(2));
+    let mut old_node = pager.page(old_page_num)?.unwrap_internal();
+    old_node.set_children(pager, left)?;
+    let mut new_node = pager.page(new_page_num)?.unwrap_internal();
+    new_node.set_children(pager, right)?;
+    new_node.node.set_parent(parent_page_num);
+
+    let mut parent = pager.page(parent_page_num)?.unwrap_internal();
+    let new_max = left.last().unwrap().1;
+    parent.update_key(old_max, new_max);
+    if !splitting_root {
+        parent.insert(table, new_page_num)?;
+    }
+    Ok(())
+}
+
+/// Restore the invariants of an internal node after one of its children was
+/// removed. A root left with a single child is collapsed into that child,
+/// while other nodes with too few keys borrow from or merge with a sibling.
+pub(crate) fn internal_node_rebalance<T: Storage>(
+    table: &mut Table<T>,
+    page_num: u32,
+) -> Result<()> {
+    let pager = &mut table.pager;
+    let node = pager.page(page_num)?.unwrap_internal();
+    if node.node.is_root() {
+        if node.num_keys() == 0 {
+            table.collapse_root()?;
+        }
+        return Ok(());
+    }
+
+    if node.num_keys() >= node.min_keys() {
+        return table.update_ancestor_keys(page_num);
+    }
+
+    let parent_page_num = node.node.parent();
+    let mut parent = pager.page(parent_page_num)?.unwrap_internal();
+
+    // Pair the node with its left sibling, or its right sibling if it's the first child.
+    let left_index = parent.child_index(page_num)?.max(1) - 1;
+    let right_page_num = parent.child(left_index + 1)?;
+    let mut left = pager.page(parent.child(left_index)?)?.unwrap_internal();
+    let mut right = pager.page(right_page_num)?.unwrap_internal();
+    let mut left_children = left.children(pager)?;
+    let mut right_children = right.children(pager)?;
+
+    if left_children.len() + right_children.len() <= left.max_keys() as usize + 1 {
+        // Merge the right node into the left one
+        left_children.append(&mut right_children);
+        left.set_children(pager, &left_children)?;
+        pager.free_page(right_page_num)?;
+
+        parent.remove_child(left_index + 1, left_children.last().unwrap().1);
+        return internal_node_rebalance(table, parent_page_num);
+    }
+
+    if left_children.len() > right_children.len() {
+        let child = left_children.pop().unwrap();
+        right_children.insert(0, child);
+    } else {
+        let child = right_children.remove(0);
+        left_children.push(child);
+    }
+    left.set_children(pager, &left_children)?;
+    right.set_children(pager, &right_children)?;
+
+    parent.set_key(left_index, left_children.last().unwrap().1);
+    table.update_ancestor_keys(right_page_num)
 }
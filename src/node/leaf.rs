@@ -1,7 +1,8 @@
+use crate::error::Result;
 use crate::node::common;
 use crate::node::common::CommonNode;
+use crate::node::internal::internal_node_rebalance;
 use crate::node::NodeType;
-use crate::pager::PAGE_SIZE;
 use crate::serialization::ROW_SIZE;
 use crate::storage::Storage;
 use crate::table::Cursor;
@@ -9,6 +10,7 @@ use crate::{Row, Table};
 use libc::memcpy;
 use std::ffi::c_void;
 use std::mem::size_of;
+use std::ops::Range;
 
 // Leaf Node Header Layout
 //
@@ -23,11 +25,18 @@ pub(crate) const HEADER_SIZE: usize = common::HEADER_SIZE + NUM_CELLS_SIZE + NEX
 const KEY_SIZE: usize = size_of::<u32>();
 const VALUE_SIZE: usize = ROW_SIZE;
 pub(crate) const CELL_SIZE: usize = KEY_SIZE + VALUE_SIZE;
-pub(crate) const SPACE_FOR_CELLS: usize = PAGE_SIZE - HEADER_SIZE;
-pub(crate) const MAX_CELLS: usize = SPACE_FOR_CELLS / CELL_SIZE;
 
-const RIGHT_SPLIT_COUNT: usize = (MAX_CELLS + 1) / 2;
-const LEFT_SPLIT_COUNT: usize = (MAX_CELLS + 1) - RIGHT_SPLIT_COUNT;
+/// Gets the bytes available for cells on a page with `usable_size` bytes left
+/// for its contents.
+pub(crate) fn space_for_cells(usable_size: usize) -> usize {
+    usable_size - HEADER_SIZE
+}
+
+/// Gets how many cells fit in a leaf on a page with `usable_size` bytes left
+/// for its contents.
+pub(crate) fn max_cells(usable_size: usize) -> u32 {
+    (space_for_cells(usable_size) / CELL_SIZE) as u32
+}
 
 #[derive(Debug)]
 pub struct LeafNode {
@@ -51,43 +60,65 @@ impl LeafNode {
         leaf
     }
 
+    /// Get the number of cells that fit in the node.
+    pub fn max_cells(&self) -> u32 {
+        max_cells(self.node.page_size())
+    }
+
+    /// Leaves other than the root are rebalanced once they hold fewer cells than this.
+    fn min_cells(&self) -> u32 {
+        self.max_cells() / 2
+    }
+
+    /// Gets the bytes of the page that aren't taken up by the header or cells,
+    /// which is none if the node is damaged and claims more cells than fit.
+    pub fn unused_bytes(&self) -> usize {
+        space_for_cells(self.node.page_size()).saturating_sub(self.num_cells() as usize * CELL_SIZE)
+    }
+
     /// Get the number of cells currently occupied in the node.
     pub fn num_cells(&self) -> u32 {
-        unsafe { *(self.node.buffer.add(NUM_CELLS_OFFSET) as *mut u32) }
+        self.node.read_u32(NUM_CELLS_OFFSET)
     }
 
     /// Set the number of cells currently occupied in the node.
     pub fn set_num_cells(&mut self, num_cells: u32) {
-        unsafe {
-            *(self.node.buffer.add(NUM_CELLS_OFFSET) as *mut u32) = num_cells;
-        }
+        self.node.write_u32(NUM_CELLS_OFFSET, num_cells);
+    }
+
+    /// Get the offset of the leaf node cell within the page.
+    fn cell_offset(cell_num: u32) -> usize {
+        HEADER_SIZE + cell_num as usize * CELL_SIZE
+    }
+
+    /// Get the cells in `range` as a slice.
+    pub(crate) fn cells(&self, range: Range<u32>) -> &[u8] {
+        &self.node.get_buffer()[Self::cell_offset(range.start)..Self::cell_offset(range.end)]
+    }
+
+    /// Overwrite the cells starting at `cell_num` with `cells`.
+    pub(crate) fn set_cells(&mut self, cell_num: u32, cells: &[u8]) {
+        let offset = Self::cell_offset(cell_num);
+        self.node.get_buffer_mut()[offset..offset + cells.len()].copy_from_slice(cells);
     }
 
     /// Get the pointer to the leaf node cell.
     fn cell(&self, cell_num: u32) -> *mut u8 {
-        unsafe {
-            self.node
-                .buffer
-                .add(HEADER_SIZE + cell_num as usize * CELL_SIZE)
-        }
+        unsafe { self.node.buffer.add(Self::cell_offset(cell_num)) }
     }
 
     pub fn key(&self, cell_num: u32) -> u32 {
-        unsafe { *(self.cell(cell_num) as *mut u32) }
+        self.node.read_u32(Self::cell_offset(cell_num))
     }
 
     pub fn set_key(&mut self, cell_num: u32, key: u32) {
-        unsafe {
-            *(self.cell(cell_num) as *mut u32) = key;
-        }
+        self.node.write_u32(Self::cell_offset(cell_num), key);
     }
 
     /// Get a mutable slice to the leaf node value.
     pub fn value_mut(&mut self, cell_num: u32) -> &mut [u8] {
-        unsafe {
-            let ptr = self.cell(cell_num).add(KEY_SIZE);
-            std::slice::from_raw_parts_mut(ptr, ROW_SIZE)
-        }
+        let offset = Self::cell_offset(cell_num) + KEY_SIZE;
+        &mut self.node.get_buffer_mut()[offset..offset + VALUE_SIZE]
     }
 
     /// Get a slice to the leaf node value
@@ -100,14 +131,12 @@ impl LeafNode {
 
     /// Gets the location of the next leaf.
     pub fn next_leaf(&self) -> u32 {
-        unsafe { *(self.node.buffer.add(NEXT_LEAF_OFFSET) as *mut u32) }
+        self.node.read_u32(NEXT_LEAF_OFFSET)
     }
 
     /// Sets the location of the next leaf.
     pub fn set_next_leaf(&mut self, next_leaf: u32) {
-        unsafe {
-            *(self.node.buffer.add(NEXT_LEAF_OFFSET) as *mut u32) = next_leaf;
-        }
+        self.node.write_u32(NEXT_LEAF_OFFSET, next_leaf);
     }
 
     /// Gets the max key in the node.
@@ -147,7 +176,7 @@ impl LeafNode {
     }
 }
 
-fn leaf_node_split_and_insert<T: Storage>(cursor: Cursor<T>, key: u32, value: &Row) {
+fn leaf_node_split_and_insert<T: Storage>(cursor: Cursor<T>, key: u32, value: &Row) -> Result<()> {
     // Create a new node and move half the cells over.
     // Insert the new value in one of the two nodes.
     // Update parent or create a new parent.
@@ -155,8 +184,14 @@ fn leaf_node_split_and_insert<T: Storage>(cursor: Cursor<T>, key: u32, value: &R
     let pager = &mut table.pager;
     let mut old_node = cursor.node;
     let old_max = old_node.get_max_key();
-    let new_page_num = pager.get_unused_page_num();
-    let mut new_node = pager.new_leaf_page(new_page_num);
+    let max_cells = old_node.max_cells();
+    let right_split_count = max_cells.div_ceil(2);
+    let left_split_count = (max_cells + 1) - right_split_count;
+    let new_page_num = pager.get_unused_page_num()?;
+    let mut new_node = pager.new_leaf_page(new_page_num)?;
+    // Cells are copied through raw pointers below.
+    old_node.node.mark_dirty();
+    new_node.node.mark_dirty();
     new_node.node.set_parent(old_node.node.parent());
     new_node.set_next_leaf(old_node.next_leaf());
     old_node.set_next_leaf(new_page_num);
@@ -164,13 +199,13 @@ fn leaf_node_split_and_insert<T: Storage>(cursor: Cursor<T>, key: u32, value: &R
     // All existing keys plus new key should be divided
     // evenly between old (left) and new (right) nodes.
     // Starting from the right, move each key to correct position.
-    for i in (0..=MAX_CELLS as i32).rev() {
-        let destination_node = if i >= LEFT_SPLIT_COUNT as i32 {
+    for i in (0..=max_cells as i32).rev() {
+        let destination_node = if i >= left_split_count as i32 {
             &mut new_node
         } else {
             &mut old_node
         };
-        let index_within_node = i % LEFT_SPLIT_COUNT as i32;
+        let index_within_node = i % left_split_count as i32;
         let destination = destination_node.cell(index_within_node as u32);
 
         unsafe {
@@ -198,47 +233,123 @@ fn leaf_node_split_and_insert<T: Storage>(cursor: Cursor<T>, key: u32, value: &R
     }
 
     // Update cell count on both leaf nodes
-    old_node.set_num_cells(LEFT_SPLIT_COUNT as u32);
-    new_node.set_num_cells(RIGHT_SPLIT_COUNT as u32);
+    old_node.set_num_cells(left_split_count);
+    new_node.set_num_cells(right_split_count);
 
     unsafe {
         if old_node.node.is_root() {
-            (&mut *cursor.table).create_new_root(new_page_num);
+            (&mut *cursor.table).create_new_root(new_page_num)
         } else {
             let parent_page_num = old_node.node.parent();
             let new_max = old_node.get_max_key();
             let mut parent = (&mut *cursor.table)
                 .pager
-                .page(parent_page_num)
+                .page(parent_page_num)?
                 .unwrap_internal();
             parent.update_key(old_max, new_max);
-            parent.insert(&mut *cursor.table, new_page_num);
+            parent.insert(&mut *cursor.table, new_page_num)
         }
     }
 }
 
-pub(crate) fn leaf_node_insert<T: Storage>(mut cursor: Cursor<T>, key: u32, value: &Row) {
+pub(crate) fn leaf_node_insert<T: Storage>(
+    mut cursor: Cursor<T>,
+    key: u32,
+    value: &Row,
+) -> Result<()> {
     let num_cells = cursor.node.num_cells();
-    if num_cells >= MAX_CELLS as u32 {
+    if num_cells >= cursor.node.max_cells() {
         // Node full
-        leaf_node_split_and_insert(cursor, key, value);
-        return;
+        return leaf_node_split_and_insert(cursor, key, value);
     }
 
     if cursor.cell_num < num_cells {
         // Make room for new cell
-        for i in (cursor.cell_num + 1..=num_cells).rev() {
-            unsafe {
-                memcpy(
-                    cursor.node.cell(i) as *mut c_void,
-                    cursor.node.cell(i - 1) as *mut c_void,
-                    CELL_SIZE,
-                );
-            }
-        }
+        cursor.node.node.get_buffer_mut().copy_within(
+            LeafNode::cell_offset(cursor.cell_num)..LeafNode::cell_offset(num_cells),
+            LeafNode::cell_offset(cursor.cell_num + 1),
+        );
     }
 
     cursor.node.set_num_cells(cursor.node.num_cells() + 1);
     cursor.node.set_key(cursor.cell_num, key);
     bincode::serialize_into(cursor.node.value_mut(cursor.cell_num), value).unwrap();
+    Ok(())
+}
+
+pub(crate) fn leaf_node_delete<T: Storage>(mut cursor: Cursor<T>) -> Result<()> {
+    let num_cells = cursor.node.num_cells();
+
+    // Close the gap left by the removed cell
+    cursor.node.node.get_buffer_mut().copy_within(
+        LeafNode::cell_offset(cursor.cell_num + 1)..LeafNode::cell_offset(num_cells),
+        LeafNode::cell_offset(cursor.cell_num),
+    );
+    cursor.node.set_num_cells(num_cells - 1);
+
+    if cursor.node.node.is_root() {
+        return Ok(());
+    }
+
+    let table = unsafe { &mut *cursor.table };
+    let page_num = cursor.node.node.page_num();
+    if cursor.node.num_cells() < cursor.node.min_cells() {
+        leaf_node_rebalance(table, page_num)
+    } else {
+        table.update_ancestor_keys(page_num)
+    }
+}
+
+/// Refill a leaf that has too few cells, either by borrowing a cell from a
+/// sibling or by merging with it when both fit in a single node.
+fn leaf_node_rebalance<T: Storage>(table: &mut Table<T>, page_num: u32) -> Result<()> {
+    let pager = &mut table.pager;
+    let parent_page_num = pager.page(page_num)?.unwrap_leaf().node.parent();
+    let mut parent = pager.page(parent_page_num)?.unwrap_internal();
+
+    // Pair the node with its left sibling, or its right sibling if it's the first child.
+    let left_index = parent.child_index(page_num)?.max(1) - 1;
+    let left_page_num = parent.child(left_index)?;
+    let right_page_num = parent.child(left_index + 1)?;
+    let mut left = pager.page(left_page_num)?.unwrap_leaf();
+    let mut right = pager.page(right_page_num)?.unwrap_leaf();
+    let left_num_cells = left.num_cells();
+    let right_num_cells = right.num_cells();
+
+    if left_num_cells + right_num_cells <= left.max_cells() {
+        // Merge the right node into the left one
+        left.set_cells(left_num_cells, right.cells(0..right_num_cells));
+        left.set_num_cells(left_num_cells + right_num_cells);
+        left.set_next_leaf(right.next_leaf());
+        pager.free_page(right_page_num)?;
+
+        parent.remove_child(left_index + 1, left.get_max_key());
+        return internal_node_rebalance(table, parent_page_num);
+    }
+
+    if left_num_cells > right_num_cells {
+        // Move the last cell of the left node to the front of the right node
+        let cell = left.cells(left_num_cells - 1..left_num_cells).to_vec();
+        let buffer = right.node.get_buffer_mut();
+        buffer.copy_within(
+            LeafNode::cell_offset(0)..LeafNode::cell_offset(right_num_cells),
+            LeafNode::cell_offset(1),
+        );
+        right.set_cells(0, &cell);
+        left.set_num_cells(left_num_cells - 1);
+        right.set_num_cells(right_num_cells + 1);
+    } else {
+        // Move the first cell of the right node to the end of the left node
+        left.set_cells(left_num_cells, right.cells(0..1));
+        let buffer = right.node.get_buffer_mut();
+        buffer.copy_within(
+            LeafNode::cell_offset(1)..LeafNode::cell_offset(right_num_cells),
+            LeafNode::cell_offset(0),
+        );
+        left.set_num_cells(left_num_cells + 1);
+        right.set_num_cells(right_num_cells - 1);
+    }
+
+    parent.set_key(left_index, left.get_max_key());
+    table.update_ancestor_keys(right_page_num)
 }
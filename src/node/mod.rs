@@ -2,10 +2,15 @@ pub mod common;
 pub mod internal;
 pub mod leaf;
 
+use crate::buffer_pool::Frame;
+use crate::error::Result;
 use crate::node::common::CommonNode;
 use crate::node::internal::InternalNode;
 use crate::node::leaf::LeafNode;
+use crate::pager::Pager;
+use crate::storage::Storage;
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::rc::Rc;
 
 #[derive(Serialize_repr, Deserialize_repr, Debug, Clone, Copy)]
 #[repr(u8)]
@@ -21,11 +26,18 @@ pub enum Node {
 }
 
 impl Node {
-    /// Gets the max key in the node.
-    pub(crate) fn get_max_key(&self) -> u32 {
+    /// Decodes a page read straight from storage rather than through the pager,
+    /// with its checksum at the end.
+    pub fn from_page(page: Box<[u8]>, page_num: u32) -> Result<Node> {
+        let node = CommonNode::new(Rc::new(Frame::new(page)), page_num)?;
+        Ok(Node::from(node))
+    }
+
+    /// Gets the max key in the subtree rooted at the node.
+    pub(crate) fn get_max_key<T: Storage>(&self, pager: &mut Pager<T>) -> Result<u32> {
         match self {
-            Node::Internal(node) => node.get_max_key(),
-            Node::Leaf(node) => node.get_max_key(),
+            Node::Internal(node) => node.get_max_key(pager),
+            Node::Leaf(node) => Ok(node.get_max_key()),
         }
     }
 
@@ -59,10 +71,7 @@ impl Node {
 
     #[allow(dead_code)]
     pub fn buffer_mut_ptr(&mut self) -> *mut u8 {
-        match self {
-            Node::Internal(node) => node.node.buffer,
-            Node::Leaf(node) => node.node.buffer,
-        }
+        self.buffer_mut().as_mut_ptr()
     }
 
     #[allow(dead_code)]
@@ -80,6 +89,15 @@ impl Node {
         }
     }
 
+    /// Gets the location of the parent node, unless the node is the root.
+    pub fn parent(&self) -> Option<u32> {
+        let node = match self {
+            Node::Internal(node) => &node.node,
+            Node::Leaf(node) => &node.node,
+        };
+        (!node.is_root()).then(|| node.parent())
+    }
+
     pub fn set_parent(&mut self, parent: u32) {
         match self {
             Node::Internal(node) => node.node.set_parent(parent),
@@ -1,105 +1,1691 @@
+use crate::buffer_pool::{BufferPool, Frame};
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::journal::{journal_filename, Journal};
+use crate::node::common;
 use crate::node::common::CommonNode;
 use crate::node::internal::InternalNode;
 use crate::node::leaf::LeafNode;
 use crate::node::Node;
-use crate::storage::{Storage, StorageFactory};
+use crate::storage::{Lock, Storage, StorageFactory};
+use crate::wal::{wal_filename, Wal};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub const TABLE_MAX_PAGES: usize = 100;
-pub const PAGE_SIZE: usize = 4096;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+
+/// Smallest page size that still fits a few rows in a leaf.
+pub const MIN_PAGE_SIZE: usize = 1024;
+pub const MAX_PAGE_SIZE: usize = 65536;
+
+/// Settings used when opening a database.
+#[derive(Debug, Clone)]
+pub struct PagerConfig {
+    /// Number of pages the buffer pool holds before evicting the least recently used.
+    /// Pinned pages are never evicted, so loading a page fails with
+    /// `Error::CacheFull` when they take up the whole pool.
+    pub cache_capacity: usize,
+    /// Page size for new databases. Existing databases keep the page size in their header.
+    pub page_size: usize,
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// How long to keep retrying when another connection has the database locked
+    /// before giving up with `Error::Busy`.
+    pub busy_timeout: Duration,
+}
+
+impl Default for PagerConfig {
+    fn default() -> Self {
+        PagerConfig {
+            cache_capacity: 1000,
+            page_size: DEFAULT_PAGE_SIZE,
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How changes are kept atomic when they're written.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum JournalMode {
+    /// Save the original pages in a rollback journal, then overwrite them in the
+    /// database file.
+    #[default]
+    Rollback,
+    /// Append modified pages to a write-ahead log, and copy them into the database
+    /// file when checkpointing.
+    Wal,
+}
+
+/// When writes are synced to make them durable.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Synchronous {
+    /// Never sync, leaving it up to the operating system. A crash or power loss
+    /// can corrupt the database.
+    Off,
+    /// Sync at the moments that keep the database consistent, but not always at
+    /// the end of a commit, so a power loss can undo the last transactions.
+    Normal,
+    /// Sync whenever needed to make every commit durable before it returns.
+    #[default]
+    Full,
+}
+
+impl Display for Synchronous {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Synchronous::Off => write!(f, "off"),
+            Synchronous::Normal => write!(f, "normal"),
+            Synchronous::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl FromStr for Synchronous {
+    type Err = ();
+
+    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+        match name {
+            "off" | "0" => Ok(Synchronous::Off),
+            "normal" | "1" => Ok(Synchronous::Normal),
+            "full" | "2" => Ok(Synchronous::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Page sizes are powers of two between `MIN_PAGE_SIZE` and `MAX_PAGE_SIZE`.
+fn is_valid_page_size(page_size: usize) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
+
+/// Page 0 holds the database header rather than a node.
+pub const HEADER_PAGE_NUM: u32 = 0;
+
+/// Every database file starts with these bytes.
+pub const MAGIC: [u8; 16] = *b"cstack format 1\0";
+
+/// Version of the file layout written by this build.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Size of every page in files from before the header page, which had the root
+/// node on page 0 instead.
+const OLD_PAGE_SIZE: usize = 4096;
+
+// Page Layout
+//
+// | contents | checksum |
+//
+// Every page ends with a little-endian checksum of its page number and contents,
+// so a page that was damaged, or written where another one belongs, is caught
+// when it's read.
+pub const CHECKSUM_SIZE: usize = size_of::<u32>();
+
+/// Gets the bytes of a page of `page_size` bytes that are left for its contents.
+pub fn usable_size(page_size: usize) -> usize {
+    page_size - CHECKSUM_SIZE
+}
+
+/// Database header stored at the start of the header page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatabaseHeader {
+    pub magic: [u8; 16],
+    pub version: u32,
+    pub page_size: u32,
+    /// Number of pages in the database, the header page included.
+    pub page_count: u32,
+    /// First freelist trunk page, or 0 if there are no free pages.
+    pub freelist_trunk: u32,
+    /// Number of pages on the freelist, trunks included.
+    pub freelist_count: u32,
+    /// Bumped whenever the table layout changes.
+    pub schema_cookie: u32,
+    /// Bumped by every commit, so other connections can tell their cached pages
+    /// are out of date.
+    pub change_counter: u32,
+}
+
+impl DatabaseHeader {
+    /// Creates the header of an empty database.
+    pub fn new(page_size: usize) -> DatabaseHeader {
+        DatabaseHeader {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size: page_size as u32,
+            page_count: 1,
+            freelist_trunk: 0,
+            freelist_count: 0,
+            schema_cookie: 0,
+            change_counter: 0,
+        }
+    }
+
+    /// Checks that the header describes a database this build can open, stored
+    /// in a file of `file_length` bytes.
+    fn validate(&self, file_length: u64) -> Result<()> {
+        if self.magic != MAGIC {
+            return Err(Error::NotADatabase);
+        }
+        if self.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        if !is_valid_page_size(self.page_size as usize) {
+            return Err(Error::UnsupportedPageSize(self.page_size));
+        }
+        let page_size = self.page_size as u64;
+        if !file_length.is_multiple_of(page_size)
+            || self.page_count == 0
+            || self.page_count as u64 > file_length / page_size
+        {
+            return Err(Error::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+// Freelist Trunk Page Layout
+//
+// | next trunk | num leaves | leaf page nums... |
+//
+// Leaf pages on the freelist hold no data.
+const TRUNK_NEXT_OFFSET: usize = 0;
+const TRUNK_NUM_LEAVES_OFFSET: usize = TRUNK_NEXT_OFFSET + size_of::<u32>();
+const TRUNK_LEAVES_OFFSET: usize = TRUNK_NUM_LEAVES_OFFSET + size_of::<u32>();
+
+/// A point in a transaction that can be rolled back to.
+struct Savepoint {
+    name: String,
+    /// Contents of pages as of the savepoint. Holds every page that was dirty
+    /// then or has been written to storage since, while any other page still
+    /// reads from storage as it was at the savepoint.
+    pages: HashMap<u32, Box<[u8]>>,
+    num_pages: u32,
+    /// Whether the savepoint started the transaction, so releasing it commits.
+    starts_transaction: bool,
+}
 
 pub struct Pager<T> {
     storage: T,
-    file_length: u32,
+    /// Keeps the database intact if a write is interrupted. Temporary databases
+    /// don't need one, and WAL mode uses the log instead.
+    journal: Option<Journal<T>>,
+    /// Takes the place of the journal in WAL mode.
+    wal: Option<Wal<T>>,
+    page_size: usize,
+    file_length: u64,
     pub num_pages: u32,
-    pages: [Option<Box<[u8; PAGE_SIZE]>>; TABLE_MAX_PAGES],
+    /// Number of pages as of the last commit, every one of which was written with
+    /// its checksum.
+    committed_num_pages: u32,
+    pool: BufferPool,
+    config: PagerConfig,
+    /// Whether an explicit transaction is open, so changes aren't committed
+    /// until it ends.
+    in_transaction: bool,
+    /// Savepoints of the open transaction, from oldest to newest.
+    savepoints: Vec<Savepoint>,
+    /// Lock held on the database file. Taken as the transaction first reads and
+    /// writes, and released when it ends.
+    lock: Lock,
+    /// Change counter from the header as of the last transaction.
+    change_counter: u32,
+}
+
+/// How long to wait before trying again to take a lock that's in use.
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Takes `lock` on `storage`, retrying while another connection is in the way
+/// for up to `timeout`.
+fn wait_for_lock<T: Storage>(storage: &mut T, lock: Lock, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    while !storage.lock(lock)? {
+        if start.elapsed() >= timeout {
+            return Err(Error::Busy);
+        }
+        thread::sleep(BUSY_RETRY_INTERVAL);
+    }
+    Ok(())
 }
 
-impl<'a, T: Storage + 'a> Pager<T> {
-    pub fn open<F: StorageFactory<'a, T>>(
-        storage_factory: &'a mut F,
-        filename: &'a str,
-    ) -> Pager<T> {
-        let mut storage = storage_factory.open(filename);
+/// Gets whether the journal and log of `storage` can be deleted, which is only
+/// once no other connection has the database open and might still use them.
+/// Leaves the database locked exclusively if so.
+fn can_remove_files<T: Storage>(storage: &mut T) -> Result<bool> {
+    Ok(storage.lock(Lock::Exclusive)? && !storage.is_open_elsewhere()?)
+}
+
+impl<T: Storage> Pager<T> {
+    pub fn open<F: StorageFactory<T>>(
+        storage_factory: &mut F,
+        filename: &str,
+        mut config: PagerConfig,
+    ) -> Result<Pager<T>> {
+        let mut storage = storage_factory.open(filename)?;
+        // The journal and log are only opened once the database is locked, so no
+        // other connection deletes them in the meantime.
+        wait_for_lock(&mut storage, Lock::Shared, config.busy_timeout)?;
+        let mut wal = storage_factory.open_journal(&wal_filename(filename))?;
+        let mut journal = storage_factory.open_journal(&journal_filename(filename))?;
+        // A log is in use by the other connections that have the database open,
+        // and only otherwise was it left behind by one that crashed.
+        let mut wal_in_use = wal.size()? > 0 && storage.is_open_elsewhere()?;
+        if journal.size()? > 0
+            || (wal.size()? > 0 && !wal_in_use)
+            || (config.journal_mode == JournalMode::Wal && wal.size()? == 0)
+        {
+            // Recovering writes the database, and other readers may be waiting to
+            // do the same, so start over from no lock at all. Starting a log needs
+            // the database to itself too.
+            storage.lock(Lock::Unlocked)?;
+            wait_for_lock(&mut storage, Lock::Exclusive, config.busy_timeout)?;
+            wal_in_use = wal.size()? > 0 && storage.is_open_elsewhere()?;
+            // Whatever the mode, anything committed to a WAL goes back into the database first.
+            if !wal_in_use {
+                crate::wal::recover(&mut wal, &mut storage)?;
+            }
+            crate::journal::recover(&mut journal, &mut storage)?;
+        }
+        if wal_in_use && storage.size()? == 0 {
+            // Every page of a new database is still in the log.
+            if let Some(page_size) = crate::wal::page_size(&mut wal)? {
+                config.page_size = page_size;
+            }
+        }
+
+        let header = match Pager::read_header(&mut storage, &config) {
+            Ok(header) => header,
+            Err(error) => {
+                // Don't leave an empty journal and log behind for a file we can't open.
+                if can_remove_files(&mut storage)? {
+                    journal.remove()?;
+                    wal.remove()?;
+                }
+                return Err(error);
+            }
+        };
+        let mut pager = Pager::new(storage, header.as_ref(), config)?;
+        pager.lock = Lock::Shared;
 
-        let file_length = storage.size();
-        if file_length as usize % PAGE_SIZE != 0 {
-            panic!("Db file is not a whole number of pages. Corrupt file.");
+        // Connections follow the mode of the ones that have the database open.
+        let can_remove_files = can_remove_files(&mut pager.storage)?;
+        if wal_in_use {
+            pager.config.journal_mode = JournalMode::Wal;
+        } else if !can_remove_files {
+            pager.config.journal_mode = JournalMode::Rollback;
+        }
+        match pager.config.journal_mode {
+            JournalMode::Rollback => {
+                if can_remove_files {
+                    wal.remove()?;
+                }
+                pager.journal = Some(Journal::new(journal, pager.page_size));
+            }
+            JournalMode::Wal => {
+                if can_remove_files {
+                    journal.remove()?;
+                }
+                let mut wal = Wal::new(wal, pager.page_size);
+                if !wal_in_use {
+                    wal.start()?;
+                }
+                pager.wal = Some(wal);
+            }
         }
+        pager.unlock()?;
+        Ok(pager)
+    }
 
-        Pager {
+    /// Creates a pager over `storage`, which has `header` unless it's empty.
+    fn new(
+        mut storage: T,
+        header: Option<&DatabaseHeader>,
+        config: PagerConfig,
+    ) -> Result<Pager<T>> {
+        let page_size = header.map_or(config.page_size, |header| header.page_size as usize);
+        Ok(Pager {
+            file_length: storage.size()?,
             storage,
-            file_length: file_length as u32,
-            num_pages: file_length as u32 / PAGE_SIZE as u32,
-            pages: std::array::from_fn(|_| None),
+            journal: None,
+            wal: None,
+            page_size,
+            num_pages: header.map_or(0, |header| header.page_count),
+            committed_num_pages: header.map_or(0, |header| header.page_count),
+            pool: BufferPool::new(config.cache_capacity),
+            config,
+            in_transaction: false,
+            savepoints: Vec::new(),
+            lock: Lock::Unlocked,
+            change_counter: header.map_or(0, |header| header.change_counter),
+        })
+    }
+
+    /// Reads and checks the header of `storage`, unless it's empty.
+    fn read_header(storage: &mut T, config: &PagerConfig) -> Result<Option<DatabaseHeader>> {
+        let header = Pager::load_header(storage)?;
+        match &header {
+            Some(header) if header.magic != MAGIC && Pager::has_old_layout(storage)? => {
+                return Err(Error::OldLayout);
+            }
+            Some(header) => header.validate(storage.size()?)?,
+            None if !is_valid_page_size(config.page_size) => {
+                return Err(Error::UnsupportedPageSize(config.page_size as u32));
+            }
+            None => {}
+        }
+        Ok(header)
+    }
+
+    /// Reads the header of `storage` without checking it, unless it's empty.
+    fn load_header(storage: &mut T) -> Result<Option<DatabaseHeader>> {
+        let file_length = storage.size()?;
+        if file_length == 0 {
+            return Ok(None);
+        }
+        if file_length < MIN_PAGE_SIZE as u64 {
+            return Err(Error::NotADatabase);
+        }
+        // The header fits in the smallest page, whatever the page size.
+        let mut buf = vec![0u8; MIN_PAGE_SIZE];
+        let page = storage.page(HEADER_PAGE_NUM as usize, &mut buf)?;
+        Ok(Some(bincode::deserialize(page).unwrap()))
+    }
+
+    /// Gets whether `storage` was written before databases had a header page,
+    /// when the root node was on page 0 and every page was `OLD_PAGE_SIZE` bytes.
+    fn has_old_layout(storage: &mut T) -> Result<bool> {
+        if !storage.size()?.is_multiple_of(OLD_PAGE_SIZE as u64) {
+            return Ok(false);
+        }
+        let mut buf = vec![0u8; OLD_PAGE_SIZE];
+        let page = storage.page(0, &mut buf)?;
+        Ok(matches!(
+            bincode::deserialize::<common::Header>(page),
+            Ok(common::Header {
+                is_root: true,
+                parent: 0,
+                ..
+            })
+        ))
+    }
+
+    /// Takes `lock`, shared or reserved, at the start of a transaction.
+    ///
+    /// A journal left behind by a connection that crashed is played back first.
+    /// Other connections may have changed the database since the last transaction,
+    /// in which case the cached pages are dropped.
+    fn begin_transaction(&mut self, lock: Lock) -> Result<()> {
+        wait_for_lock(&mut self.storage, lock, self.config.busy_timeout)?;
+        self.lock = lock;
+        if let Some(journal) = &mut self.journal {
+            if journal.is_hot()? {
+                // Other readers may be waiting to do the same, so start over from
+                // no lock at all.
+                self.storage.lock(Lock::Unlocked)?;
+                self.lock = Lock::Unlocked;
+                wait_for_lock(&mut self.storage, Lock::Exclusive, self.config.busy_timeout)?;
+                journal.rollback(&mut self.storage)?;
+                self.storage.lock(lock)?;
+                self.lock = lock;
+            }
+        }
+
+        self.refresh()
+    }
+
+    /// Catches up with what other connections committed, reading the header from
+    /// the log in WAL mode if it's there.
+    fn refresh(&mut self) -> Result<()> {
+        let mut header = None;
+        if let Some(wal) = &mut self.wal {
+            wal.refresh()?;
+            if let Some(frame) = wal.find(HEADER_PAGE_NUM) {
+                let mut page = vec![0u8; self.page_size];
+                wal.read(frame, &mut page)?;
+                header = Some(bincode::deserialize::<DatabaseHeader>(&page).unwrap());
+            }
+        }
+        let header = match header {
+            Some(header) => Some(header),
+            None => Pager::load_header(&mut self.storage)?,
+        };
+        let file_length = self.storage.size()?;
+        let change_counter = header.as_ref().map_or(0, |header| header.change_counter);
+        if file_length != self.file_length || change_counter != self.change_counter {
+            self.pool.clear();
+        }
+        self.file_length = file_length;
+        self.num_pages = header.as_ref().map_or(0, |header| header.page_count);
+        self.committed_num_pages = self.num_pages;
+        self.change_counter = change_counter;
+        Ok(())
+    }
+
+    /// Locks the database for writing ahead of a change, waiting while another
+    /// connection is writing. Once a transaction has read, waiting could deadlock
+    /// with a writer waiting on it in turn, so the database being locked is an
+    /// error right away. So is another connection having committed to the log
+    /// since, as the transaction would overwrite a change it never saw.
+    pub fn begin_write(&mut self) -> Result<()> {
+        match self.lock {
+            Lock::Unlocked => self.begin_transaction(Lock::Reserved),
+            Lock::Shared => {
+                if !self.storage.lock(Lock::Reserved)? {
+                    return Err(Error::Busy);
+                }
+                if let Some(wal) = &mut self.wal {
+                    if wal.is_behind()? {
+                        self.storage.lock(Lock::Shared)?;
+                        return Err(Error::Busy);
+                    }
+                }
+                self.lock = Lock::Reserved;
+                Ok(())
+            }
+            Lock::Reserved | Lock::Exclusive => Ok(()),
+        }
+    }
+
+    /// Takes the exclusive lock needed to write to the database file, waiting for
+    /// other connections to finish reading.
+    fn lock_exclusive(&mut self) -> Result<()> {
+        if self.lock < Lock::Exclusive {
+            self.begin_write()?;
+            wait_for_lock(&mut self.storage, Lock::Exclusive, self.config.busy_timeout)?;
+            self.lock = Lock::Exclusive;
+        }
+        Ok(())
+    }
+
+    /// Releases the lock on the database at the end of a transaction.
+    fn unlock(&mut self) -> Result<()> {
+        self.storage.lock(Lock::Unlocked)?;
+        self.lock = Lock::Unlocked;
+        Ok(())
+    }
+
+    /// Commits any changes and deletes the journal, checkpointing first in WAL mode.
+    /// An open transaction is rolled back. The journal and log are kept while other
+    /// connections still have the database open, and the checkpoint is left to
+    /// them while they're reading.
+    pub fn close(mut self) -> Result<()> {
+        if self.in_transaction {
+            self.rollback()?;
+        }
+        self.commit()?;
+        if !self.storage.lock(Lock::Exclusive)? {
+            return Ok(());
+        }
+        self.lock = Lock::Exclusive;
+        if self.wal.is_some() {
+            self.refresh()?;
+            self.checkpoint()?;
+        }
+        if !self.storage.is_open_elsewhere()? {
+            if let Some(journal) = self.journal {
+                journal.remove()?;
+            }
+            if let Some(wal) = self.wal {
+                wal.remove()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// In WAL mode, copies every committed page from the log into the database file
+    /// so the log can start over, waiting for other connections to finish reading.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.wal.is_some() {
+            self.lock_exclusive()?;
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.checkpoint(&mut self.storage, self.config.synchronous)?;
+            self.file_length = self.storage.size()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty page back to storage as one atomic change.
+    ///
+    /// The original contents of the pages are saved in the journal and synced
+    /// first, then the pages are written and synced, and finally emptying the
+    /// journal marks the change as committed. In WAL mode, the pages are appended
+    /// to the log instead, and the change is committed once the log is synced.
+    /// Which of these syncs happen depends on the synchronous setting.
+    ///
+    /// Ends the open transaction, if any, and releases the lock on the database.
+    /// If the commit fails, the transaction stays open.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.has_changes() {
+            self.write_changes()?;
+        }
+        self.in_transaction = false;
+        self.savepoints.clear();
+        self.unlock()
+    }
+
+    /// Gets whether the transaction changed any pages, whether they're still
+    /// cached or already written to storage.
+    fn has_changes(&self) -> bool {
+        self.pool.dirty_pages().next().is_some()
+            || self.journal.as_ref().is_some_and(Journal::is_active)
+            || self.wal.as_ref().is_some_and(Wal::has_pending)
+    }
+
+    fn write_changes(&mut self) -> Result<()> {
+        if self.num_pages > 0 {
+            // Pages evicted to the log still need a frame to commit them, which
+            // the header always gets.
+            let mut header = self.header()?;
+            header.page_count = self.num_pages;
+            header.change_counter = header.change_counter.wrapping_add(1);
+            self.set_header(&header)?;
+            self.change_counter = header.change_counter;
+        }
+
+        let mut dirty_pages: Vec<_> = self
+            .pool
+            .dirty_pages()
+            .map(|(page_num, frame)| (page_num, frame.clone()))
+            .collect();
+        dirty_pages.sort_by_key(|(page_num, _)| *page_num);
+        if let Some(wal) = &mut self.wal {
+            let num_dirty_pages = dirty_pages.len();
+            for (i, (page_num, frame)) in dirty_pages.into_iter().enumerate() {
+                let page_count = (i + 1 == num_dirty_pages).then_some(self.num_pages);
+                set_checksum(page_num, &frame);
+                wal.append(page_num, frame.data(), page_count)?;
+                frame.set_dirty(false);
+            }
+            // In normal mode, the log is synced before checkpointing instead.
+            if self.config.synchronous == Synchronous::Full {
+                wal.sync()?;
+            }
+            return Ok(());
+        }
+
+        self.lock_exclusive()?;
+        if let Some(journal) = &mut self.journal {
+            // Save every page up front so the journal is only synced once.
+            for (page_num, _) in &dirty_pages {
+                journal.save(&mut self.storage, *page_num, self.file_length)?;
+            }
+        }
+        for (page_num, frame) in dirty_pages {
+            self.flush(page_num, &frame)?;
+        }
+
+        if let Some(journal) = &mut self.journal {
+            if journal.is_active() {
+                if self.config.synchronous != Synchronous::Off {
+                    self.storage.sync()?;
+                }
+                journal.finish()?;
+                // Otherwise a power loss can bring back the journal and roll back the commit.
+                if self.config.synchronous == Synchronous::Full {
+                    journal.sync()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets how changes are kept atomic, which follows the connections that
+    /// already had the database open rather than the config.
+    pub fn journal_mode(&self) -> JournalMode {
+        self.config.journal_mode
+    }
+
+    /// Gets when writes are synced.
+    pub fn synchronous(&self) -> Synchronous {
+        self.config.synchronous
+    }
+
+    /// Sets when writes are synced from now on.
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.config.synchronous = synchronous;
+    }
+
+    /// Gets how long to wait for another connection's lock.
+    pub fn busy_timeout(&self) -> Duration {
+        self.config.busy_timeout
+    }
+
+    /// Sets how long to wait for another connection's lock from now on.
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.config.busy_timeout = timeout;
+    }
+
+    /// Starts a transaction that lasts until `commit` or `rollback`.
+    pub fn begin(&mut self) {
+        self.in_transaction = true;
+    }
+
+    /// Gets whether an explicit transaction is open.
+    pub fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    /// Gets the lock this connection holds on the database.
+    pub fn lock(&self) -> Lock {
+        self.lock
+    }
+
+    /// Discards every change since the last commit, including pages already
+    /// written to storage, ends the open transaction, if any, and releases the
+    /// lock on the database.
+    pub fn rollback(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.savepoints.clear();
+        if self.has_changes() {
+            self.discard_changes()?;
+        }
+        self.unlock()
+    }
+
+    fn discard_changes(&mut self) -> Result<()> {
+        self.pool.clear();
+        if let Some(journal) = &mut self.journal {
+            journal.rollback(&mut self.storage)?;
+            self.file_length = self.storage.size()?;
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.rollback();
+        }
+
+        let has_header = self.file_length > 0
+            || self
+                .wal
+                .as_ref()
+                .is_some_and(|wal| wal.find(HEADER_PAGE_NUM).is_some());
+        self.num_pages = 0;
+        if has_header {
+            self.num_pages = self.header()?.page_count;
+        }
+        Ok(())
+    }
+
+    /// Adds a savepoint named `name`, starting a transaction if none is open.
+    pub fn savepoint(&mut self, name: &str) {
+        let pages = self
+            .pool
+            .dirty_pages()
+            .map(|(page_num, frame)| (page_num, frame.data().into()))
+            .collect();
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            pages,
+            num_pages: self.num_pages,
+            starts_transaction: !self.in_transaction,
+        });
+        self.in_transaction = true;
+    }
+
+    /// Gets whether the open transaction has a savepoint named `name`.
+    pub fn has_savepoint(&self, name: &str) -> bool {
+        self.savepoints
+            .iter()
+            .any(|savepoint| savepoint.name == name)
+    }
+
+    /// Gets the index of the newest savepoint named `name`, which must exist.
+    fn savepoint_index(&self, name: &str) -> usize {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .expect("no such savepoint")
+    }
+
+    /// Removes the newest savepoint named `name` and every savepoint after it,
+    /// keeping their changes. Commits if the savepoint started the transaction.
+    pub fn release(&mut self, name: &str) -> Result<()> {
+        let index = self.savepoint_index(name);
+        if self.savepoints[index].starts_transaction {
+            return self.commit();
+        }
+        self.savepoints.truncate(index);
+        Ok(())
+    }
+
+    /// Discards every change since the newest savepoint named `name`, which stays
+    /// in place while the savepoints after it are removed.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        let index = self.savepoint_index(name);
+        self.savepoints.truncate(index + 1);
+        let savepoint = &self.savepoints[index];
+
+        // Pages not held by the savepoint read the same from storage as they did
+        // at the savepoint, so only its pages have to be put back.
+        self.pool.clear();
+        for (&page_num, page) in &savepoint.pages {
+            self.pool.insert(page_num, page.clone()).set_dirty(true);
+        }
+        self.num_pages = savepoint.num_pages;
+        Ok(())
+    }
+
+    /// Keeps the contents of a page that's about to change in storage for every
+    /// savepoint that doesn't have it yet.
+    fn save_for_savepoints(&mut self, page_num: u32) -> Result<()> {
+        if self
+            .savepoints
+            .iter()
+            .all(|savepoint| savepoint.pages.contains_key(&page_num))
+        {
+            return Ok(());
+        }
+
+        let mut page = vec![0u8; self.page_size].into_boxed_slice();
+        self.read_page(page_num, &mut page)?;
+        for savepoint in &mut self.savepoints {
+            savepoint
+                .pages
+                .entry(page_num)
+                .or_insert_with(|| page.clone());
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole database with the pages of `source`, which must use
+    /// the same page size. Every page written and cut off is saved in the journal
+    /// first, so nothing changes for good until the transaction commits.
+    pub fn copy_from(&mut self, mut source: Pager<T>) -> Result<()> {
+        source.commit()?;
+        let mut buf = vec![0u8; self.page_size];
+        for i in 0..source.num_pages {
+            let page = source.storage.page(i as usize, &mut buf)?;
+            self.write_page(i, page)?;
+        }
+
+        self.pool.clear();
+        self.num_pages = source.num_pages;
+        if self.wal.is_some() {
+            // The database file is cut down when the commit is checkpointed.
+            return Ok(());
+        }
+
+        // Pages cut off the end have to be restored on rollback too.
+        self.lock_exclusive()?;
+        let file_length = source.num_pages as u64 * self.page_size as u64;
+        let num_pages = self.file_length.div_ceil(self.page_size as u64) as u32;
+        for page_num in source.num_pages..num_pages {
+            self.save_for_savepoints(page_num)?;
         }
+        if let Some(journal) = &mut self.journal {
+            for page_num in source.num_pages..num_pages {
+                journal.save(&mut self.storage, page_num, self.file_length)?;
+            }
+            if self.config.synchronous != Synchronous::Off {
+                journal.sync()?;
+            }
+        }
+        self.storage.truncate(file_length)?;
+        self.file_length = file_length;
+        Ok(())
+    }
+
+    /// Creates an empty pager on a temporary storage of the same kind.
+    pub fn create_temporary(&self) -> Result<Pager<T>> {
+        let config = PagerConfig {
+            page_size: self.page_size,
+            ..self.config.clone()
+        };
+        Pager::new(self.storage.create_temporary()?, None, config)
+    }
+
+    /// Gets the length of the database file, which in WAL mode leaves out pages
+    /// that are only in the log.
+    pub fn file_size(&mut self) -> Result<u64> {
+        self.storage.size()
+    }
+
+    /// Gets the bytes of every page that are left for its contents.
+    pub fn usable_size(&self) -> usize {
+        usable_size(self.page_size)
+    }
+
+    pub fn page(&mut self, page_num: u32) -> Result<Node> {
+        Ok(Node::from(self.get_page(page_num)?))
+    }
+
+    pub fn new_leaf_page(&mut self, page_num: u32) -> Result<LeafNode> {
+        let node = self.get_page(page_num)?;
+        Ok(LeafNode::new(node))
+    }
+
+    pub fn new_internal_page(&mut self, page_num: u32) -> Result<InternalNode> {
+        let node = self.get_page(page_num)?;
+        Ok(InternalNode::new(node))
+    }
+
+    /// Initializes the header page of a new database.
+    pub fn new_header_page(&mut self) -> Result<()> {
+        self.set_header(&DatabaseHeader::new(self.page_size))
+    }
+
+    /// Reads the database header from the header page.
+    pub fn header(&mut self) -> Result<DatabaseHeader> {
+        Ok(bincode::deserialize(self.get_buffer(HEADER_PAGE_NUM)?).unwrap())
+    }
+
+    fn set_header(&mut self, header: &DatabaseHeader) -> Result<()> {
+        bincode::serialize_into(self.get_buffer_mut(HEADER_PAGE_NUM)?, header).unwrap();
+        Ok(())
+    }
+
+    fn get_page(&mut self, page_num: u32) -> Result<CommonNode> {
+        CommonNode::new(self.get_frame(page_num)?, page_num)
+    }
+
+    /// Gets the contents of a page, leaving out its checksum.
+    fn get_buffer(&mut self, page_num: u32) -> Result<&[u8]> {
+        let frame = self.get_frame(page_num)?;
+        // The frame stays alive in the pool while `self` is borrowed.
+        Ok(unsafe { std::slice::from_raw_parts(frame.as_mut_ptr(), self.usable_size()) })
+    }
+
+    /// Gets the contents of a page for writing, marking the page as modified.
+    fn get_buffer_mut(&mut self, page_num: u32) -> Result<&mut [u8]> {
+        let frame = self.get_frame(page_num)?;
+        self.begin_write()?;
+        frame.set_dirty(true);
+        Ok(unsafe { std::slice::from_raw_parts_mut(frame.as_mut_ptr(), self.usable_size()) })
+    }
+
+    fn get_frame(&mut self, page_num: u32) -> Result<Rc<Frame>> {
+        if self.lock == Lock::Unlocked {
+            self.begin_transaction(Lock::Shared)?;
+        }
+        if let Some(frame) = self.pool.get(page_num) {
+            return Ok(frame);
+        }
+
+        // Cache miss. Make room by evicting one page and reusing its memory, then
+        // load from file.
+        let page = if self.pool.is_full() {
+            let (evicted_page_num, frame) = self.pool.evict().ok_or(Error::CacheFull)?;
+            if frame.is_dirty() {
+                self.flush(evicted_page_num, &frame)?;
+            }
+            Rc::try_unwrap(frame).ok().map(Frame::into_data)
+        } else {
+            None
+        };
+
+        let mut page = page.unwrap_or_else(|| vec![0u8; self.page_size].into_boxed_slice());
+        self.read_page(page_num, &mut page)?;
+        // Pages added since the last commit may still be zeros, having been skipped
+        // over when a page after them was evicted first.
+        if page_num < self.committed_num_pages || page.iter().any(|&byte| byte != 0) {
+            verify_checksum(page_num, &page)?;
+        }
+
+        if page_num >= self.num_pages {
+            self.num_pages = page_num + 1;
+        }
+        Ok(self.pool.insert(page_num, page))
+    }
+
+    /// Reads a page from the log in WAL mode, or else from the database file.
+    /// Pages past the end of the file are all zeros.
+    fn read_page(&mut self, page_num: u32, page: &mut [u8]) -> Result<()> {
+        let num_pages = self.file_length.div_ceil(self.page_size as u64);
+        let wal_frame = self.wal.as_ref().and_then(|wal| wal.find(page_num));
+        if let (Some(wal), Some(frame)) = (&mut self.wal, wal_frame) {
+            wal.read(frame, page)
+        } else if (page_num as u64) < num_pages {
+            self.storage.read(page_num as usize, page)
+        } else {
+            page.fill(0);
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self, page_num: u32, frame: &Frame) -> Result<()> {
+        set_checksum(page_num, frame);
+        self.write_page(page_num, frame.data())?;
+        frame.set_dirty(false);
+        Ok(())
+    }
+
+    /// Writes a page to storage, saving its original contents in the journal first.
+    /// In WAL mode, the page is appended to the log without being committed.
+    fn write_page(&mut self, page_num: u32, page: &[u8]) -> Result<()> {
+        self.save_for_savepoints(page_num)?;
+        if let Some(wal) = &mut self.wal {
+            return wal.append(page_num, page, None);
+        }
+        self.lock_exclusive()?;
+        if let Some(journal) = &mut self.journal {
+            journal.save(&mut self.storage, page_num, self.file_length)?;
+            if self.config.synchronous != Synchronous::Off {
+                journal.sync()?;
+            }
+        }
+        self.storage.write(page_num as usize, page)?;
+        self.file_length = self
+            .file_length
+            .max((page_num as u64 + 1) * self.page_size as u64);
+        Ok(())
+    }
+
+    /// Gets a page that isn't in use, preferring pages on the freelist
+    /// over growing the database file.
+    pub fn get_unused_page_num(&mut self) -> Result<u32> {
+        let mut header = self.header()?;
+        if header.freelist_trunk == 0 {
+            let page_num = self.num_pages;
+            self.num_pages += 1;
+            header.page_count = self.num_pages;
+            self.set_header(&header)?;
+            // Written on commit even if it's left empty, so it gets a checksum.
+            self.get_buffer_mut(page_num)?;
+            return Ok(page_num);
+        }
+
+        let trunk_page_num = header.freelist_trunk;
+        let trunk = self.get_buffer_mut(trunk_page_num)?;
+        let num_leaves = read_u32(trunk, TRUNK_NUM_LEAVES_OFFSET);
+        let page_num = if num_leaves > 0 {
+            // Take the last leaf listed on the trunk
+            let leaf_offset = TRUNK_LEAVES_OFFSET + (num_leaves as usize - 1) * size_of::<u32>();
+            write_u32(trunk, TRUNK_NUM_LEAVES_OFFSET, num_leaves - 1);
+            read_u32(trunk, leaf_offset)
+        } else {
+            // The trunk has no leaves left, so hand out the trunk itself
+            header.freelist_trunk = read_u32(trunk, TRUNK_NEXT_OFFSET);
+            trunk_page_num
+        };
+        header.freelist_count -= 1;
+        self.set_header(&header)?;
+
+        self.get_buffer_mut(page_num)?.fill(0);
+        Ok(page_num)
+    }
+
+    /// Gets how many leaves fit on a freelist trunk.
+    fn trunk_max_leaves(&self) -> u32 {
+        ((self.usable_size() - TRUNK_LEAVES_OFFSET) / size_of::<u32>()) as u32
     }
 
-    pub fn close(mut self) {
-        let mut pager = &mut self;
-        for i in 0..pager.num_pages as usize {
-            if pager.pages[i as usize].is_none() {
-                continue;
+    /// Lists every page on the freelist, trunks included, each with the page
+    /// that lists it, which is the header page for the first trunk.
+    ///
+    /// Stops at a trunk that's past the end of the database or was listed
+    /// before, so a damaged freelist can't send it round in circles.
+    pub fn freelist(&mut self) -> Result<Vec<(u32, u32)>> {
+        let mut pages = Vec::new();
+        let mut trunks = HashSet::new();
+        let mut listed_by = HEADER_PAGE_NUM;
+        let mut trunk_page_num = self.header()?.freelist_trunk;
+        let trunk_max_leaves = self.trunk_max_leaves();
+        while trunk_page_num != 0 {
+            pages.push((trunk_page_num, listed_by));
+            if trunk_page_num >= self.num_pages || !trunks.insert(trunk_page_num) {
+                break;
             }
-            pager.flush(i);
-            pager.pages[i] = None;
+            let trunk = self.get_buffer(trunk_page_num)?;
+            let num_leaves = read_u32(trunk, TRUNK_NUM_LEAVES_OFFSET).min(trunk_max_leaves);
+            for i in 0..num_leaves as usize {
+                let leaf_offset = TRUNK_LEAVES_OFFSET + i * size_of::<u32>();
+                pages.push((read_u32(trunk, leaf_offset), trunk_page_num));
+            }
+            listed_by = trunk_page_num;
+            trunk_page_num = read_u32(trunk, TRUNK_NEXT_OFFSET);
         }
+        Ok(pages)
+    }
+
+    /// Puts a page that is no longer used on the freelist.
+    pub fn free_page(&mut self, page_num: u32) -> Result<()> {
+        let mut header = self.header()?;
+        let trunk_page_num = header.freelist_trunk;
+        let trunk_max_leaves = self.trunk_max_leaves();
+        let trunk_num_leaves = match trunk_page_num {
+            0 => trunk_max_leaves,
+            _ => read_u32(self.get_buffer(trunk_page_num)?, TRUNK_NUM_LEAVES_OFFSET),
+        };
 
-        for i in 0..TABLE_MAX_PAGES {
-            let _ = pager.pages[i].take();
+        if trunk_num_leaves < trunk_max_leaves {
+            // List the page as a leaf of the current trunk
+            let trunk = self.get_buffer_mut(trunk_page_num)?;
+            let leaf_offset = TRUNK_LEAVES_OFFSET + trunk_num_leaves as usize * size_of::<u32>();
+            write_u32(trunk, leaf_offset, page_num);
+            write_u32(trunk, TRUNK_NUM_LEAVES_OFFSET, trunk_num_leaves + 1);
+        } else {
+            // The current trunk is full, so the page becomes the new trunk
+            let trunk = self.get_buffer_mut(page_num)?;
+            trunk.fill(0);
+            write_u32(trunk, TRUNK_NEXT_OFFSET, trunk_page_num);
+            header.freelist_trunk = page_num;
         }
+        header.freelist_count += 1;
+        self.set_header(&header)
+    }
+}
+
+/// Checksum of the contents of a page, which ends up at its end.
+pub fn page_checksum(page_num: u32, page: &[u8]) -> u32 {
+    let checksum = checksum::update(checksum::INITIAL, &page_num.to_le_bytes());
+    checksum::update(checksum, &page[..usable_size(page.len())])
+}
+
+/// Stores the checksum of the page in `frame` before it's written to storage.
+fn set_checksum(page_num: u32, frame: &Frame) {
+    let page = unsafe { std::slice::from_raw_parts_mut(frame.as_mut_ptr(), frame.data().len()) };
+    let checksum = page_checksum(page_num, page);
+    write_u32(page, usable_size(page.len()), checksum);
+}
+
+/// Checks a page read from storage against its checksum.
+fn verify_checksum(page_num: u32, page: &[u8]) -> Result<()> {
+    if read_u32(page, usable_size(page.len())) != page_checksum(page_num, page) {
+        return Err(Error::CorruptPage(page_num));
+    }
+    Ok(())
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(
+        buffer[offset..offset + size_of::<u32>()]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + size_of::<u32>()].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeType;
+    use crate::storage::{
+        FileStorage, FileStorageFactory, SharedMemoryStorage, SharedMemoryStorageFactory, TempPath,
+    };
+    use std::cell::Cell;
+    use std::path::Path;
+
+    fn new_pager(factory: &mut SharedMemoryStorageFactory) -> Pager<SharedMemoryStorage> {
+        let mut pager = Pager::open(factory, "foobar", PagerConfig::default()).unwrap();
+        pager.new_header_page().unwrap();
+        pager
+    }
+
+    #[test]
+    fn allocate_appends_without_free_pages() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        let mut pager = new_pager(&mut factory);
+        assert_eq!(pager.get_unused_page_num().unwrap(), 1);
+        assert_eq!(pager.get_unused_page_num().unwrap(), 2);
+        assert_eq!(pager.num_pages, 3);
+    }
+
+    #[test]
+    fn free_pages_are_reused() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        let mut pager = new_pager(&mut factory);
+        let pages: Vec<_> = (0..5)
+            .map(|_| pager.get_unused_page_num().unwrap())
+            .collect();
+        pager.get_buffer_mut(pages[1]).unwrap().fill(0xff);
+
+        for &page_num in &pages[1..4] {
+            pager.free_page(page_num).unwrap();
+        }
+        assert_eq!(pager.header().unwrap().freelist_count, 3);
+
+        let mut reused: Vec<_> = (0..3)
+            .map(|_| pager.get_unused_page_num().unwrap())
+            .collect();
+        reused.sort();
+        assert_eq!(reused, pages[1..4]);
+        assert_eq!(pager.header().unwrap().freelist_count, 0);
+        assert_eq!(pager.header().unwrap().freelist_trunk, 0);
+
+        // Recycled pages come back zeroed
+        assert!(pager.get_buffer(pages[1]).unwrap().iter().all(|&b| b == 0));
+
+        // Once the freelist is empty, the file grows again
+        assert_eq!(pager.get_unused_page_num().unwrap(), 6);
+    }
+
+    #[test]
+    fn freelist_survives_reopen() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        {
+            let mut pager = new_pager(&mut factory);
+            for _ in 0..4 {
+                let page_num = pager.get_unused_page_num().unwrap();
+                pager.get_buffer_mut(page_num).unwrap();
+            }
+            pager.free_page(2).unwrap();
+            pager.free_page(3).unwrap();
+            pager.close().unwrap();
+        }
+
+        let mut pager: Pager<_> =
+            Pager::open(&mut factory, "foobar", PagerConfig::default()).unwrap();
+        assert_eq!(pager.header().unwrap().freelist_count, 2);
+        let mut reused = vec![
+            pager.get_unused_page_num().unwrap(),
+            pager.get_unused_page_num().unwrap(),
+        ];
+        reused.sort();
+        assert_eq!(reused, vec![2, 3]);
+        assert_eq!(pager.get_unused_page_num().unwrap(), 5);
     }
 
-    pub fn page(&mut self, page_num: u32) -> Node {
-        Node::from(self.get_page(page_num))
+    fn dirty_pages(pager: &Pager<SharedMemoryStorage>) -> Vec<u32> {
+        let mut pages: Vec<_> = pager
+            .pool
+            .dirty_pages()
+            .map(|(page_num, _)| page_num)
+            .collect();
+        pages.sort();
+        pages
     }
 
-    pub fn new_leaf_page(&mut self, page_num: u32) -> LeafNode {
-        let node = self.get_page(page_num);
-        LeafNode::new(node)
+    #[test]
+    fn only_modified_pages_are_dirty() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        {
+            let mut pager = new_pager(&mut factory);
+            for _ in 0..3 {
+                let page_num = pager.get_unused_page_num().unwrap();
+                pager.new_leaf_page(page_num).unwrap();
+            }
+            assert_eq!(dirty_pages(&pager), vec![0, 1, 2, 3]);
+            pager.close().unwrap();
+        }
+
+        let mut pager: Pager<_> =
+            Pager::open(&mut factory, "foobar", PagerConfig::default()).unwrap();
+        pager.header().unwrap();
+        for page_num in 1..4 {
+            pager.page(page_num).unwrap().unwrap_leaf().num_cells();
+        }
+        assert!(dirty_pages(&pager).is_empty());
+
+        pager.page(2).unwrap().unwrap_leaf().set_next_leaf(3);
+        assert_eq!(dirty_pages(&pager), vec![2]);
+
+        pager.free_page(1).unwrap();
+        assert_eq!(dirty_pages(&pager), vec![0, 1, 2]);
     }
 
-    pub fn new_internal_page(&mut self, page_num: u32) -> InternalNode {
-        let node = self.get_page(page_num);
-        InternalNode::new(node)
+    #[test]
+    fn cache_holds_no_more_than_its_capacity() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            cache_capacity: 2,
+            ..PagerConfig::default()
+        };
+        let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", config).unwrap();
+        for page_num in 1..=4 {
+            pager.new_leaf_page(page_num).unwrap();
+            assert!(pager.pool.len() <= 2);
+        }
+
+        let pinned: Vec<_> = (1..=2)
+            .map(|page_num| pager.page(page_num).unwrap())
+            .collect();
+        assert!(matches!(pager.page(3), Err(Error::CacheFull)));
+        drop(pinned);
+        pager.page(3).unwrap();
+        assert_eq!(pager.pool.len(), 2);
     }
 
-    fn get_page(&mut self, page_num: u32) -> CommonNode {
-        let page_num = page_num as usize;
-        if self.pages[page_num].is_none() {
-            // Cache miss. Allocate memory and load from file.
-            let mut page = Box::new([0u8; PAGE_SIZE]);
-            let mut num_pages = self.file_length as usize / PAGE_SIZE;
+    fn contents(storage: &mut SharedMemoryStorage) -> Vec<u8> {
+        let mut contents = vec![0u8; storage.size().unwrap() as usize];
+        storage.read(0, &mut contents).unwrap();
+        contents
+    }
 
-            // We might save a partial page at the end of the file
-            if self.file_length as usize % PAGE_SIZE != 0 {
-                num_pages += 1;
+    #[test]
+    fn interrupted_transaction_is_rolled_back() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        {
+            let mut pager = new_pager(&mut factory);
+            for byte in 1..=4 {
+                let page_num = pager.get_unused_page_num().unwrap();
+                pager.get_buffer_mut(page_num).unwrap().fill(byte);
             }
+            pager.close().unwrap();
+        }
+        let mut database = factory.open("foobar").unwrap();
+        let committed = contents(&mut database);
 
-            if page_num <= num_pages {
-                self.storage.read(page_num, page.as_mut_slice());
+        {
+            let config = PagerConfig {
+                cache_capacity: 2,
+                ..PagerConfig::default()
+            };
+            let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", config).unwrap();
+            for page_num in 1..=4 {
+                pager.get_buffer_mut(page_num).unwrap().fill(0xff);
+            }
+            for _ in 0..3 {
+                let page_num = pager.get_unused_page_num().unwrap();
+                pager.get_buffer_mut(page_num).unwrap().fill(0xff);
             }
+            pager.free_page(2).unwrap();
+            pager.get_buffer(1).unwrap();
+            // Dropped without closing, like a crash, after evictions wrote some pages
+        }
+        assert_ne!(contents(&mut database), committed);
+        assert!(database.size().unwrap() > committed.len() as u64);
+        let mut journal = factory.open(&journal_filename("foobar")).unwrap();
+        assert!(journal.size().unwrap() > 0);
+
+        let mut pager: Pager<_> =
+            Pager::open(&mut factory, "foobar", PagerConfig::default()).unwrap();
+        assert_eq!(contents(&mut database), committed);
+        assert_eq!(journal.size().unwrap(), 0);
+        assert_eq!(pager.num_pages, 5);
+        assert!(pager.get_buffer(3).unwrap().iter().all(|&b| b == 3));
+    }
 
-            self.pages[page_num] = Some(page);
+    #[test]
+    fn damaged_pages_fail_their_checksum() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        let mut pager = new_pager(&mut factory);
+        for byte in 1..=3 {
+            let page_num = pager.get_unused_page_num().unwrap();
+            pager.get_buffer_mut(page_num).unwrap().fill(byte);
+        }
+        pager.close().unwrap();
+
+        let mut database = factory.open("foobar").unwrap();
+        let mut page = [0u8; DEFAULT_PAGE_SIZE];
+        database.read(1, &mut page).unwrap();
+        page[10] ^= 0x10;
+        database.write(1, &page).unwrap();
+        // A page written where another one belongs
+        database.read(3, &mut page).unwrap();
+        database.write(2, &page).unwrap();
+        // Zeros where the database has a page, rather than one never written
+        database.write(3, &[0u8; DEFAULT_PAGE_SIZE]).unwrap();
 
-            if page_num >= self.num_pages as usize {
-                self.num_pages = page_num as u32 + 1;
+        let mut pager: Pager<_> =
+            Pager::open(&mut factory, "foobar", PagerConfig::default()).unwrap();
+        assert!(matches!(pager.get_buffer(1), Err(Error::CorruptPage(1))));
+        assert!(matches!(pager.get_buffer(2), Err(Error::CorruptPage(2))));
+        assert!(matches!(pager.get_buffer(3), Err(Error::CorruptPage(3))));
+    }
+
+    #[test]
+    fn journal_is_removed_on_close() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        let mut pager = new_pager(&mut factory);
+        let page_num = pager.get_unused_page_num().unwrap();
+        pager.get_buffer_mut(page_num).unwrap().fill(1);
+        pager.close().unwrap();
+
+        let mut journal = factory.open(&journal_filename("foobar")).unwrap();
+        assert_eq!(journal.size().unwrap(), 0);
+    }
+
+    fn wal_config(cache_capacity: usize) -> PagerConfig {
+        PagerConfig {
+            cache_capacity,
+            journal_mode: JournalMode::Wal,
+            ..PagerConfig::default()
+        }
+    }
+
+    #[test]
+    fn wal_commits_leave_database_file_alone() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        {
+            let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", wal_config(100)).unwrap();
+            pager.new_header_page().unwrap();
+            for byte in 1..=3 {
+                let page_num = pager.get_unused_page_num().unwrap();
+                pager.get_buffer_mut(page_num).unwrap().fill(byte);
             }
+            pager.commit().unwrap();
+            assert_eq!(factory.open("foobar").unwrap().size().unwrap(), 0);
+
+            // Reads come from the log
+            pager.pool.clear();
+            assert!(pager.get_buffer(2).unwrap().iter().all(|&b| b == 2));
+            pager.get_buffer_mut(2).unwrap().fill(0xff);
+            pager.commit().unwrap();
+            // Dropped without closing, like a crash
+        }
+
+        let mut wal = factory.open(&wal_filename("foobar")).unwrap();
+        assert!(wal.size().unwrap() > 0);
+        let mut pager: Pager<_> =
+            Pager::open(&mut factory, "foobar", PagerConfig::default()).unwrap();
+        assert_eq!(wal.size().unwrap(), 0);
+        assert_eq!(pager.num_pages, 4);
+        assert!(pager.get_buffer(1).unwrap().iter().all(|&b| b == 1));
+        assert!(pager.get_buffer(2).unwrap().iter().all(|&b| b == 0xff));
+        assert!(pager.get_buffer(3).unwrap().iter().all(|&b| b == 3));
+    }
+
+    #[test]
+    fn uncommitted_wal_frames_are_ignored() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        {
+            let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", wal_config(2)).unwrap();
+            pager.new_header_page().unwrap();
+            for byte in 1..=4 {
+                let page_num = pager.get_unused_page_num().unwrap();
+                pager.get_buffer_mut(page_num).unwrap().fill(byte);
+            }
+            pager.commit().unwrap();
+
+            for page_num in 1..=4 {
+                pager.get_buffer_mut(page_num).unwrap().fill(0xff);
+            }
+            // Evicted pages are read back from the log
+            assert!(pager.get_buffer(1).unwrap().iter().all(|&b| b == 0xff));
+            pager.get_unused_page_num().unwrap();
+        }
+
+        let mut pager: Pager<_> =
+            Pager::open(&mut factory, "foobar", PagerConfig::default()).unwrap();
+        assert_eq!(pager.num_pages, 5);
+        for page_num in 1..=4 {
+            let byte = page_num as u8;
+            assert!(pager
+                .get_buffer(page_num)
+                .unwrap()
+                .iter()
+                .all(|&b| b == byte));
+        }
+    }
+
+    #[test]
+    fn checkpoint_copies_wal_into_database() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", wal_config(100)).unwrap();
+        pager.new_header_page().unwrap();
+        for byte in 1..=3 {
+            let page_num = pager.get_unused_page_num().unwrap();
+            pager.get_buffer_mut(page_num).unwrap().fill(byte);
+        }
+        pager.commit().unwrap();
+        pager.checkpoint().unwrap();
+
+        let mut database = factory.open("foobar").unwrap();
+        let mut wal = factory.open(&wal_filename("foobar")).unwrap();
+        assert_eq!(database.size().unwrap(), 4 * DEFAULT_PAGE_SIZE as u64);
+        // Only the header is left in the log
+        assert!(wal.size().unwrap() < DEFAULT_PAGE_SIZE as u64 * 2);
+        let mut page = [0u8; DEFAULT_PAGE_SIZE];
+        let usable_size = usable_size(DEFAULT_PAGE_SIZE);
+        database.read(3, &mut page).unwrap();
+        assert!(page[..usable_size].iter().all(|&b| b == 3));
+
+        // The log starts over after a checkpoint
+        pager.get_buffer_mut(1).unwrap().fill(0xff);
+        pager.close().unwrap();
+        database.read(1, &mut page).unwrap();
+        assert!(page[..usable_size].iter().all(|&b| b == 0xff));
+        assert_eq!(wal.size().unwrap(), 0);
+    }
+
+    /// Counts the syncs of every storage it opens.
+    struct SyncCountingFactory {
+        factory: SharedMemoryStorageFactory,
+        syncs: Rc<Cell<usize>>,
+    }
+
+    struct SyncCountingStorage {
+        storage: SharedMemoryStorage,
+        syncs: Rc<Cell<usize>>,
+    }
+
+    impl StorageFactory<SyncCountingStorage> for SyncCountingFactory {
+        fn open(&mut self, filename: &str) -> Result<SyncCountingStorage> {
+            Ok(SyncCountingStorage {
+                storage: self.factory.open(filename)?,
+                syncs: self.syncs.clone(),
+            })
+        }
+    }
+
+    impl Storage for SyncCountingStorage {
+        fn size(&mut self) -> Result<u64> {
+            self.storage.size()
+        }
+
+        fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+            self.storage.read(page_num, buf)
+        }
+
+        fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+            self.storage.write(page_num, buf)
+        }
+
+        fn truncate(&mut self, size: u64) -> Result<()> {
+            self.storage.truncate(size)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.syncs.set(self.syncs.get() + 1);
+            self.storage.sync()
         }
 
-        let buffer = (&mut self.pages[page_num]).as_mut().unwrap().as_mut_ptr();
-        CommonNode::new(buffer)
+        fn remove(self) -> Result<()> {
+            self.storage.remove()
+        }
+
+        fn lock(&mut self, lock: Lock) -> Result<bool> {
+            self.storage.lock(lock)
+        }
+
+        fn is_open_elsewhere(&mut self) -> Result<bool> {
+            self.storage.is_open_elsewhere()
+        }
+
+        fn create_temporary(&self) -> Result<SyncCountingStorage> {
+            Ok(SyncCountingStorage {
+                storage: self.storage.create_temporary()?,
+                syncs: self.syncs.clone(),
+            })
+        }
     }
 
-    fn flush(&mut self, page_num: usize) {
-        let page = self.pages[page_num]
-            .as_ref()
-            .expect("Tried to flush null page");
-        self.storage.write(page_num, page.as_slice());
+    #[test]
+    fn synchronous_controls_syncs_per_commit() {
+        let cases = [
+            (JournalMode::Rollback, Synchronous::Off, 0),
+            (JournalMode::Rollback, Synchronous::Normal, 2),
+            (JournalMode::Rollback, Synchronous::Full, 3),
+            (JournalMode::Wal, Synchronous::Off, 0),
+            (JournalMode::Wal, Synchronous::Normal, 0),
+            (JournalMode::Wal, Synchronous::Full, 1),
+        ];
+        for (journal_mode, synchronous, expected_syncs) in cases {
+            let mut factory = SyncCountingFactory {
+                factory: SharedMemoryStorageFactory::new(),
+                syncs: Rc::default(),
+            };
+            let config = PagerConfig {
+                journal_mode,
+                synchronous,
+                ..PagerConfig::default()
+            };
+            let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", config.clone()).unwrap();
+            pager.new_header_page().unwrap();
+            let page_num = pager.get_unused_page_num().unwrap();
+            pager.commit().unwrap();
+
+            factory.syncs.set(0);
+            pager.get_buffer_mut(page_num).unwrap().fill(1);
+            pager.commit().unwrap();
+            assert_eq!(
+                factory.syncs.get(),
+                expected_syncs,
+                "{journal_mode:?} {synchronous}"
+            );
+            pager.close().unwrap();
+
+            let mut pager: Pager<_> = Pager::open(&mut factory, "foobar", config).unwrap();
+            assert!(pager.get_buffer(page_num).unwrap().iter().all(|&b| b == 1));
+        }
     }
 
-    // TODO: Until we start recycling free pages, new pages will always
-    // go onto the end of the database file.
-    pub fn get_unused_page_num(&self) -> u32 {
-        self.num_pages
+    #[test]
+    fn connections_lock_each_other_out() {
+        let path = TempPath::new();
+        let filename = path.filename();
+        let config = PagerConfig {
+            busy_timeout: Duration::from_millis(10),
+            ..PagerConfig::default()
+        };
+        let mut first: Pager<FileStorage> =
+            Pager::open(&mut FileStorageFactory, filename, config.clone()).unwrap();
+        first.new_header_page().unwrap();
+        let page_num = first.get_unused_page_num().unwrap();
+        first.get_buffer_mut(page_num).unwrap().fill(1);
+        first.commit().unwrap();
+
+        let mut second: Pager<FileStorage> =
+            Pager::open(&mut FileStorageFactory, filename, config).unwrap();
+        first.begin_write().unwrap();
+        first.get_buffer_mut(page_num).unwrap().fill(2);
+        assert!(matches!(second.begin_write(), Err(Error::Busy)));
+
+        // Reading goes on while the first connection prepares its changes, but
+        // they can't be written until it's done.
+        assert_eq!(second.get_buffer(page_num).unwrap()[0], 1);
+        assert!(matches!(first.commit(), Err(Error::Busy)));
+        second.rollback().unwrap();
+        first.commit().unwrap();
+        assert_eq!(second.get_buffer(page_num).unwrap()[0], 2);
+        second.rollback().unwrap();
+
+        // The journal stays until the last connection closes.
+        let journal_path = journal_filename(filename);
+        first.close().unwrap();
+        assert!(Path::new(&journal_path).exists());
+        second.close().unwrap();
+        assert!(!Path::new(&journal_path).exists());
+    }
+
+    #[test]
+    fn wal_readers_keep_going_while_a_writer_appends() {
+        let path = TempPath::new();
+        let filename = path.filename();
+        let config = PagerConfig {
+            busy_timeout: Duration::from_millis(10),
+            ..wal_config(1)
+        };
+        let mut first: Pager<FileStorage> =
+            Pager::open(&mut FileStorageFactory, filename, config.clone()).unwrap();
+        first.new_header_page().unwrap();
+        let page_num = first.get_unused_page_num().unwrap();
+        first.get_buffer_mut(page_num).unwrap().fill(1);
+        first.commit().unwrap();
+
+        // The second connection shares the log whatever its config says
+        let config = PagerConfig {
+            journal_mode: JournalMode::Rollback,
+            ..config
+        };
+        let mut second: Pager<FileStorage> =
+            Pager::open(&mut FileStorageFactory, filename, config).unwrap();
+        assert_eq!(second.journal_mode(), JournalMode::Wal);
+
+        // Evicting the page appends it to the log without committing it
+        first.get_buffer_mut(page_num).unwrap().fill(2);
+        first.get_buffer(HEADER_PAGE_NUM).unwrap();
+        assert_eq!(second.get_buffer(page_num).unwrap()[0], 1);
+        first.commit().unwrap();
+
+        // The reader keeps what it saw until its transaction ends, and can't
+        // write on top of it
+        assert_eq!(second.get_buffer(page_num).unwrap()[0], 1);
+        assert!(matches!(second.begin_write(), Err(Error::Busy)));
+        second.rollback().unwrap();
+        assert_eq!(second.get_buffer(page_num).unwrap()[0], 2);
+
+        // Checkpointing waits for readers
+        assert!(matches!(first.checkpoint(), Err(Error::Busy)));
+        second.rollback().unwrap();
+        first.checkpoint().unwrap();
+        first.commit().unwrap();
+        assert_eq!(second.get_buffer(page_num).unwrap()[0], 2);
+        second.rollback().unwrap();
+
+        // The log stays until the last connection closes.
+        let wal_path = wal_filename(filename);
+        first.close().unwrap();
+        assert!(Path::new(&wal_path).exists());
+        second.close().unwrap();
+        assert!(!Path::new(&wal_path).exists());
+    }
+
+    #[test]
+    fn header_is_validated_on_open() {
+        let mut factory = SharedMemoryStorageFactory::new();
+        new_pager(&mut factory).close().unwrap();
+
+        let mut storage = factory.open("foobar").unwrap();
+        let mut page = [0u8; DEFAULT_PAGE_SIZE];
+        storage.read(0, &mut page).unwrap();
+        let header: DatabaseHeader = bincode::deserialize(&page).unwrap();
+        assert_eq!(header.magic, MAGIC);
+        assert_eq!(header.page_count, 1);
+        assert!(Pager::open(&mut factory, "foobar", PagerConfig::default()).is_ok());
+
+        let mut write_header = |header: &DatabaseHeader| {
+            let mut page = [0u8; DEFAULT_PAGE_SIZE];
+            bincode::serialize_into(page.as_mut_slice(), header).unwrap();
+            storage.write(0, &page).unwrap();
+        };
+        let open = |factory: &mut SharedMemoryStorageFactory| {
+            Pager::open(factory, "foobar", PagerConfig::default()).err()
+        };
+
+        // Files from before pages had checksums
+        write_header(&DatabaseHeader {
+            version: 1,
+            ..DatabaseHeader::new(DEFAULT_PAGE_SIZE)
+        });
+        assert!(matches!(
+            open(&mut factory),
+            Some(Error::UnsupportedVersion(1))
+        ));
+
+        write_header(&DatabaseHeader {
+            page_size: 512,
+            ..DatabaseHeader::new(DEFAULT_PAGE_SIZE)
+        });
+        assert!(matches!(
+            open(&mut factory),
+            Some(Error::UnsupportedPageSize(512))
+        ));
+
+        write_header(&DatabaseHeader {
+            page_count: 3,
+            ..DatabaseHeader::new(DEFAULT_PAGE_SIZE)
+        });
+        assert!(matches!(open(&mut factory), Some(Error::Corrupt)));
+
+        write_header(&DatabaseHeader {
+            magic: *b"SQLite format 3\0",
+            ..DatabaseHeader::new(DEFAULT_PAGE_SIZE)
+        });
+        assert!(matches!(open(&mut factory), Some(Error::NotADatabase)));
+
+        // Files from before the header page, with the root leaf on page 0
+        let mut root = [0u8; DEFAULT_PAGE_SIZE];
+        root[..2].copy_from_slice(&[NodeType::Leaf as u8, 1]);
+        storage.write(0, &root).unwrap();
+        assert!(matches!(open(&mut factory), Some(Error::OldLayout)));
     }
 }
@@ -1,3 +1,4 @@
+use crate::error::Result;
 use crate::node::common;
 use crate::node::leaf;
 use crate::node::Node;
@@ -6,8 +7,13 @@ use crate::serialization::ROW_SIZE;
 use crate::storage::Storage;
 use std::io::{BufRead, Write};
 
-pub fn print_prompt() {
-    print!("db > ");
+/// Prints the prompt, which is marked with a `*` while a transaction is open.
+pub fn print_prompt(in_transaction: bool) {
+    if in_transaction {
+        print!("db*> ");
+    } else {
+        print!("db > ");
+    }
     std::io::stdout().flush().unwrap();
 }
 
@@ -18,17 +24,24 @@ pub fn read_input() -> String {
     input.trim_end().to_string()
 }
 
-pub fn print_constants() {
+pub fn print_constants(usable_size: usize) {
     println!("ROW_SIZE: {}", ROW_SIZE);
     println!("COMMON_NODE_HEADER_SIZE: {}", common::HEADER_SIZE);
     println!("LEAF_NODE_HEADER_SIZE: {}", leaf::HEADER_SIZE);
     println!("LEAF_NODE_CELL_SIZE: {}", leaf::CELL_SIZE);
-    println!("LEAF_NODE_SPACE_FOR_CELLS: {}", leaf::SPACE_FOR_CELLS);
-    println!("LEAF_NODE_MAX_CELLS: {}", leaf::MAX_CELLS);
+    println!(
+        "LEAF_NODE_SPACE_FOR_CELLS: {}",
+        leaf::space_for_cells(usable_size)
+    );
+    println!("LEAF_NODE_MAX_CELLS: {}", leaf::max_cells(usable_size));
 }
 
-pub fn print_tree<T: Storage>(pager: &mut Pager<T>, page_num: u32, indentation_level: u32) {
-    let node = pager.page(page_num);
+pub fn print_tree<T: Storage>(
+    pager: &mut Pager<T>,
+    page_num: u32,
+    indentation_level: u32,
+) -> Result<()> {
+    let node = pager.page(page_num)?;
 
     match node {
         Node::Leaf(leaf_node) => {
@@ -45,16 +58,17 @@ pub fn print_tree<T: Storage>(pager: &mut Pager<T>, page_num: u32, indentation_l
             indent(indentation_level);
             println!("- internal (size {})", num_keys);
             for i in 0..num_keys {
-                let child = internal_node.child(i);
-                print_tree(pager, child, indentation_level + 1);
+                let child = internal_node.child(i)?;
+                print_tree(pager, child, indentation_level + 1)?;
 
                 indent(indentation_level + 1);
                 println!("- key {}", internal_node.key(i));
             }
             let child = internal_node.right_child();
-            print_tree(pager, child, indentation_level + 1);
+            print_tree(pager, child, indentation_level + 1)?;
         }
     }
+    Ok(())
 }
 
 fn indent(level: u32) {
@@ -50,7 +50,7 @@ mod tests {
         let expected = Row {
             id: u32::MAX,
             username: String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
-            email: std::iter::repeat("a").take(255).collect(),
+            email: "a".repeat(255),
         };
         let mut row_bytes = [0u8; ROW_SIZE];
 
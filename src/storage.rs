@@ -1,113 +1,913 @@
-use crate::pager::PAGE_SIZE;
+#[cfg(test)]
+use crate::error::Error;
+use crate::error::Result;
+use libc::{c_int, c_short, off_t, F_RDLCK, F_UNLCK, F_WRLCK, SEEK_SET};
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
 #[cfg(test)]
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+#[cfg(test)]
+use std::rc::{Rc, Weak};
 
-pub trait StorageFactory<'a, S: Storage + 'a> {
-    fn open(&'a mut self, filename: &'a str) -> S;
+pub trait StorageFactory<S: Storage> {
+    fn open(&mut self, filename: &str) -> Result<S>;
+
+    /// Opens the rollback journal or write-ahead log kept next to a database,
+    /// which wrappers may store differently from the database's own pages.
+    fn open_journal(&mut self, filename: &str) -> Result<S> {
+        self.open(filename)
+    }
 }
 
 pub struct FileStorageFactory;
 
-impl<'a> StorageFactory<'a, FileStorage> for FileStorageFactory {
-    fn open(&mut self, filename: &str) -> FileStorage {
-        FileStorage::new(filename)
+impl StorageFactory<FileStorage> for FileStorageFactory {
+    fn open(&mut self, filename: &str) -> Result<FileStorage> {
+        FileStorage::new(PathBuf::from(filename), false)
     }
 }
 
+/// How far a connection has locked a database, from least to most restrictive.
+///
+/// Any number of connections can hold a shared lock while they read. One of
+/// them can also hold a reserved lock while it prepares changes, and it waits
+/// for the others to finish reading before taking an exclusive lock to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lock {
+    Unlocked,
+    Shared,
+    Reserved,
+    Exclusive,
+}
+
+/// Page-addressed storage. Pages are `buf.len()` bytes, so page `n` starts at
+/// byte `n * buf.len()`.
 pub trait Storage {
-    fn size(&mut self) -> u64;
-    fn read(&mut self, page_num: usize, buf: &mut [u8]);
-    fn write(&mut self, page_num: usize, buf: &[u8]);
+    fn size(&mut self) -> Result<u64>;
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()>;
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()>;
+
+    /// Shrinks the storage to `size` bytes.
+    fn truncate(&mut self, size: u64) -> Result<()>;
+
+    /// Makes every write so far durable.
+    fn sync(&mut self) -> Result<()>;
+
+    /// Deletes the storage, so it's empty the next time it's opened.
+    fn remove(self) -> Result<()>
+    where
+        Self: Sized;
+
+    /// Raises or lowers the lock this connection holds to `lock`. Returns false,
+    /// keeping the current lock, if another connection holds a lock in the way.
+    fn lock(&mut self, lock: Lock) -> Result<bool>;
+
+    /// Gets whether another connection has the storage open.
+    fn is_open_elsewhere(&mut self) -> Result<bool>;
+
+    /// Gets page `page_num`, which is as long as `buf`. Storages that can hand
+    /// out the page without copying it do, and the rest read it into `buf`.
+    fn page<'a>(&'a mut self, page_num: usize, buf: &'a mut [u8]) -> Result<&'a [u8]> {
+        self.read(page_num, buf)?;
+        Ok(buf)
+    }
+
+    /// Creates an empty storage of the same kind for scratch work,
+    /// which is removed once dropped.
+    fn create_temporary(&self) -> Result<Self>
+    where
+        Self: Sized;
 }
 
+// Lock Bytes
+//
+// | pending | reserved | shared | connection |
+//
+// Locks are taken on single bytes past the end of any realistic database, the
+// way SQLite does, so they never get in the way of reading and writing pages.
+// A connection on its way to an exclusive lock holds the pending byte, which
+// keeps new readers out until the current ones are done.
+const PENDING_BYTE: off_t = 0x4000_0000;
+const RESERVED_BYTE: off_t = PENDING_BYTE + 1;
+const SHARED_BYTE: off_t = RESERVED_BYTE + 1;
+/// Read locked by every connection for as long as it has the file open.
+const CONNECTION_BYTE: off_t = SHARED_BYTE + 1;
+
+// Open file description locks belong to the open file rather than the process,
+// so connections within one process lock each other out too.
+#[cfg(target_os = "linux")]
+const SET_LOCK: c_int = libc::F_OFD_SETLK;
+#[cfg(target_os = "linux")]
+const GET_LOCK: c_int = libc::F_OFD_GETLK;
+#[cfg(not(target_os = "linux"))]
+const SET_LOCK: c_int = libc::F_SETLK;
+#[cfg(not(target_os = "linux"))]
+const GET_LOCK: c_int = libc::F_GETLK;
+
 pub struct FileStorage {
     file: File,
+    path: PathBuf,
+    temporary: bool,
+    lock: Lock,
 }
 
 impl FileStorage {
-    fn new(filename: &str) -> FileStorage {
+    fn new(path: PathBuf, temporary: bool) -> Result<FileStorage> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(filename)
-            .unwrap();
-        FileStorage { file }
+            .truncate(temporary)
+            .open(&path)?;
+        let storage = FileStorage {
+            file,
+            path,
+            temporary,
+            lock: Lock::Unlocked,
+        };
+        storage.set_lock(F_RDLCK, CONNECTION_BYTE)?;
+        Ok(storage)
     }
+
+    /// Takes the locks that make up `lock`, stopping at the first one held by
+    /// another connection.
+    fn acquire(&mut self, lock: Lock) -> Result<bool> {
+        if self.lock == Lock::Unlocked {
+            if !self.set_lock(F_RDLCK, PENDING_BYTE)? {
+                return Ok(false);
+            }
+            let locked = self.set_lock(F_RDLCK, SHARED_BYTE)?;
+            self.set_lock(F_UNLCK, PENDING_BYTE)?;
+            if !locked {
+                return Ok(false);
+            }
+        }
+        if lock >= Lock::Reserved
+            && self.lock < Lock::Reserved
+            && !self.set_lock(F_WRLCK, RESERVED_BYTE)?
+        {
+            return Ok(false);
+        }
+        if lock == Lock::Exclusive {
+            return Ok(
+                self.set_lock(F_WRLCK, PENDING_BYTE)? && self.set_lock(F_WRLCK, SHARED_BYTE)?
+            );
+        }
+        Ok(true)
+    }
+
+    /// Lets go of every lock beyond the ones that make up `lock`.
+    fn release(&mut self, lock: Lock) -> Result<()> {
+        match lock {
+            Lock::Unlocked => self.set_lock(F_UNLCK, SHARED_BYTE)?,
+            _ => self.set_lock(F_RDLCK, SHARED_BYTE)?,
+        };
+        if lock < Lock::Reserved {
+            self.set_lock(F_UNLCK, RESERVED_BYTE)?;
+        }
+        if lock < Lock::Exclusive {
+            self.set_lock(F_UNLCK, PENDING_BYTE)?;
+        }
+        self.lock = lock;
+        Ok(())
+    }
+
+    /// Sets a lock of `lock_type` on the byte at `offset`. Returns false if
+    /// another connection holds a lock on it that conflicts.
+    fn set_lock(&self, lock_type: c_int, offset: off_t) -> Result<bool> {
+        let flock = byte_lock(lock_type, offset);
+        if unsafe { libc::fcntl(self.file.as_raw_fd(), SET_LOCK, &flock) } == 0 {
+            return Ok(true);
+        }
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EAGAIN | libc::EACCES) => Ok(false),
+            _ => Err(error.into()),
+        }
+    }
+}
+
+/// Describes a lock of `lock_type` on the byte at `offset`.
+fn byte_lock(lock_type: c_int, offset: off_t) -> libc::flock {
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = lock_type as c_short;
+    flock.l_whence = SEEK_SET as c_short;
+    flock.l_start = offset;
+    flock.l_len = 1;
+    flock
 }
 
 impl Storage for FileStorage {
-    fn size(&mut self) -> u64 {
-        self.file.seek(SeekFrom::End(0)).unwrap()
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.file.seek(SeekFrom::End(0))?)
     }
 
-    fn read(&mut self, page_num: usize, buf: &mut [u8]) {
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
         self.file
-            .seek(SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))
-            .unwrap();
-        self.file.read(buf).unwrap();
+            .seek(SeekFrom::Start((page_num * buf.len()) as u64))?;
+        Ok(self.file.read_exact(buf)?)
     }
 
-    fn write(&mut self, page_num: usize, buf: &[u8]) {
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
         self.file
-            .seek(SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))
-            .unwrap();
-        self.file.write_all(buf).unwrap();
-        self.file.flush().unwrap();
+            .seek(SeekFrom::Start((page_num * buf.len()) as u64))?;
+        Ok(self.file.write_all(buf)?)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        Ok(self.file.set_len(size)?)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
+    fn remove(self) -> Result<()> {
+        Ok(std::fs::remove_file(&self.path)?)
+    }
+
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        if lock <= self.lock {
+            if lock < self.lock {
+                self.release(lock)?;
+            }
+            return Ok(true);
+        }
+
+        let previous = self.lock;
+        if !self.acquire(lock)? {
+            self.release(previous)?;
+            return Ok(false);
+        }
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        let mut flock = byte_lock(F_WRLCK, CONNECTION_BYTE);
+        if unsafe { libc::fcntl(self.file.as_raw_fd(), GET_LOCK, &mut flock) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(flock.l_type != F_UNLCK as c_short)
+    }
+
+    fn create_temporary(&self) -> Result<FileStorage> {
+        let mut path = self.path.clone().into_os_string();
+        path.push("-tmp");
+        FileStorage::new(PathBuf::from(path), true)
     }
 }
 
+impl Drop for FileStorage {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub struct MmapStorageFactory;
+
+impl StorageFactory<MmapStorage> for MmapStorageFactory {
+    fn open(&mut self, filename: &str) -> Result<MmapStorage> {
+        MmapStorage::new(FileStorage::new(PathBuf::from(filename), false)?)
+    }
+}
+
+/// Smallest mapping, so small files don't have to be remapped as they grow.
+const MIN_MAPPING_LENGTH: usize = 1 << 20;
+
+/// File storage that maps the file into memory, so reading and writing a page
+/// is a memory copy rather than a system call.
+///
+/// The pager still copies every page it reads into its cache, where the page
+/// can change before it's written back. Only readers that look at a page
+/// through `Storage::page` without keeping it, like the journal and the storage
+/// wrappers, skip the copy.
+///
+/// The mapping covers more than the file, growing by doubling when the file
+/// outgrows it, but only bytes within the file are ever touched. Another
+/// connection must not shrink the file while it's being read, which the database
+/// lock rules out.
+pub struct MmapStorage {
+    file: FileStorage,
+    map: *mut u8,
+    map_length: usize,
+    /// Length of the file as of the last time it was checked or changed.
+    length: usize,
+}
+
+impl MmapStorage {
+    fn new(mut file: FileStorage) -> Result<MmapStorage> {
+        let length = file.size()? as usize;
+        let mut storage = MmapStorage {
+            file,
+            map: std::ptr::null_mut(),
+            map_length: 0,
+            length,
+        };
+        storage.map(length)?;
+        Ok(storage)
+    }
+
+    /// Makes sure the mapping covers the first `length` bytes of the file.
+    fn map(&mut self, length: usize) -> Result<()> {
+        if length <= self.map_length {
+            return Ok(());
+        }
+        self.unmap();
+        let map_length = length.next_power_of_two().max(MIN_MAPPING_LENGTH);
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.file.file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        self.map = map as *mut u8;
+        self.map_length = map_length;
+        Ok(())
+    }
+
+    fn unmap(&mut self) {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map as *mut libc::c_void, self.map_length) };
+            self.map = std::ptr::null_mut();
+            self.map_length = 0;
+        }
+    }
+
+    /// Gets the mapped bytes from `offset` to `end`, which must be within the file.
+    /// The file may have grown since it was last checked.
+    fn bytes(&mut self, offset: usize, end: usize) -> Result<&mut [u8]> {
+        if end > self.length {
+            self.size()?;
+            if end > self.length {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        self.map(end)?;
+        Ok(unsafe { &mut std::slice::from_raw_parts_mut(self.map, end)[offset..] })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn size(&mut self) -> Result<u64> {
+        let length = self.file.file.metadata()?.len();
+        self.length = length as usize;
+        Ok(length)
+    }
+
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+        let offset = page_num * buf.len();
+        buf.copy_from_slice(self.bytes(offset, offset + buf.len())?);
+        Ok(())
+    }
+
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+        let offset = page_num * buf.len();
+        let end = offset + buf.len();
+        if end > self.length {
+            self.file.truncate(end as u64)?;
+            self.length = end;
+        }
+        self.bytes(offset, end)?.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file.truncate(size)?;
+        self.length = size as usize;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.length > 0 {
+            let result =
+                unsafe { libc::msync(self.map as *mut libc::c_void, self.length, libc::MS_SYNC) };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        self.file.sync()
+    }
+
+    fn remove(self) -> Result<()> {
+        let path = self.file.path.clone();
+        drop(self);
+        Ok(std::fs::remove_file(path)?)
+    }
+
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        self.file.lock(lock)
+    }
+
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        self.file.is_open_elsewhere()
+    }
+
+    fn page<'a>(&'a mut self, page_num: usize, buf: &'a mut [u8]) -> Result<&'a [u8]> {
+        let offset = page_num * buf.len();
+        Ok(self.bytes(offset, offset + buf.len())?)
+    }
+
+    fn create_temporary(&self) -> Result<MmapStorage> {
+        MmapStorage::new(self.file.create_temporary()?)
+    }
+}
+
+impl Drop for MmapStorage {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
+
+/// Opens databases that live in memory for as long as they're open, like a
+/// `:memory:` database. Every file it opens starts out empty.
+pub struct InMemoryStorageFactory;
+
+impl StorageFactory<InMemoryStorage> for InMemoryStorageFactory {
+    fn open(&mut self, _filename: &str) -> Result<InMemoryStorage> {
+        Ok(InMemoryStorage::default())
+    }
+}
+
+/// Storage that keeps its bytes in memory, and loses them when dropped.
+#[derive(Default)]
+pub struct InMemoryStorage {
+    bytes: Vec<u8>,
+}
+
+impl Storage for InMemoryStorage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+        read_at(&self.bytes, page_num * buf.len(), buf);
+        Ok(())
+    }
+
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+        write_at(&mut self.bytes, page_num * buf.len(), buf);
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.bytes.truncate(size as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove(self) -> Result<()> {
+        Ok(())
+    }
+
+    // No other connection can see the storage.
+    fn lock(&mut self, _lock: Lock) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn create_temporary(&self) -> Result<InMemoryStorage> {
+        Ok(InMemoryStorage::default())
+    }
+}
+
+/// Reads from byte `offset` of `bytes` into `buf`. Like reading past the end of
+/// a file, bytes past the end are left alone.
+fn read_at(bytes: &[u8], offset: usize, buf: &mut [u8]) {
+    let offset = offset.min(bytes.len());
+    let end = (offset + buf.len()).min(bytes.len());
+    buf[..end - offset].copy_from_slice(&bytes[offset..end]);
+}
+
+/// Writes `buf` at byte `offset` of `bytes`, growing it if needed.
+fn write_at(bytes: &mut Vec<u8>, offset: usize, buf: &[u8]) {
+    if bytes.len() < offset + buf.len() {
+        bytes.resize(offset + buf.len(), 0);
+    }
+    bytes[offset..offset + buf.len()].copy_from_slice(buf);
+}
+
+/// Splits the `length` bytes from byte `offset` into the pages of `page_size`
+/// bytes they fall in, giving each page number along with the range of the page
+/// and the range of the bytes that go in it. Storages that keep whole pages use
+/// it to serve reads and writes of any size.
+pub fn split_pages(
+    offset: u64,
+    length: usize,
+    page_size: usize,
+) -> Vec<(usize, Range<usize>, Range<usize>)> {
+    let mut parts = Vec::new();
+    let mut done = 0;
+    while done < length {
+        let position = offset as usize + done;
+        let page_num = position / page_size;
+        let start = position % page_size;
+        let part_length = (page_size - start).min(length - done);
+        parts.push((
+            page_num,
+            start..start + part_length,
+            done..done + part_length,
+        ));
+        done += part_length;
+    }
+    parts
+}
+
+/// The bytes of an in-memory file, shared by every storage that opens it.
+#[cfg(test)]
+type SharedPages = Rc<RefCell<Vec<u8>>>;
+
+/// Locks held by the storages open on an in-memory file. A storage's lock drops
+/// out of the list along with the storage.
+#[cfg(test)]
+type SharedLocks = Rc<RefCell<Vec<Weak<Cell<Lock>>>>>;
+
+/// Opens in-memory files that outlive the storages opened on them, so tests
+/// can close or crash a database and open it again.
 #[cfg(test)]
-pub struct InMemoryStorageFactory {
-    stores: HashMap<String, HashMap<usize, [u8; PAGE_SIZE]>>,
+#[derive(Default)]
+pub struct SharedMemoryStorageFactory {
+    stores: HashMap<String, (SharedPages, SharedLocks)>,
 }
 
 #[cfg(test)]
-impl InMemoryStorageFactory {
+impl SharedMemoryStorageFactory {
     pub fn new() -> Self {
-        Self {
-            stores: HashMap::new(),
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+impl StorageFactory<SharedMemoryStorage> for SharedMemoryStorageFactory {
+    fn open(&mut self, filename: &str) -> Result<SharedMemoryStorage> {
+        let (pages, locks) = self.stores.entry(filename.to_string()).or_default();
+        Ok(SharedMemoryStorage::new(pages.clone(), locks.clone()))
+    }
+}
+
+#[cfg(test)]
+pub struct SharedMemoryStorage {
+    pages: SharedPages,
+    locks: SharedLocks,
+    lock: Rc<Cell<Lock>>,
+}
+
+#[cfg(test)]
+impl SharedMemoryStorage {
+    fn new(pages: SharedPages, locks: SharedLocks) -> SharedMemoryStorage {
+        let lock = Rc::new(Cell::new(Lock::Unlocked));
+        locks.borrow_mut().push(Rc::downgrade(&lock));
+        SharedMemoryStorage { pages, locks, lock }
+    }
+}
+
+#[cfg(test)]
+impl Storage for SharedMemoryStorage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.pages.borrow().len() as u64)
+    }
+
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+        read_at(&self.pages.borrow(), page_num * buf.len(), buf);
+        Ok(())
+    }
+
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+        write_at(&mut self.pages.borrow_mut(), page_num * buf.len(), buf);
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.pages.borrow_mut().truncate(size as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove(self) -> Result<()> {
+        self.pages.borrow_mut().clear();
+        Ok(())
+    }
+
+    // Storages open on the same file lock each other out the way connections do.
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        let mut locks = self.locks.borrow_mut();
+        locks.retain(|other| other.strong_count() > 0);
+        let strongest_other = locks
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|other| !Rc::ptr_eq(other, &self.lock))
+            .map(|other| other.get())
+            .max()
+            .unwrap_or(Lock::Unlocked);
+        let allowed = lock <= self.lock.get()
+            || match lock {
+                Lock::Unlocked => true,
+                Lock::Shared => strongest_other < Lock::Exclusive,
+                Lock::Reserved => strongest_other < Lock::Reserved,
+                Lock::Exclusive => strongest_other == Lock::Unlocked,
+            };
+        if allowed {
+            self.lock.set(lock);
         }
+        Ok(allowed)
     }
+
+    // Tests open files to look at them while a database has them open, which
+    // mustn't keep the journal and log around.
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn create_temporary(&self) -> Result<SharedMemoryStorage> {
+        Ok(SharedMemoryStorage::new(
+            SharedPages::default(),
+            SharedLocks::default(),
+        ))
+    }
+}
+
+/// How a write goes wrong when a `FaultyStorage` crashes.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// The write never happens.
+    Drop,
+    /// Only the first half of the write happens.
+    Tear,
+    /// The write is lost, but only once the write after it has happened, unless
+    /// its file is synced or truncated in between.
+    Reorder,
+}
+
+/// A write that hasn't made it to disk yet, along with what it overwrote.
+#[cfg(test)]
+struct UnsyncedWrite {
+    pages: SharedPages,
+    offset: usize,
+    overwritten: Vec<u8>,
+    length: usize,
+}
+
+#[cfg(test)]
+impl UnsyncedWrite {
+    /// Puts back what the write overwrote.
+    fn undo(self) {
+        let mut pages = self.pages.borrow_mut();
+        pages[self.offset..self.offset + self.overwritten.len()].copy_from_slice(&self.overwritten);
+        pages.truncate(self.length);
+    }
+}
+
+/// Writes counted so far and the fault to inject, shared by every file of a
+/// `FaultyStorageFactory`.
+#[cfg(test)]
+#[derive(Default)]
+struct FaultState {
+    num_writes: usize,
+    fault: Option<(usize, Fault)>,
+    /// The write held back by `Fault::Reorder`.
+    unsynced: Option<UnsyncedWrite>,
+    crashed: bool,
 }
 
 #[cfg(test)]
-impl<'a> StorageFactory<'a, InMemoryStorage<'a>> for InMemoryStorageFactory {
-    fn open<'b>(&'b mut self, filename: &'b str) -> InMemoryStorage<'_> {
-        if self.stores.get(filename).is_none() {
-            self.stores.insert(filename.to_string(), HashMap::new());
+impl FaultState {
+    /// Fails every operation once crashed, like a process that died.
+    fn check(&self) -> Result<()> {
+        if self.crashed {
+            return Err(crash());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn crash() -> Error {
+    Error::Io(std::io::Error::other("simulated crash"))
+}
+
+/// Opens in-memory files that crash at a chosen write, counting from 0 across
+/// every file. Once crashed, every operation fails until `restart`.
+#[cfg(test)]
+pub struct FaultyStorageFactory {
+    factory: SharedMemoryStorageFactory,
+    state: Rc<RefCell<FaultState>>,
+}
+
+#[cfg(test)]
+impl FaultyStorageFactory {
+    pub fn new(fault: Option<(usize, Fault)>) -> Self {
+        FaultyStorageFactory {
+            factory: SharedMemoryStorageFactory::new(),
+            state: Rc::new(RefCell::new(FaultState {
+                fault,
+                ..FaultState::default()
+            })),
+        }
+    }
+
+    /// Gets the number of writes so far.
+    pub fn num_writes(&self) -> usize {
+        self.state.borrow().num_writes
+    }
+
+    /// Comes back up after a crash, losing any write that wasn't synced, with no
+    /// more faults to inject.
+    pub fn restart(&mut self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(unsynced) = state.unsynced.take() {
+            unsynced.undo();
         }
+        *state = FaultState::default();
+    }
+}
 
-        let pages = self.stores.get_mut(filename).unwrap();
-        InMemoryStorage { pages }
+#[cfg(test)]
+impl StorageFactory<FaultyStorage> for FaultyStorageFactory {
+    fn open(&mut self, filename: &str) -> Result<FaultyStorage> {
+        self.state.borrow().check()?;
+        Ok(FaultyStorage {
+            storage: self.factory.open(filename)?,
+            state: self.state.clone(),
+        })
     }
 }
 
 #[cfg(test)]
-pub struct InMemoryStorage<'a> {
-    pages: &'a mut HashMap<usize, [u8; PAGE_SIZE]>,
+pub struct FaultyStorage {
+    storage: SharedMemoryStorage,
+    state: Rc<RefCell<FaultState>>,
 }
 
 #[cfg(test)]
-impl<'a> Storage for InMemoryStorage<'a> {
-    fn size(&mut self) -> u64 {
-        (self.pages.len() * PAGE_SIZE) as u64
+impl FaultyStorage {
+    /// Makes the write held back by `Fault::Reorder` durable if it's to this file.
+    fn settle(&mut self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(unsynced) = &state.unsynced {
+            if Rc::ptr_eq(&unsynced.pages, &self.storage.pages) {
+                state.unsynced = None;
+            }
+        }
     }
+}
 
-    fn read(&mut self, page_num: usize, buf: &mut [u8]) {
-        if let Some(page) = self.pages.get(&page_num) {
-            buf.copy_from_slice(page.as_slice());
+#[cfg(test)]
+impl Storage for FaultyStorage {
+    fn size(&mut self) -> Result<u64> {
+        self.state.borrow().check()?;
+        self.storage.size()
+    }
+
+    fn read(&mut self, page_num: usize, buf: &mut [u8]) -> Result<()> {
+        self.state.borrow().check()?;
+        self.storage.read(page_num, buf)
+    }
+
+    fn write(&mut self, page_num: usize, buf: &[u8]) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.check()?;
+        let index = state.num_writes;
+        state.num_writes += 1;
+        let offset = page_num * buf.len();
+        if let Some(unsynced) = state.unsynced.take() {
+            // This write makes it to disk before the one held back.
+            unsynced.undo();
+            write_at(&mut self.storage.pages.borrow_mut(), offset, buf);
+            state.crashed = true;
+            return Err(crash());
+        }
+
+        match state.fault {
+            Some((fault_index, fault)) if fault_index == index => match fault {
+                Fault::Drop => {
+                    state.crashed = true;
+                    Err(crash())
+                }
+                Fault::Tear => {
+                    write_at(
+                        &mut self.storage.pages.borrow_mut(),
+                        offset,
+                        &buf[..buf.len() / 2],
+                    );
+                    state.crashed = true;
+                    Err(crash())
+                }
+                Fault::Reorder => {
+                    let pages = self.storage.pages.borrow();
+                    let end = (offset + buf.len()).min(pages.len()).max(offset);
+                    state.unsynced = Some(UnsyncedWrite {
+                        pages: self.storage.pages.clone(),
+                        offset,
+                        overwritten: pages.get(offset..end).unwrap_or_default().to_vec(),
+                        length: pages.len(),
+                    });
+                    drop(pages);
+                    write_at(&mut self.storage.pages.borrow_mut(), offset, buf);
+                    Ok(())
+                }
+            },
+            _ => {
+                write_at(&mut self.storage.pages.borrow_mut(), offset, buf);
+                Ok(())
+            }
         }
     }
 
-    fn write(&mut self, page_num: usize, buf: &[u8]) {
-        if self.pages.get(&page_num).is_none() {
-            self.pages.insert(page_num, [0u8; PAGE_SIZE]);
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.state.borrow().check()?;
+        self.settle();
+        self.storage.truncate(size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.state.borrow().check()?;
+        self.settle();
+        self.storage.sync()
+    }
+
+    fn remove(self) -> Result<()> {
+        self.state.borrow().check()?;
+        self.storage.remove()
+    }
+
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        self.state.borrow().check()?;
+        self.storage.lock(lock)
+    }
+
+    fn is_open_elsewhere(&mut self) -> Result<bool> {
+        self.state.borrow().check()?;
+        self.storage.is_open_elsewhere()
+    }
+
+    fn create_temporary(&self) -> Result<FaultyStorage> {
+        self.state.borrow().check()?;
+        Ok(FaultyStorage {
+            storage: self.storage.create_temporary()?,
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// A new database path in the temporary directory. The database is removed
+/// along with its journal and log when this is dropped, even if a test fails.
+#[cfg(test)]
+pub struct TempPath(PathBuf);
+
+#[cfg(test)]
+impl TempPath {
+    pub fn new() -> TempPath {
+        let name = format!("cstack-{}.db", uuid::Uuid::new_v4());
+        TempPath(std::env::temp_dir().join(name))
+    }
+
+    pub fn filename(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Default for TempPath {
+    fn default() -> Self {
+        TempPath::new()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let filename = self.filename();
+        for path in [
+            filename.to_string(),
+            crate::journal::journal_filename(filename),
+            crate::wal::wal_filename(filename),
+        ] {
+            let _ = std::fs::remove_file(path);
         }
-        let page = self.pages.get_mut(&page_num).unwrap();
-        page[..buf.len()].copy_from_slice(buf);
     }
 }
 
@@ -115,40 +915,161 @@ impl<'a> Storage for InMemoryStorage<'a> {
 mod tests {
     use super::*;
 
+    const PAGE_SIZE: usize = 4096;
+
     #[test]
     fn in_memory_sanity() {
-        let mut factory = InMemoryStorageFactory::new();
-        let mut storage = factory.open("foobar");
-        let text1 = b"first";
-        storage.write(0, text1);
+        let mut factory = InMemoryStorageFactory;
+        let mut storage = factory.open("foobar").unwrap();
+        let text1 = [1u8; PAGE_SIZE];
+        storage.write(0, &text1).unwrap();
+
+        let text2 = [2u8; PAGE_SIZE];
+        storage.write(1, &text2).unwrap();
 
-        let text2 = b"second";
-        storage.write(1, text2);
+        let mut buf = [0u8; PAGE_SIZE];
+        storage.read(0, &mut buf).unwrap();
+        assert_eq!(text1, buf);
 
         let mut buf = [0u8; PAGE_SIZE];
-        storage.read(0, &mut buf);
-        assert_eq!(text1, &buf[..text1.len()]);
+        storage.read(1, &mut buf).unwrap();
+        assert_eq!(text2, buf);
+        assert_eq!(storage.size().unwrap(), 2 * PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn file_locks_conflict_between_connections() {
+        let path = std::env::temp_dir().join(format!("cstack-{}.db", uuid::Uuid::new_v4()));
+        let mut factory = FileStorageFactory;
+        let mut first = factory.open(path.to_str().unwrap()).unwrap();
+        assert!(!first.is_open_elsewhere().unwrap());
+        let mut second = factory.open(path.to_str().unwrap()).unwrap();
+        assert!(first.is_open_elsewhere().unwrap());
+
+        assert!(first.lock(Lock::Shared).unwrap());
+        assert!(second.lock(Lock::Shared).unwrap());
+        assert!(first.lock(Lock::Reserved).unwrap());
+        assert!(!second.lock(Lock::Reserved).unwrap());
+        assert!(!first.lock(Lock::Exclusive).unwrap());
+
+        assert!(second.lock(Lock::Unlocked).unwrap());
+        assert!(first.lock(Lock::Exclusive).unwrap());
+        assert!(!second.lock(Lock::Shared).unwrap());
+        assert!(first.lock(Lock::Shared).unwrap());
+        assert!(second.lock(Lock::Shared).unwrap());
+
+        drop(second);
+        assert!(!first.is_open_elsewhere().unwrap());
+        first.remove().unwrap();
+    }
 
+    #[test]
+    fn mmap_storage_grows_and_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("cstack-{}.db", uuid::Uuid::new_v4()));
+        let mut factory = MmapStorageFactory;
+        let num_pages = 2 * MIN_MAPPING_LENGTH / PAGE_SIZE + 1;
+        {
+            let mut storage = factory.open(path.to_str().unwrap()).unwrap();
+            let mut buf = [0u8; PAGE_SIZE];
+            assert!(storage.read(0, &mut buf).is_err());
+            for page_num in 0..num_pages {
+                storage
+                    .write(page_num, &[page_num as u8; PAGE_SIZE])
+                    .unwrap();
+            }
+            assert!(storage.map_length > MIN_MAPPING_LENGTH);
+            assert_eq!(storage.size().unwrap(), (num_pages * PAGE_SIZE) as u64);
+            assert_eq!(storage.page(7, &mut buf).unwrap(), [7u8; PAGE_SIZE]);
+            // The page is borrowed from the mapping rather than copied.
+            assert_eq!(buf, [0u8; PAGE_SIZE]);
+            storage
+                .truncate((num_pages - 1) as u64 * PAGE_SIZE as u64)
+                .unwrap();
+            storage.sync().unwrap();
+        }
+
+        let mut storage = factory.open(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            storage.size().unwrap(),
+            ((num_pages - 1) * PAGE_SIZE) as u64
+        );
         let mut buf = [0u8; PAGE_SIZE];
-        storage.read(1, &mut buf);
-        assert_eq!(text2, &buf[..text2.len()]);
+        storage.read(num_pages - 2, &mut buf).unwrap();
+        assert_eq!(buf, [(num_pages - 2) as u8; PAGE_SIZE]);
+        assert!(storage.read(num_pages - 1, &mut buf).is_err());
+        storage.remove().unwrap();
+    }
+
+    #[test]
+    fn faulty_storage_crashes_at_the_chosen_write() {
+        let contents = |factory: &mut FaultyStorageFactory| {
+            let mut storage = factory.open("foobar").unwrap();
+            let mut buf = vec![0u8; storage.size().unwrap() as usize];
+            storage.read(0, &mut buf).unwrap();
+            buf
+        };
+
+        let mut factory = FaultyStorageFactory::new(Some((1, Fault::Drop)));
+        let mut storage = factory.open("foobar").unwrap();
+        storage.write(0, &[1; 4]).unwrap();
+        assert!(storage.write(1, &[2; 4]).is_err());
+        assert!(storage.size().is_err());
+        factory.restart();
+        assert_eq!(contents(&mut factory), [1; 4]);
+
+        let mut factory = FaultyStorageFactory::new(Some((0, Fault::Tear)));
+        let mut storage = factory.open("foobar").unwrap();
+        assert!(storage.write(0, &[1; 4]).is_err());
+        factory.restart();
+        assert_eq!(contents(&mut factory), [1; 2]);
+
+        // The held back write is lost once a later one happens...
+        let mut factory = FaultyStorageFactory::new(Some((0, Fault::Reorder)));
+        let mut storage = factory.open("foobar").unwrap();
+        storage.write(0, &[1; 4]).unwrap();
+        assert_eq!(contents(&mut factory), [1; 4]);
+        assert!(storage.write(1, &[2; 4]).is_err());
+        factory.restart();
+        assert_eq!(contents(&mut factory), [0, 0, 0, 0, 2, 2, 2, 2]);
+
+        // ...unless it was synced first.
+        let mut factory = FaultyStorageFactory::new(Some((0, Fault::Reorder)));
+        let mut storage = factory.open("foobar").unwrap();
+        storage.write(0, &[1; 4]).unwrap();
+        storage.sync().unwrap();
+        storage.write(1, &[2; 4]).unwrap();
+        factory.restart();
+        assert_eq!(contents(&mut factory), [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(factory.num_writes(), 0);
     }
 
     #[test]
-    fn in_memory_flush_reopen() {
-        let mut factory = InMemoryStorageFactory::new();
+    fn shared_memory_survives_reopen() {
+        let mut factory = SharedMemoryStorageFactory::new();
         let text1 = b"first";
 
         {
-            let mut storage = factory.open("foobar");
-            storage.write(0, text1);
+            let mut storage = factory.open("foobar").unwrap();
+            storage.write(0, text1).unwrap();
         }
 
         {
-            let mut storage = factory.open("foobar");
+            let mut storage = factory.open("foobar").unwrap();
             let mut buf = [0u8; PAGE_SIZE];
-            storage.read(0, &mut buf);
+            storage.read(0, &mut buf).unwrap();
             assert_eq!(text1, &buf[..text1.len()]);
         }
     }
+
+    #[test]
+    fn in_memory_starts_empty_on_every_open() {
+        let mut factory = InMemoryStorageFactory;
+        let mut storage = factory.open("foobar").unwrap();
+        storage.write(0, &[1u8; PAGE_SIZE]).unwrap();
+        let mut temporary = storage.create_temporary().unwrap();
+        assert_eq!(temporary.size().unwrap(), 0);
+
+        let mut storage = factory.open("foobar").unwrap();
+        assert_eq!(storage.size().unwrap(), 0);
+    }
 }
@@ -1,53 +1,142 @@
+use crate::error::Result;
+use crate::node::internal;
+use crate::node::leaf;
 use crate::node::leaf::LeafNode;
 use crate::node::Node;
-use crate::pager::Pager;
+use crate::pager::{Pager, PagerConfig};
 use crate::storage::{Storage, StorageFactory};
 
+/// The root node always lives on the page right after the header page.
+const ROOT_PAGE_NUM: u32 = 1;
+
 pub struct Table<T> {
     pub pager: Pager<T>,
-    root_page_num: u32,
+    pub(crate) root_page_num: u32,
 }
 
-impl<'a, T: Storage + 'a> Table<T> {
+impl<T: Storage> Table<T> {
     /// Return the position of the given key.
     /// If the key is not present, return the position
     /// where it should be inserted.
-    pub fn find(&mut self, key: u32) -> Cursor<T> {
+    pub fn find(&mut self, key: u32) -> Result<Cursor<T>> {
         let root_page_num = self.root_page_num;
-        let root_node = self.pager.page(root_page_num);
+        let root_node = self.pager.page(root_page_num)?;
 
         match root_node {
             Node::Internal(internal) => internal.find(self, key),
-            Node::Leaf(leaf) => leaf.find(self, key),
+            Node::Leaf(leaf) => Ok(leaf.find(self, key)),
         }
     }
 
-    pub fn start(&mut self) -> Cursor<T> {
-        let mut cursor = self.find(0);
+    pub fn start(&mut self) -> Result<Cursor<T>> {
+        let mut cursor = self.find(0)?;
         let num_cells = cursor.node.num_cells();
         cursor.end_of_table = num_cells == 0;
-        cursor
+        Ok(cursor)
+    }
+
+    #[allow(dead_code)]
+    pub fn open<F: StorageFactory<T>>(storage_factory: &mut F, filename: &str) -> Result<Table<T>> {
+        Table::open_with_config(storage_factory, filename, PagerConfig::default())
     }
 
-    pub fn open<F: StorageFactory<'a, T>>(
-        storage_factory: &'a mut F,
-        filename: &'a str,
-    ) -> Table<T> {
-        let mut pager = Pager::open(storage_factory, filename);
+    pub fn open_with_config<F: StorageFactory<T>>(
+        storage_factory: &mut F,
+        filename: &str,
+        config: PagerConfig,
+    ) -> Result<Table<T>> {
+        let mut pager = Pager::open(storage_factory, filename, config)?;
         if pager.num_pages == 0 {
-            // New database file. Initialize page 0 as leaf node.
-            let mut root_node = pager.new_leaf_page(0);
-            root_node.node.set_root(true);
+            // Another connection may initialize the file before this one locks it.
+            pager.begin_write()?;
+            if pager.num_pages == 0 {
+                // New database file. Initialize the header page, and page 1 as leaf node.
+                pager.new_header_page()?;
+                let mut root_node = pager.new_leaf_page(ROOT_PAGE_NUM)?;
+                root_node.node.set_root(true);
+            }
+            pager.commit()?;
         }
 
-        Table {
+        Ok(Table {
             pager,
-            root_page_num: 0,
-        }
+            root_page_num: ROOT_PAGE_NUM,
+        })
     }
 
-    pub fn close(self) {
-        self.pager.close();
+    pub fn close(self) -> Result<()> {
+        self.pager.close()
+    }
+
+    /// Rebuilds the table into a fresh file with densely packed nodes and no free
+    /// pages, then copies it over the current database and truncates the rest.
+    ///
+    /// The copy is part of the current transaction, so the journal or log swaps
+    /// in the rebuilt database atomically when it commits.
+    pub fn vacuum(&mut self) -> Result<()> {
+        let mut pager = self.pager.create_temporary()?;
+        pager.new_header_page()?;
+        let root_page_num = pager.get_unused_page_num()?;
+
+        // Copy every cell, in order, into as few leaves as possible.
+        let mut num_cells = 0;
+        let mut cursor = self.start()?;
+        while !cursor.end_of_table {
+            num_cells += 1;
+            cursor.advance()?;
+        }
+
+        let mut cursor = self.start()?;
+        let mut children = Vec::new();
+        let mut previous_leaf: Option<LeafNode> = None;
+        let usable_size = self.pager.usable_size();
+        let leaf_sizes = even_chunks(num_cells, leaf::max_cells(usable_size) as usize);
+        let single_leaf = leaf_sizes.len() == 1;
+        for size in leaf_sizes {
+            let page_num = if single_leaf {
+                root_page_num
+            } else {
+                pager.get_unused_page_num()?
+            };
+            let mut leaf = pager.new_leaf_page(page_num)?;
+            for i in 0..size as u32 {
+                leaf.set_cells(i, cursor.node.cells(cursor.cell_num..cursor.cell_num + 1));
+                cursor.advance()?;
+            }
+            leaf.set_num_cells(size as u32);
+            if let Some(mut previous_leaf) = previous_leaf {
+                previous_leaf.set_next_leaf(page_num);
+            }
+            if size > 0 {
+                children.push((page_num, leaf.get_max_key()));
+            }
+            previous_leaf = Some(leaf);
+        }
+
+        // Build each level of internal nodes on top of the one below it.
+        while children.len() > 1 {
+            let node_sizes =
+                even_chunks(children.len(), internal::max_keys(usable_size) as usize + 1);
+            let single_node = node_sizes.len() == 1;
+            let mut parents = Vec::new();
+            let mut remaining = children.as_slice();
+            for size in node_sizes {
+                let page_num = if single_node {
+                    root_page_num
+                } else {
+                    pager.get_unused_page_num()?
+                };
+                let (node_children, rest) = remaining.split_at(size);
+                let mut node = pager.new_internal_page(page_num)?;
+                node.set_children(&mut pager, node_children)?;
+                parents.push((page_num, node_children.last().unwrap().1));
+                remaining = rest;
+            }
+            children = parents;
+        }
+        pager.page(root_page_num)?.set_root(true);
+
+        self.pager.copy_from(pager)
     }
 
     // Handle splitting the root.
@@ -55,36 +144,97 @@ impl<'a, T: Storage + 'a> Table<T> {
     // Address of right child passed in.
     // Re-initialize root page to contain the new root node.
     // New root node points to two children.
-    pub(crate) fn create_new_root(&mut self, right_child_page_num: u32) {
+    pub(crate) fn create_new_root(&mut self, right_child_page_num: u32) -> Result<()> {
         let pager = &mut self.pager;
 
         // get old root page
-        let root = pager.page(self.root_page_num);
-        let left_child_max_key = root.get_max_key();
+        let root = pager.page(self.root_page_num)?;
+        let left_child_max_key = root.get_max_key(pager)?;
 
         // get right child page
-        let mut right_child = pager.page(right_child_page_num);
+        let mut right_child = pager.page(right_child_page_num)?;
 
         // get an unused page for the left child
-        let left_child_page_num = pager.get_unused_page_num();
-        let mut left_child = pager.page(left_child_page_num);
+        let left_child_page_num = pager.get_unused_page_num()?;
+        let mut left_child = pager.page(left_child_page_num)?;
 
         // Copy data from old root to left child
         left_child.buffer_mut().copy_from_slice(root.buffer());
         left_child.set_root(false);
 
+        // The children of an internal root now belong to the left child
+        if let Node::Internal(left_child) = pager.page(left_child_page_num)? {
+            for i in 0..=left_child.num_keys() {
+                pager
+                    .page(left_child.child(i)?)?
+                    .set_parent(left_child_page_num);
+            }
+        }
+
         // Create a new root node as an internal node with one key and two children
-        let mut root = pager.new_internal_page(self.root_page_num);
+        let mut root = pager.new_internal_page(self.root_page_num)?;
         root.node.set_root(true);
         root.set_num_keys(1);
-        root.set_child(0, left_child_page_num);
+        root.set_child(0, left_child_page_num)?;
         root.set_key(0, left_child_max_key);
         root.set_right_child(right_child_page_num);
         left_child.set_parent(self.root_page_num);
         right_child.set_parent(self.root_page_num);
+        Ok(())
+    }
+
+    // Handle a root with a single child left.
+    // The only child is copied into the root page,
+    // which shrinks the tree by one level.
+    pub(crate) fn collapse_root(&mut self) -> Result<()> {
+        let pager = &mut self.pager;
+        let child_page_num = pager.page(self.root_page_num)?.unwrap_internal().child(0)?;
+        let child = pager.page(child_page_num)?;
+
+        let mut root = pager.page(self.root_page_num)?;
+        root.buffer_mut().copy_from_slice(child.buffer());
+        root.set_root(true);
+        pager.free_page(child_page_num)?;
+
+        // The children of an internal child now belong to the root
+        if let Node::Internal(root) = pager.page(self.root_page_num)? {
+            for i in 0..=root.num_keys() {
+                pager.page(root.child(i)?)?.set_parent(self.root_page_num);
+            }
+        }
+        Ok(())
+    }
+
+    /// Refresh the keys leading to `page_num` after its max key might have changed.
+    /// Each ancestor that isn't a right child records the max key of its subtree.
+    pub(crate) fn update_ancestor_keys(&mut self, mut page_num: u32) -> Result<()> {
+        let pager = &mut self.pager;
+        loop {
+            let node = pager.page(page_num)?;
+            let Some(parent_page_num) = node.parent() else {
+                return Ok(());
+            };
+
+            let mut parent = pager.page(parent_page_num)?.unwrap_internal();
+            let index = parent.child_index(page_num)?;
+            if index < parent.num_keys() {
+                let max_key = node.get_max_key(pager)?;
+                parent.set_key(index, max_key);
+            }
+            page_num = parent_page_num;
+        }
     }
 }
 
+/// Splits `total` items into the fewest chunks of at most `max` items,
+/// with sizes as even as possible. There is always at least one chunk.
+fn even_chunks(total: usize, max: usize) -> Vec<usize> {
+    let num_chunks = total.div_ceil(max).max(1);
+    (0..num_chunks)
+        .map(|i| total / num_chunks + usize::from(i < total % num_chunks))
+        .collect()
+}
+
 /// Leaf node iterator
 pub struct Cursor<T> {
     pub table: *mut Table<T>,
@@ -95,11 +245,15 @@ pub struct Cursor<T> {
 }
 
 impl<T: Storage> Cursor<T> {
+    pub fn key(&self) -> u32 {
+        self.node.key(self.cell_num)
+    }
+
     pub fn value(&self) -> &[u8] {
         self.node.value(self.cell_num)
     }
 
-    pub fn advance(&mut self) {
+    pub fn advance(&mut self) -> Result<()> {
         self.cell_num += 1;
         if self.cell_num >= self.node.num_cells() {
             // Advance to next leaf node
@@ -110,10 +264,11 @@ impl<T: Storage> Cursor<T> {
             } else {
                 self.node = unsafe { &mut *self.table }
                     .pager
-                    .page(next_page_num)
+                    .page(next_page_num)?
                     .unwrap_leaf();
                 self.cell_num = 0;
             }
         }
+        Ok(())
     }
 }
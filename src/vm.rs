@@ -1,27 +1,84 @@
-use crate::node::leaf::leaf_node_insert;
+use crate::analyzer::analyze;
+use crate::error::Error;
+use crate::integrity::check_integrity;
+use crate::node::leaf::{leaf_node_delete, leaf_node_insert};
+use crate::pager::Synchronous;
 use crate::repl::{print_constants, print_tree};
 use crate::serialization::{Row, EMAIL_SIZE, USERNAME_SIZE};
 use crate::storage::Storage;
 use crate::table::Table;
 use crate::Logger;
-use libc::EXIT_SUCCESS;
+use libc::{EXIT_FAILURE, EXIT_SUCCESS};
+use std::ops::RangeInclusive;
 use std::process::exit;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Statement {
     Insert(Row),
+    /// Replaces the row with the same id.
+    Update(Row),
     Select,
+    /// Deletes every row with an id in the range.
+    Delete(RangeInclusive<u32>),
+    /// Compacts the database file.
+    Vacuum,
+    /// Copies committed pages from the write-ahead log into the database file.
+    Checkpoint,
+    /// Starts a transaction, so the statements after it commit together.
+    Begin,
+    Commit,
+    /// Discards every change since the transaction began.
+    Rollback,
+    /// Marks a point in the transaction to roll back to, starting a transaction
+    /// if none is open.
+    Savepoint(String),
+    /// Forgets a savepoint and those after it, keeping their changes.
+    Release(String),
+    /// Discards every change since a savepoint.
+    RollbackTo(String),
+    Pragma(Pragma),
+}
+
+/// A setting that's read with `pragma <name>` and changed with `pragma <name> = <value>`.
+#[derive(Debug)]
+pub enum Pragma {
+    /// When writes are synced.
+    Synchronous(Option<Synchronous>),
+    /// How long to wait for another connection's lock, in milliseconds.
+    BusyTimeout(Option<Duration>),
 }
 
 impl TryFrom<&str> for Statement {
     type Error = PrepareError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if &value[..6] == "insert" {
-            prepare_insert(value)
+        if value.starts_with("insert") {
+            prepare_row(value).map(Statement::Insert)
+        } else if value.starts_with("update") {
+            prepare_row(value).map(Statement::Update)
+        } else if value.starts_with("delete") {
+            prepare_delete(value)
         } else if value == "select" {
             Ok(Statement::Select)
+        } else if value == "vacuum" {
+            Ok(Statement::Vacuum)
+        } else if value == "checkpoint" {
+            Ok(Statement::Checkpoint)
+        } else if value == "begin" {
+            Ok(Statement::Begin)
+        } else if value == "commit" {
+            Ok(Statement::Commit)
+        } else if value == "rollback" {
+            Ok(Statement::Rollback)
+        } else if value.starts_with("savepoint")
+            || value.starts_with("release")
+            || value.starts_with("rollback to")
+        {
+            prepare_savepoint(value)
+        } else if value.starts_with("pragma") {
+            prepare_pragma(value)
         } else {
             Err(PrepareError::UnrecognizedStatement)
         }
@@ -36,7 +93,8 @@ pub enum PrepareError {
     UnrecognizedStatement,
 }
 
-fn prepare_insert(input: &str) -> Result<Statement, PrepareError> {
+/// Parses the `<id> <username> <email>` arguments of an insert or update.
+fn prepare_row(input: &str) -> Result<Row, PrepareError> {
     let mut splitter = input.split(" ");
     let _keyword = splitter.next();
     let id_string = splitter.next();
@@ -48,59 +106,194 @@ fn prepare_insert(input: &str) -> Result<Statement, PrepareError> {
         _ => return Err(PrepareError::SyntaxError),
     };
 
-    let id = i32::from_str(id_string).unwrap();
-    if id < 0 {
-        return Err(PrepareError::NegativeId);
-    }
-
-    if username.as_bytes().len() > USERNAME_SIZE || email.as_bytes().len() > EMAIL_SIZE {
+    let id = parse_id(id_string)?;
+    if username.len() > USERNAME_SIZE || email.len() > EMAIL_SIZE {
         return Err(PrepareError::StringTooLong);
     }
 
-    let row = Row {
-        id: id as u32,
+    Ok(Row {
+        id,
         username: username.to_string(),
         email: email.to_string(),
+    })
+}
+
+fn prepare_delete(input: &str) -> Result<Statement, PrepareError> {
+    let tokens: Vec<_> = input.split(' ').collect();
+    let ids = match tokens[..] {
+        ["delete", "where", "id", "between", start, "and", end] => {
+            parse_id(start)?..=parse_id(end)?
+        }
+        ["delete", "where", "id", operator, id] => {
+            let id = parse_id(id)?;
+            let none = RangeInclusive::new(1, 0);
+            match operator {
+                "=" => id..=id,
+                "<" => id.checked_sub(1).map_or(none, |end| 0..=end),
+                "<=" => 0..=id,
+                ">" => id.checked_add(1).map_or(none, |start| start..=u32::MAX),
+                ">=" => id..=u32::MAX,
+                _ => return Err(PrepareError::SyntaxError),
+            }
+        }
+        _ => return Err(PrepareError::SyntaxError),
+    };
+
+    Ok(Statement::Delete(ids))
+}
+
+/// Parses `savepoint <name>`, `release [savepoint] <name>` and
+/// `rollback to [savepoint] <name>`.
+fn prepare_savepoint(input: &str) -> Result<Statement, PrepareError> {
+    let tokens: Vec<_> = input.split(' ').collect();
+    match tokens[..] {
+        ["savepoint", name] => Ok(Statement::Savepoint(name.to_string())),
+        ["release", name] | ["release", "savepoint", name] => {
+            Ok(Statement::Release(name.to_string()))
+        }
+        ["rollback", "to", name] | ["rollback", "to", "savepoint", name] => {
+            Ok(Statement::RollbackTo(name.to_string()))
+        }
+        _ => Err(PrepareError::SyntaxError),
+    }
+}
+
+fn prepare_pragma(input: &str) -> Result<Statement, PrepareError> {
+    let tokens: Vec<_> = input.split(' ').collect();
+    let pragma = match tokens[..] {
+        ["pragma", "synchronous"] => Pragma::Synchronous(None),
+        ["pragma", "synchronous", "=", value] => Pragma::Synchronous(Some(
+            Synchronous::from_str(value).map_err(|_| PrepareError::SyntaxError)?,
+        )),
+        ["pragma", "busy_timeout"] => Pragma::BusyTimeout(None),
+        ["pragma", "busy_timeout", "=", value] => Pragma::BusyTimeout(Some(Duration::from_millis(
+            u64::from_str(value).map_err(|_| PrepareError::SyntaxError)?,
+        ))),
+        _ => return Err(PrepareError::SyntaxError),
     };
+    Ok(Statement::Pragma(pragma))
+}
 
-    Ok(Statement::Insert(row))
+fn parse_id(id: &str) -> Result<u32, PrepareError> {
+    let id = i64::from_str(id).map_err(|_| PrepareError::SyntaxError)?;
+    if id < 0 {
+        return Err(PrepareError::NegativeId);
+    }
+    u32::try_from(id).map_err(|_| PrepareError::SyntaxError)
 }
 
 pub enum MetaCommandError {
     UnrecognizedCommand,
+    Database(Error),
 }
 
+#[allow(clippy::result_large_err)]
 pub fn do_meta_command<T: Storage>(
     query: &str,
     mut table: Table<T>,
 ) -> Result<Table<T>, (Table<T>, MetaCommandError)> {
     match query {
-        ".exit" => {
-            table.close();
-            exit(EXIT_SUCCESS);
-        }
+        ".exit" => match table.close() {
+            Ok(()) => exit(EXIT_SUCCESS),
+            Err(error) => {
+                println!("Error: {error}.");
+                exit(EXIT_FAILURE);
+            }
+        },
         ".btree" => {
             println!("Tree:");
-            print_tree(&mut table.pager, 0, 0);
-            Ok(table)
+            let result = print_tree(&mut table.pager, table.root_page_num, 0);
+            match end_read(&mut table, result) {
+                Ok(()) => Ok(table),
+                Err(error) => Err((table, MetaCommandError::Database(error))),
+            }
+        }
+        ".check" => {
+            let result = check_integrity(&mut table);
+            match end_read(&mut table, result) {
+                Ok(problems) if problems.is_empty() => {
+                    println!("ok");
+                    Ok(table)
+                }
+                Ok(problems) => {
+                    for problem in problems {
+                        println!("{problem}");
+                    }
+                    Ok(table)
+                }
+                Err(error) => Err((table, MetaCommandError::Database(error))),
+            }
+        }
+        ".analyze" | ".analyze json" => {
+            let result = analyze(&mut table);
+            match end_read(&mut table, result) {
+                Ok(report) if query == ".analyze json" => {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                    Ok(table)
+                }
+                Ok(report) => {
+                    println!("{report}");
+                    Ok(table)
+                }
+                Err(error) => Err((table, MetaCommandError::Database(error))),
+            }
         }
         ".constants" => {
             println!("Constants:");
-            print_constants();
+            print_constants(table.pager.usable_size());
             Ok(table)
         }
         _ => Err((table, MetaCommandError::UnrecognizedCommand)),
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Ends the read that a meta command started, like a statement outside of a
+/// transaction, so an idle session doesn't keep other connections from writing.
+fn end_read<T: Storage, R>(table: &mut Table<T>, result: Result<R, Error>) -> Result<R, Error> {
+    if table.pager.in_transaction() {
+        return result;
+    }
+    match result {
+        Ok(value) => {
+            table.pager.commit()?;
+            Ok(value)
+        }
+        Err(error) => {
+            // Nothing changed, so there's nothing to undo but the lock, and the
+            // error that stopped the command is the one worth reporting.
+            let _ = table.pager.rollback();
+            Err(error)
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum ExecuteError {
     DuplicateKey,
+    RowNotFound,
+    /// `begin` while a transaction is already open.
+    TransactionActive,
+    /// `commit` or `rollback` without an open transaction.
+    NoTransaction,
+    /// `release` or `rollback to` a savepoint that doesn't exist.
+    NoSuchSavepoint,
+    Database(Error),
+    /// Undoing a statement that failed with `error` failed too.
+    RollbackFailed {
+        error: Box<ExecuteError>,
+        rollback: Error,
+    },
+}
+
+impl From<Error> for ExecuteError {
+    fn from(error: Error) -> Self {
+        ExecuteError::Database(error)
+    }
 }
 
 fn execute_insert<T: Storage>(row: &Row, table: &mut Table<T>) -> Result<(), ExecuteError> {
     let key_to_insert = row.id;
-    let cursor = table.find(key_to_insert);
+    let cursor = table.find(key_to_insert)?;
 
     // The cursor will always point to a leaf node.
     if cursor.cell_num < cursor.node.num_cells() {
@@ -110,7 +303,43 @@ fn execute_insert<T: Storage>(row: &Row, table: &mut Table<T>) -> Result<(), Exe
         }
     }
 
-    leaf_node_insert(cursor, row.id, row);
+    leaf_node_insert(cursor, row.id, row)?;
+    Ok(())
+}
+
+fn execute_update<T: Storage>(row: &Row, table: &mut Table<T>) -> Result<(), ExecuteError> {
+    let mut cursor = table.find(row.id)?;
+    if cursor.cell_num >= cursor.node.num_cells() || cursor.key() != row.id {
+        return Err(ExecuteError::RowNotFound);
+    }
+
+    let value = cursor.node.value_mut(cursor.cell_num);
+    value.fill(0);
+    bincode::serialize_into(value, row).unwrap();
+    Ok(())
+}
+
+fn execute_delete<T: Storage>(
+    ids: &RangeInclusive<u32>,
+    table: &mut Table<T>,
+) -> Result<(), ExecuteError> {
+    // Collect the keys up front since deleting rebalances the tree under the cursor.
+    let mut keys = Vec::new();
+    let mut cursor = table.find(*ids.start())?;
+    while !cursor.end_of_table && cursor.cell_num < cursor.node.num_cells() {
+        let key = cursor.key();
+        if !ids.contains(&key) {
+            break;
+        }
+        keys.push(key);
+        cursor.advance()?;
+    }
+
+    for key in keys {
+        let cursor = table.find(key)?;
+        leaf_node_delete(cursor)?;
+    }
+
     Ok(())
 }
 
@@ -119,32 +348,158 @@ fn execute_select<T: Storage, L: Logger>(
     table: &mut Table<T>,
     logger: &L,
 ) -> Result<(), ExecuteError> {
-    let mut cursor = table.start();
+    let mut cursor = table.start()?;
     while !cursor.end_of_table {
         let row = bincode::deserialize(cursor.value()).unwrap();
         logger.print_row(&row);
-        cursor.advance();
+        cursor.advance()?;
     }
 
     Ok(())
 }
 
+fn execute_begin<T: Storage>(table: &mut Table<T>) -> Result<(), ExecuteError> {
+    if table.pager.in_transaction() {
+        return Err(ExecuteError::TransactionActive);
+    }
+    table.pager.begin();
+    Ok(())
+}
+
+fn execute_commit<T: Storage>(table: &mut Table<T>) -> Result<(), ExecuteError> {
+    if !table.pager.in_transaction() {
+        return Err(ExecuteError::NoTransaction);
+    }
+    Ok(table.pager.commit()?)
+}
+
+fn execute_rollback<T: Storage>(table: &mut Table<T>) -> Result<(), ExecuteError> {
+    if !table.pager.in_transaction() {
+        return Err(ExecuteError::NoTransaction);
+    }
+    Ok(table.pager.rollback()?)
+}
+
+fn execute_release<T: Storage>(name: &str, table: &mut Table<T>) -> Result<(), ExecuteError> {
+    if !table.pager.has_savepoint(name) {
+        return Err(ExecuteError::NoSuchSavepoint);
+    }
+    Ok(table.pager.release(name)?)
+}
+
+fn execute_rollback_to<T: Storage>(name: &str, table: &mut Table<T>) -> Result<(), ExecuteError> {
+    if !table.pager.has_savepoint(name) {
+        return Err(ExecuteError::NoSuchSavepoint);
+    }
+    Ok(table.pager.rollback_to(name)?)
+}
+
+fn execute_pragma<T: Storage, L: Logger>(
+    pragma: &Pragma,
+    table: &mut Table<T>,
+    logger: &L,
+) -> Result<(), ExecuteError> {
+    match pragma {
+        Pragma::Synchronous(Some(synchronous)) => table.pager.set_synchronous(*synchronous),
+        Pragma::Synchronous(None) => logger.print_value(&table.pager.synchronous().to_string()),
+        Pragma::BusyTimeout(Some(timeout)) => table.pager.set_busy_timeout(*timeout),
+        Pragma::BusyTimeout(None) => {
+            logger.print_value(&table.pager.busy_timeout().as_millis().to_string())
+        }
+    }
+    Ok(())
+}
+
+/// Name of the savepoint that every write within a transaction runs under. Names
+/// typed in can't have spaces, so it never clashes with one of them.
+const STATEMENT_SAVEPOINT: &str = "statement savepoint";
+
 pub fn execute_statement<T: Storage, L: Logger>(
     statement: &Statement,
     table: &mut Table<T>,
     logger: &L,
 ) -> Result<(), ExecuteError> {
-    match statement {
+    let is_write = matches!(
+        statement,
+        Statement::Insert(_) | Statement::Update(_) | Statement::Delete(_) | Statement::Vacuum
+    );
+    // Waiting for another writer is only safe before the statement reads anything.
+    if is_write {
+        table.pager.begin_write()?;
+    }
+    // Within a transaction, a write that fails partway is undone on its own, so
+    // the transaction goes on as it was before the statement.
+    let in_savepoint = is_write && table.pager.in_transaction();
+    if in_savepoint {
+        table.pager.savepoint(STATEMENT_SAVEPOINT);
+    }
+
+    let result = match statement {
         Statement::Insert(row) => execute_insert(row, table),
+        Statement::Update(row) => execute_update(row, table),
         Statement::Select => execute_select(statement, table, logger),
+        Statement::Delete(ids) => execute_delete(ids, table),
+        Statement::Vacuum => table.vacuum().map_err(ExecuteError::from),
+        Statement::Checkpoint => table.pager.checkpoint().map_err(ExecuteError::from),
+        Statement::Begin => execute_begin(table),
+        Statement::Commit => execute_commit(table),
+        Statement::Rollback => execute_rollback(table),
+        Statement::Savepoint(name) => {
+            table.pager.savepoint(name);
+            Ok(())
+        }
+        Statement::Release(name) => execute_release(name, table),
+        Statement::RollbackTo(name) => execute_rollback_to(name, table),
+        Statement::Pragma(pragma) => execute_pragma(pragma, table, logger),
+    };
+
+    if in_savepoint {
+        return match result {
+            Ok(()) => Ok(table.pager.release(STATEMENT_SAVEPOINT)?),
+            Err(error) => Err(undo(error, || {
+                table.pager.rollback_to(STATEMENT_SAVEPOINT)?;
+                table.pager.release(STATEMENT_SAVEPOINT)
+            })),
+        };
+    }
+
+    // Outside of a transaction, every statement commits on its own, and rolls
+    // back if either it or the commit fails.
+    if !table.pager.in_transaction() {
+        return result
+            .and_then(|()| Ok(table.pager.commit()?))
+            .map_err(|error| undo(error, || table.pager.rollback()));
+    }
+    result
+}
+
+/// Undoes a statement that failed with `error`, which stays the error reported
+/// even if undoing it fails too.
+fn undo(error: ExecuteError, undo: impl FnOnce() -> Result<(), Error>) -> ExecuteError {
+    match undo() {
+        Ok(()) => error,
+        Err(rollback) => ExecuteError::RollbackFailed {
+            error: Box::new(error),
+            rollback,
+        },
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::InMemoryStorageFactory;
+    use crate::analyzer::LevelReport;
+    use crate::compression::CompressedStorageFactory;
+    use crate::encryption::EncryptedStorageFactory;
+    use crate::integrity::Problem;
+    use crate::node::{leaf, Node};
+    use crate::pager::{usable_size, JournalMode, PagerConfig, DEFAULT_PAGE_SIZE, MIN_PAGE_SIZE};
+    use crate::storage::{
+        Fault, FaultyStorageFactory, FileStorageFactory, Lock, MmapStorageFactory,
+        SharedMemoryStorageFactory, StorageFactory, TempPath,
+    };
     use std::sync::Mutex;
+    use std::time::Instant;
 
     struct InMemoryLogger {
         logs: Mutex<Vec<String>>,
@@ -163,12 +518,17 @@ mod tests {
             let mut logs = self.logs.lock().unwrap();
             logs.push(format!("{}", row));
         }
+
+        fn print_value(&self, value: &str) {
+            let mut logs = self.logs.lock().unwrap();
+            logs.push(value.to_string());
+        }
     }
 
     #[test]
     fn strings_too_long() {
-        let username: String = std::iter::repeat("a").take(33).collect();
-        let email: String = std::iter::repeat("a").take(256).collect();
+        let username: String = "a".repeat(33);
+        let email: String = "a".repeat(256);
         let query = format!("insert 1 {} {}", username, email);
         let result = Statement::try_from(query.as_str()).unwrap_err();
         assert_eq!(result, PrepareError::StringTooLong);
@@ -184,8 +544,8 @@ mod tests {
     #[test]
     fn select_nothing() {
         let logger = InMemoryLogger::new();
-        let mut storage_factory = InMemoryStorageFactory::new();
-        let mut table = Table::open(&mut storage_factory, "foobar");
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
         execute_select(&Statement::Select, &mut table, &logger).unwrap();
 
         let logs = logger.logs.into_inner().unwrap();
@@ -195,8 +555,8 @@ mod tests {
     #[test]
     fn insert_and_select() {
         let logger = InMemoryLogger::new();
-        let mut storage_factory = InMemoryStorageFactory::new();
-        let mut table = Table::open(&mut storage_factory, "foobar");
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
 
         let insert_statement = Statement::try_from("insert 1 a b").unwrap();
         execute_statement(&insert_statement, &mut table, &logger).unwrap();
@@ -206,20 +566,314 @@ mod tests {
         assert_eq!(logs, vec!["(1, a, b)"])
     }
 
-    #[test]
-    #[should_panic(expected = "Need to implement splitting internal node")]
-    fn table_full() {
-        let queries: Vec<_> = (0..1401)
-            .map(|i| format!("insert {i} user{i} person{i}@email.com"))
-            .collect();
+    /// Pages small enough that a few hundred rows make a tree several levels deep.
+    fn small_pages() -> PagerConfig {
+        PagerConfig {
+            page_size: MIN_PAGE_SIZE,
+            ..PagerConfig::default()
+        }
+    }
+
+    fn insert_and_select_ids(ids: impl Iterator<Item = u32>) -> Vec<String> {
         let logger = InMemoryLogger::new();
-        let mut storage_factory = InMemoryStorageFactory::new();
-        let mut table = Table::open(&mut storage_factory, "foobar");
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
 
-        for query in &queries {
+        for i in ids {
+            let query = format!("insert {i} user{i} person{i}@email.com");
             let statement = Statement::try_from(query.as_str()).unwrap();
             execute_statement(&statement, &mut table, &logger).unwrap();
         }
+
+        // The tree is at least three levels deep, so internal nodes have split.
+        let root = table
+            .pager
+            .page(table.root_page_num)
+            .unwrap()
+            .unwrap_internal();
+        table
+            .pager
+            .page(root.child(0).unwrap())
+            .unwrap()
+            .unwrap_internal();
+
+        execute_statement(&Statement::Select, &mut table, &logger).unwrap();
+        logger.logs.into_inner().unwrap()
+    }
+
+    fn expected_rows(count: u32) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("({i}, user{i}, person{i}@email.com)"))
+            .collect()
+    }
+
+    #[test]
+    fn split_internal_nodes_ascending() {
+        let logs = insert_and_select_ids(0..1000);
+        assert_eq!(logs, expected_rows(1000));
+    }
+
+    #[test]
+    fn split_internal_nodes_descending() {
+        let logs = insert_and_select_ids((0..1000).rev());
+        assert_eq!(logs, expected_rows(1000));
+    }
+
+    #[test]
+    fn split_internal_nodes_shuffled() {
+        let logs = insert_and_select_ids((0..1000).map(|i| i * 7 % 1000));
+        assert_eq!(logs, expected_rows(1000));
+    }
+
+    fn run<T: Storage>(table: &mut Table<T>, query: &str) {
+        let logger = InMemoryLogger::new();
+        let statement = Statement::try_from(query).unwrap();
+        execute_statement(&statement, table, &logger).unwrap();
+    }
+
+    fn select_ids<T: Storage>(table: &mut Table<T>) -> Vec<u32> {
+        let mut ids = Vec::new();
+        let mut cursor = table.start().unwrap();
+        while !cursor.end_of_table {
+            let row: Row = bincode::deserialize(cursor.value()).unwrap();
+            ids.push(row.id);
+            cursor.advance().unwrap();
+        }
+        ids
+    }
+
+    /// Walks the subtree at `page_num`, checking parent pointers and that every
+    /// internal key is the max key of its child. Returns the leaves in order.
+    fn check_subtree<T: Storage>(
+        table: &mut Table<T>,
+        page_num: u32,
+        keys: &mut Vec<u32>,
+    ) -> Vec<u32> {
+        match table.pager.page(page_num).unwrap() {
+            Node::Leaf(leaf) => {
+                keys.extend((0..leaf.num_cells()).map(|i| leaf.key(i)));
+                vec![page_num]
+            }
+            Node::Internal(internal) => {
+                let mut leaves = Vec::new();
+                for i in 0..=internal.num_keys() {
+                    let child = internal.child(i).unwrap();
+                    let child_node = table.pager.page(child).unwrap();
+                    assert_eq!(child_node.parent(), Some(page_num));
+                    leaves.extend(check_subtree(table, child, keys));
+                    if i < internal.num_keys() {
+                        assert_eq!(internal.key(i), *keys.last().unwrap());
+                    }
+                }
+                leaves
+            }
+        }
+    }
+
+    /// Checks the structure of the whole tree and returns its keys in order.
+    fn check_tree<T: Storage>(table: &mut Table<T>) -> Vec<u32> {
+        let problems = check_integrity(table).unwrap();
+        assert!(problems.is_empty(), "{problems:?}");
+        let mut keys = Vec::new();
+        let leaves = check_subtree(table, table.root_page_num, &mut keys);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+        let mut next_leaves: Vec<_> = leaves
+            .iter()
+            .map(|&leaf| table.pager.page(leaf).unwrap().unwrap_leaf().next_leaf())
+            .collect();
+        next_leaves.insert(0, leaves[0]);
+        next_leaves.pop();
+        assert_eq!(next_leaves[1..], leaves[1..]);
+        assert_eq!(
+            table
+                .pager
+                .page(*leaves.last().unwrap())
+                .unwrap()
+                .unwrap_leaf()
+                .next_leaf(),
+            0
+        );
+        keys
+    }
+
+    #[test]
+    fn delete_row() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        for i in 1..=3 {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+
+        run(&mut table, "delete where id = 2");
+        assert_eq!(select_ids(&mut table), vec![1, 3]);
+
+        // Deleting a missing row does nothing
+        run(&mut table, "delete where id = 2");
+        assert_eq!(select_ids(&mut table), vec![1, 3]);
+    }
+
+    #[test]
+    fn delete_ranges() {
+        let cases = [
+            ("delete where id < 10", vec![10, 11, 12]),
+            ("delete where id <= 10", vec![11, 12]),
+            ("delete where id > 3", vec![0, 1, 2, 3]),
+            ("delete where id >= 3", vec![0, 1, 2]),
+            ("delete where id between 2 and 11", vec![0, 1, 12]),
+            ("delete where id < 0", (0..=12).collect()),
+        ];
+        for (query, expected) in cases {
+            let mut storage_factory = SharedMemoryStorageFactory::new();
+            let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+            for i in 0..=12 {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+
+            run(&mut table, query);
+            assert_eq!(select_ids(&mut table), expected, "{query}");
+        }
+    }
+
+    #[test]
+    fn delete_syntax_error() {
+        for query in [
+            "delete",
+            "delete where id",
+            "delete where id == 1",
+            "delete where id = a",
+        ] {
+            let result = Statement::try_from(query).unwrap_err();
+            assert_eq!(result, PrepareError::SyntaxError, "{query}");
+        }
+        let result = Statement::try_from("delete where id = -1").unwrap_err();
+        assert_eq!(result, PrepareError::NegativeId);
+    }
+
+    #[test]
+    fn delete_merges_nodes_and_collapses_root() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        for i in 0..1000 {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+
+        let mut remaining: Vec<u32> = (0..1000).collect();
+        for i in (0..1000).map(|i| i * 7 % 1000) {
+            run(&mut table, &format!("delete where id = {i}"));
+            remaining.retain(|&id| id != i);
+            assert_eq!(check_tree(&mut table), remaining);
+        }
+
+        // The tree shrinks all the way back to an empty leaf root
+        let root = table.pager.page(table.root_page_num).unwrap().unwrap_leaf();
+        assert_eq!(root.num_cells(), 0);
+    }
+
+    #[test]
+    fn deleted_pages_are_recycled() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        for i in 0..300 {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+        let num_pages = table.pager.num_pages;
+
+        run(&mut table, "delete where id >= 0");
+        // Only the header and the root page are still in use
+        assert_eq!(table.pager.header().unwrap().freelist_count, num_pages - 2);
+
+        for i in 0..300 {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+        assert_eq!(table.pager.num_pages, num_pages);
+        assert_eq!(check_tree(&mut table), (0..300).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vacuum_compacts_tree() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        for i in 0..1000 {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+        run(&mut table, "delete where id between 300 and 700");
+        let expected: Vec<u32> = (0..300).chain(701..1000).collect();
+
+        run(&mut table, "vacuum");
+        assert_eq!(check_tree(&mut table), expected);
+        assert_eq!(table.pager.header().unwrap().freelist_count, 0);
+
+        // The header, the root, 2 internal nodes and 599 rows packed into 200 leaves
+        assert_eq!(table.pager.num_pages, 1 + 1 + 2 + 200);
+        table.close().unwrap();
+
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        assert_eq!(check_tree(&mut table), expected);
+        run(&mut table, "insert 500 user500 person500@email.com");
+        assert_eq!(check_tree(&mut table).len(), expected.len() + 1);
+    }
+
+    #[test]
+    fn vacuum_small_tables() {
+        for num_rows in [0, 1, 13, 14] {
+            let mut storage_factory = SharedMemoryStorageFactory::new();
+            let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+            for i in 0..num_rows {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+
+            run(&mut table, "vacuum");
+            assert_eq!(check_tree(&mut table), (0..num_rows).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn delete_range_from_multi_level_tree() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        for i in (0..1000).rev() {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+
+        run(&mut table, "delete where id between 20 and 980");
+        let expected: Vec<u32> = (0..20).chain(981..1000).collect();
+        assert_eq!(check_tree(&mut table), expected);
+        assert_eq!(select_ids(&mut table), expected);
+
+        // Inserting after deleting still works
+        run(&mut table, "insert 150 user150 person150@email.com");
+        assert_eq!(check_tree(&mut table).len(), expected.len() + 1);
     }
 
     #[test]
@@ -227,24 +881,60 @@ mod tests {
         let statement = Statement::try_from("insert 1 foo bar").unwrap();
 
         let logger = InMemoryLogger::new();
-        let mut storage_factory = InMemoryStorageFactory::new();
-        let mut table = Table::open(&mut storage_factory, "foobar");
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
 
         execute_statement(&statement, &mut table, &logger).unwrap();
         let error = execute_statement(&statement, &mut table, &logger).unwrap_err();
-        assert_eq!(error, ExecuteError::DuplicateKey);
+        assert!(matches!(error, ExecuteError::DuplicateKey));
+    }
+
+    #[test]
+    fn update_row() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        for i in 0..100 {
+            run(
+                &mut table,
+                &format!("insert {i} user{i} person{i}@email.com"),
+            );
+        }
+
+        run(&mut table, "update 42 someone someone@example.com");
+
+        let logger = InMemoryLogger::new();
+        execute_statement(&Statement::Select, &mut table, &logger).unwrap();
+        let logs = logger.logs.into_inner().unwrap();
+        assert_eq!(logs.len(), 100);
+        assert_eq!(logs[41], "(41, user41, person41@email.com)");
+        assert_eq!(logs[42], "(42, someone, someone@example.com)");
+        assert_eq!(logs[43], "(43, user43, person43@email.com)");
+    }
+
+    #[test]
+    fn update_missing_row() {
+        let logger = InMemoryLogger::new();
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "insert 1 foo bar");
+
+        for query in ["update 0 foo baz", "update 2 foo baz"] {
+            let statement = Statement::try_from(query).unwrap();
+            let error = execute_statement(&statement, &mut table, &logger).unwrap_err();
+            assert!(matches!(error, ExecuteError::RowNotFound));
+        }
     }
 
     #[test]
     fn insert_strings_of_max_length() {
         let username = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
-        let email: String = std::iter::repeat("a").take(255).collect();
+        let email: String = "a".repeat(255);
         let query = format!("insert 1 {} {}", username, email);
         let statement = Statement::try_from(query.as_str()).unwrap();
 
         let logger = InMemoryLogger::new();
-        let mut storage_factory = InMemoryStorageFactory::new();
-        let mut table = Table::open(&mut storage_factory, "foobar");
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
 
         execute_statement(&statement, &mut table, &logger).unwrap();
         execute_statement(&Statement::Select, &mut table, &logger).unwrap();
@@ -256,22 +946,887 @@ mod tests {
     #[test]
     fn keep_data_after_close() {
         let statement = Statement::try_from("insert 1 foo bar").unwrap();
-        let mut storage_factory = InMemoryStorageFactory::new();
+        let mut storage_factory = SharedMemoryStorageFactory::new();
 
         {
             let logger = InMemoryLogger::new();
-            let mut table = Table::open(&mut storage_factory, "foobar");
+            let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
             execute_statement(&statement, &mut table, &logger).unwrap();
-            table.close();
+            table.close().unwrap();
         }
 
         {
             let logger = InMemoryLogger::new();
-            let mut table = Table::open(&mut storage_factory, "foobar");
+            let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
             execute_statement(&Statement::Select, &mut table, &logger).unwrap();
 
             let logs = logger.logs.into_inner().unwrap();
             assert_eq!(logs, vec!["(1, foo, bar)"]);
         }
     }
+
+    #[test]
+    fn small_cache_evicts_pages() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            cache_capacity: 8,
+            ..PagerConfig::default()
+        };
+        let ids: Vec<u32> = (0..2000).map(|i| i * 7 % 2000).collect();
+
+        {
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+            for &i in &ids {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            // Far more pages than fit in the cache
+            assert!(table.pager.num_pages > 100);
+            assert_eq!(check_tree(&mut table), (0..2000).collect::<Vec<_>>());
+
+            run(&mut table, "delete where id < 500");
+            assert_eq!(check_tree(&mut table), (500..2000).collect::<Vec<_>>());
+            table.close().unwrap();
+        }
+
+        let mut table = Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+        assert_eq!(check_tree(&mut table), (500..2000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn crash_rolls_back_open_transaction() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            cache_capacity: 8,
+            ..small_pages()
+        };
+
+        {
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+            for i in 0..100 {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            table.close().unwrap();
+        }
+
+        {
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+            run(&mut table, "begin");
+            for i in (100..1000).map(|i| i * 7 % 900 + 100) {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            run(&mut table, "delete where id < 50");
+            // Dropped without closing after splits were partly written out
+        }
+
+        let mut table = Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+        assert_eq!(check_tree(&mut table), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn statements_commit_on_their_own() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        {
+            let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+            run(&mut table, "insert 1 foo bar");
+            run(&mut table, "insert 2 foo bar");
+            // Dropped without closing, like a crash
+        }
+
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        assert_eq!(select_ids(&mut table), vec![1, 2]);
+    }
+
+    #[test]
+    fn rollback_transaction() {
+        for journal_mode in [JournalMode::Rollback, JournalMode::Wal] {
+            let mut storage_factory = SharedMemoryStorageFactory::new();
+            let config = PagerConfig {
+                cache_capacity: 8,
+                journal_mode,
+                ..small_pages()
+            };
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+            for i in 0..100 {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            let num_pages = table.pager.num_pages;
+
+            run(&mut table, "begin");
+            for i in 100..1000 {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            run(&mut table, "delete where id < 50");
+            run(&mut table, "rollback");
+            assert!(!table.pager.in_transaction());
+            assert_eq!(table.pager.num_pages, num_pages);
+            assert_eq!(check_tree(&mut table), (0..100).collect::<Vec<_>>());
+
+            run(&mut table, "begin");
+            run(&mut table, "delete where id < 50");
+            run(&mut table, "insert 100 user100 person100@email.com");
+            run(&mut table, "commit");
+            assert_eq!(check_tree(&mut table), (50..101).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn transaction_errors() {
+        let logger = InMemoryLogger::new();
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        for query in ["commit", "rollback"] {
+            let statement = Statement::try_from(query).unwrap();
+            let error = execute_statement(&statement, &mut table, &logger).unwrap_err();
+            assert!(matches!(error, ExecuteError::NoTransaction), "{query}");
+        }
+
+        run(&mut table, "begin");
+        let error = execute_statement(&Statement::Begin, &mut table, &logger).unwrap_err();
+        assert!(matches!(error, ExecuteError::TransactionActive));
+        // A failed statement doesn't end the transaction
+        run(&mut table, "insert 1 foo bar");
+        let statement = Statement::try_from("insert 1 foo bar").unwrap();
+        execute_statement(&statement, &mut table, &logger).unwrap_err();
+        assert!(table.pager.in_transaction());
+        run(&mut table, "insert 2 foo bar");
+
+        // Closing with the transaction open rolls it back
+        table.close().unwrap();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        assert!(select_ids(&mut table).is_empty());
+    }
+
+    fn insert_rows<T: Storage>(table: &mut Table<T>, ids: impl Iterator<Item = u32>) {
+        for i in ids {
+            run(table, &format!("insert {i} user{i} person{i}@email.com"));
+        }
+    }
+
+    #[test]
+    fn btree_leaves_database_unlocked() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            busy_timeout: Duration::ZERO,
+            ..PagerConfig::default()
+        };
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+        run(&mut table, "insert 1 a b");
+        let Ok(_table) = do_meta_command(".btree", table) else {
+            panic!(".btree failed");
+        };
+
+        let mut other = Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+        run(&mut other, "insert 2 c d");
+    }
+
+    #[test]
+    fn failed_write_in_transaction_is_undone() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            cache_capacity: 4,
+            busy_timeout: Duration::ZERO,
+            ..small_pages()
+        };
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+        let mut reader = Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+        run(&mut reader, "begin");
+        run(&mut reader, "select");
+
+        // Evicting a page fails partway through an insert while the reader has the
+        // database locked
+        run(&mut table, "begin");
+        let logger = InMemoryLogger::new();
+        let mut inserted = Vec::new();
+        for i in 0.. {
+            let statement = Statement::try_from(format!("insert {i} a b").as_str()).unwrap();
+            match execute_statement(&statement, &mut table, &logger) {
+                Ok(()) => inserted.push(i),
+                Err(error) => {
+                    assert!(matches!(error, ExecuteError::Database(Error::Busy)));
+                    break;
+                }
+            }
+        }
+        assert!(table.pager.in_transaction());
+
+        run(&mut reader, "rollback");
+        run(&mut table, "commit");
+        check_tree(&mut table);
+        assert_eq!(select_ids(&mut table), inserted);
+    }
+
+    #[test]
+    fn failed_rollback_keeps_the_original_error() {
+        let mut factory = FaultyStorageFactory::new(None);
+        drop(Table::open(&mut factory, "foobar").unwrap());
+        let num_writes = factory.num_writes();
+
+        // Crash at the insert's first write, after which rolling back fails too
+        let mut factory = FaultyStorageFactory::new(Some((num_writes, Fault::Drop)));
+        let mut table = Table::open(&mut factory, "foobar").unwrap();
+        let statement = Statement::try_from("insert 1 a b").unwrap();
+        let error = execute_statement(&statement, &mut table, &InMemoryLogger::new()).unwrap_err();
+        let ExecuteError::RollbackFailed { error, rollback } = error else {
+            panic!("{error:?}");
+        };
+        assert!(matches!(*error, ExecuteError::Database(Error::Io(_))));
+        assert!(matches!(rollback, Error::Io(_)));
+    }
+
+    #[test]
+    fn rollback_to_savepoint() {
+        for journal_mode in [JournalMode::Rollback, JournalMode::Wal] {
+            let mut storage_factory = SharedMemoryStorageFactory::new();
+            let config = PagerConfig {
+                cache_capacity: 8,
+                journal_mode,
+                ..small_pages()
+            };
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+            insert_rows(&mut table, 0..100);
+
+            run(&mut table, "begin");
+            insert_rows(&mut table, 100..300);
+            run(&mut table, "savepoint a");
+            // Splits and merges, with pages evicted to storage along the way
+            insert_rows(&mut table, (300..1000).rev());
+            run(&mut table, "delete where id < 50");
+            run(&mut table, "savepoint b");
+            run(&mut table, "delete where id >= 0");
+            run(&mut table, "rollback to a");
+            assert_eq!(check_tree(&mut table), (0..300).collect::<Vec<_>>());
+
+            // The savepoint can be rolled back to again
+            run(&mut table, "delete where id between 10 and 250");
+            run(&mut table, "rollback to savepoint a");
+            assert_eq!(check_tree(&mut table), (0..300).collect::<Vec<_>>());
+            assert!(!table.pager.has_savepoint("b"));
+
+            insert_rows(&mut table, 300..310);
+            run(&mut table, "release a");
+            assert!(table.pager.in_transaction());
+            run(&mut table, "commit");
+            table.close().unwrap();
+
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+            assert_eq!(check_tree(&mut table), (0..310).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn savepoint_starts_transaction() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "savepoint outer");
+        assert!(table.pager.in_transaction());
+        insert_rows(&mut table, 0..10);
+        run(&mut table, "savepoint inner");
+        insert_rows(&mut table, 10..20);
+        run(&mut table, "release inner");
+        run(&mut table, "rollback to outer");
+        assert!(select_ids(&mut table).is_empty());
+
+        insert_rows(&mut table, 0..5);
+        // Releasing the savepoint that started the transaction commits it
+        run(&mut table, "release savepoint outer");
+        assert!(!table.pager.in_transaction());
+        run(&mut table, "begin");
+        run(&mut table, "insert 5 foo bar");
+        run(&mut table, "rollback");
+        assert_eq!(select_ids(&mut table), (0..5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn savepoint_errors() {
+        let logger = InMemoryLogger::new();
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "savepoint a");
+        for query in ["release b", "rollback to b"] {
+            let statement = Statement::try_from(query).unwrap();
+            let error = execute_statement(&statement, &mut table, &logger).unwrap_err();
+            assert!(matches!(error, ExecuteError::NoSuchSavepoint), "{query}");
+        }
+        run(&mut table, "release a");
+        let error = execute_statement(&Statement::Release("a".to_string()), &mut table, &logger)
+            .unwrap_err();
+        assert!(matches!(error, ExecuteError::NoSuchSavepoint));
+
+        for query in ["savepoint", "savepoint a b", "release", "rollback to"] {
+            let result = Statement::try_from(query).unwrap_err();
+            assert_eq!(result, PrepareError::SyntaxError, "{query}");
+        }
+    }
+
+    /// Statements that split, merge and rebuild the tree, in and out of a transaction.
+    fn crash_workload() -> Vec<String> {
+        let mut queries = Vec::new();
+        for i in 0..30 {
+            let id = i * 17 % 30;
+            queries.push(format!("insert {id} user{id} person{id}@email.com"));
+        }
+        queries.push("begin".to_string());
+        for id in 30..38 {
+            queries.push(format!("insert {id} user{id} person{id}@email.com"));
+        }
+        queries.push("delete where id between 5 and 20".to_string());
+        queries.push("commit".to_string());
+        queries.push("update 25 user0 person0@email.com".to_string());
+        queries.push("delete where id >= 28".to_string());
+        queries.push("checkpoint".to_string());
+        queries.push("vacuum".to_string());
+        queries
+    }
+
+    /// Runs `queries` until one fails, returning the ids committed by the
+    /// statements that succeeded, given the ids committed after each statement.
+    fn run_until_crash(
+        factory: &mut FaultyStorageFactory,
+        config: &PagerConfig,
+        queries: &[String],
+        committed_ids: &[Vec<u32>],
+    ) -> Vec<u32> {
+        let logger = InMemoryLogger::new();
+        let mut committed = Vec::new();
+        let Ok(mut table) = Table::open_with_config(factory, "foobar", config.clone()) else {
+            return committed;
+        };
+        for (query, ids) in queries.iter().zip(committed_ids) {
+            let statement = Statement::try_from(query.as_str()).unwrap();
+            if execute_statement(&statement, &mut table, &logger).is_err() {
+                break;
+            }
+            committed = ids.clone();
+        }
+        // Dropped without closing, like a crash
+        committed
+    }
+
+    #[test]
+    fn crash_at_every_write_keeps_committed_rows() {
+        let queries = crash_workload();
+        for journal_mode in [JournalMode::Rollback, JournalMode::Wal] {
+            let config = PagerConfig {
+                cache_capacity: 8,
+                journal_mode,
+                ..small_pages()
+            };
+
+            // Find out what's committed after each statement, and how many writes there are.
+            let mut factory = FaultyStorageFactory::new(None);
+            let mut table =
+                Table::open_with_config(&mut factory, "foobar", config.clone()).unwrap();
+            let mut committed_ids = Vec::new();
+            let mut committed = Vec::new();
+            for query in &queries {
+                run(&mut table, query);
+                if !table.pager.in_transaction() {
+                    committed = select_ids(&mut table);
+                }
+                committed_ids.push(committed.clone());
+            }
+            let num_writes = factory.num_writes();
+
+            for index in 0..num_writes {
+                for fault in [Fault::Drop, Fault::Tear, Fault::Reorder] {
+                    let mut factory = FaultyStorageFactory::new(Some((index, fault)));
+                    let committed =
+                        run_until_crash(&mut factory, &config, &queries, &committed_ids);
+
+                    factory.restart();
+                    let mut table =
+                        Table::open_with_config(&mut factory, "foobar", config.clone()).unwrap();
+                    let ids = check_tree(&mut table);
+                    assert_eq!(
+                        ids, committed,
+                        "{journal_mode:?} {fault:?} at write {index}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interrupted_vacuum_leaves_database_as_it_was() {
+        let config = PagerConfig {
+            cache_capacity: 8,
+            ..small_pages()
+        };
+        let queries = [
+            "delete where id between 5 and 20".to_string(),
+            "vacuum".to_string(),
+        ];
+        let create = |factory: &mut FaultyStorageFactory| {
+            let mut table = Table::open_with_config(factory, "foobar", config.clone()).unwrap();
+            insert_rows(&mut table, 0..30);
+            table.close().unwrap();
+        };
+        let remaining: Vec<_> = (0..5).chain(21..30).collect();
+
+        let mut factory = FaultyStorageFactory::new(None);
+        create(&mut factory);
+        let mut table = Table::open_with_config(&mut factory, "foobar", config.clone()).unwrap();
+        run(&mut table, &queries[0]);
+        let vacuum_start = factory.num_writes();
+        run(&mut table, &queries[1]);
+        let vacuum_end = factory.num_writes();
+
+        // The rebuilt tree is copied over the old one in a single commit, so a
+        // crash partway through leaves the rows as they were before.
+        for index in vacuum_start..vacuum_end {
+            for fault in [Fault::Drop, Fault::Tear, Fault::Reorder] {
+                let mut factory = FaultyStorageFactory::new(Some((index, fault)));
+                create(&mut factory);
+                let committed = vec![remaining.clone(), remaining.clone()];
+                run_until_crash(&mut factory, &config, &queries, &committed);
+
+                factory.restart();
+                let mut table =
+                    Table::open_with_config(&mut factory, "foobar", config.clone()).unwrap();
+                assert_eq!(
+                    check_tree(&mut table),
+                    remaining,
+                    "{fault:?} at write {index}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pragma_synchronous() {
+        let logger = InMemoryLogger::new();
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        for query in ["pragma synchronous = off", "pragma synchronous = 1"] {
+            run(&mut table, query);
+            let statement = Statement::try_from("pragma synchronous").unwrap();
+            execute_statement(&statement, &mut table, &logger).unwrap();
+        }
+        assert_eq!(table.pager.synchronous(), Synchronous::Normal);
+        let logs = logger.logs.into_inner().unwrap();
+        assert_eq!(logs, vec!["off", "normal"]);
+
+        for query in [
+            "pragma",
+            "pragma synchronous = sometimes",
+            "pragma synchronous full",
+            "pragma page_size",
+        ] {
+            let result = Statement::try_from(query).unwrap_err();
+            assert_eq!(result, PrepareError::SyntaxError, "{query}");
+        }
+    }
+
+    #[test]
+    fn pragma_busy_timeout() {
+        let logger = InMemoryLogger::new();
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "pragma busy_timeout = 250");
+        let statement = Statement::try_from("pragma busy_timeout").unwrap();
+        execute_statement(&statement, &mut table, &logger).unwrap();
+        assert_eq!(table.pager.busy_timeout(), Duration::from_millis(250));
+        assert_eq!(logger.logs.into_inner().unwrap(), vec!["250"]);
+
+        for query in ["pragma busy_timeout = -1", "pragma busy_timeout = soon"] {
+            let result = Statement::try_from(query).unwrap_err();
+            assert_eq!(result, PrepareError::SyntaxError, "{query}");
+        }
+    }
+
+    #[test]
+    fn wal_mode() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            cache_capacity: 8,
+            journal_mode: JournalMode::Wal,
+            ..small_pages()
+        };
+
+        {
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config.clone()).unwrap();
+            for i in (0..1000).map(|i| i * 7 % 1000) {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            run(&mut table, "checkpoint");
+            run(&mut table, "delete where id >= 500");
+            run(&mut table, "vacuum");
+            assert_eq!(check_tree(&mut table), (0..500).collect::<Vec<_>>());
+            table.close().unwrap();
+        }
+
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        assert_eq!(check_tree(&mut table), (0..500).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn page_size_is_kept_in_the_header() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let config = PagerConfig {
+            page_size: 16384,
+            ..PagerConfig::default()
+        };
+
+        {
+            let mut table =
+                Table::open_with_config(&mut storage_factory, "foobar", config).unwrap();
+            for i in 0..52 {
+                run(
+                    &mut table,
+                    &format!("insert {i} user{i} person{i}@email.com"),
+                );
+            }
+            // All 52 rows fit in the root leaf
+            let root = table.pager.page(table.root_page_num).unwrap().unwrap_leaf();
+            assert_eq!(root.max_cells(), 52);
+            table.close().unwrap();
+        }
+
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        assert_eq!(table.pager.usable_size(), usable_size(16384));
+        run(&mut table, "insert 52 user52 person52@email.com");
+        assert_eq!(check_tree(&mut table), (0..53).collect::<Vec<_>>());
+        table
+            .pager
+            .page(table.root_page_num)
+            .unwrap()
+            .unwrap_internal();
+    }
+
+    #[test]
+    fn integrity_check_reports_every_problem() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        insert_rows(&mut table, 0..30);
+        let pager = &mut table.pager;
+        let mut root = pager.page(table.root_page_num).unwrap().unwrap_internal();
+        let leaves: Vec<_> = (0..=root.num_keys())
+            .map(|i| root.child(i).unwrap())
+            .collect();
+
+        let mut leaf = pager.page(leaves[0]).unwrap().unwrap_leaf();
+        leaf.set_key(0, 1);
+        leaf.value_mut(1).fill(0xff);
+        pager.page(leaves[1]).unwrap().set_parent(leaves[0]);
+        pager.page(leaves[2]).unwrap().set_root(true);
+        root.set_key(3, 100);
+        let mut leaf = pager.page(leaves[4]).unwrap().unwrap_leaf();
+        leaf.set_next_leaf(leaves[6]);
+        root.set_child(6, leaves[5]).unwrap();
+        let unused_page_num = pager.get_unused_page_num().unwrap();
+
+        let problems: Vec<_> = check_integrity(&mut table)
+            .unwrap()
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                format!("leaf {} has cell 0 holding row 0 under key 1", leaves[0]),
+                format!(
+                    "leaf {} has key 1 out of order with the key before it",
+                    leaves[0]
+                ),
+                format!("leaf {} has cell 1 that isn't a row", leaves[0]),
+                format!("page {} has parent {} instead of 1", leaves[1], leaves[0]),
+                format!(
+                    "page {} is marked as the root instead of having parent 1",
+                    leaves[2]
+                ),
+                format!("page 1 has key 3 set to 100 instead of 7, the max key of its child"),
+                format!("page {} is referenced more than once", leaves[5]),
+                format!(
+                    "leaf {} has next leaf {} instead of {}",
+                    leaves[4], leaves[6], leaves[5]
+                ),
+                format!(
+                    "leaf {} has next leaf {} instead of {}",
+                    leaves[5], leaves[6], leaves[7]
+                ),
+                format!(
+                    "page {} is neither in the tree nor on the freelist",
+                    leaves[6]
+                ),
+                format!("page {unused_page_num} is neither in the tree nor on the freelist"),
+            ]
+        );
+    }
+
+    #[test]
+    fn integrity_check_reports_root_past_the_end() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "insert 1 a b");
+        table.root_page_num = 100;
+
+        let problems = check_integrity(&mut table).unwrap();
+        assert!(matches!(
+            problems[..],
+            [
+                Problem::OutOfRange {
+                    page_num: 100,
+                    referenced_by: 0,
+                },
+                Problem::Orphan { page_num: 1 },
+            ]
+        ));
+    }
+
+    #[test]
+    fn integrity_check_goes_on_past_unreadable_pages() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        insert_rows(&mut table, 0..30);
+        let root = table.pager.page(table.root_page_num).unwrap();
+        let leaf_page_num = root.unwrap_internal().child(2).unwrap();
+        table.close().unwrap();
+
+        let mut storage = storage_factory.open("foobar").unwrap();
+        let mut page = vec![0u8; MIN_PAGE_SIZE];
+        storage.read(leaf_page_num as usize, &mut page).unwrap();
+        page[100] ^= 1;
+        storage.write(leaf_page_num as usize, &page).unwrap();
+
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        let problems = check_integrity(&mut table).unwrap();
+        assert!(matches!(
+            problems[..],
+            [Problem::Unreadable {
+                page_num,
+                error: Error::CorruptPage(_),
+            }] if page_num == leaf_page_num
+        ));
+
+        let Ok(table) = do_meta_command(".check", table) else {
+            panic!(".check failed");
+        };
+        assert_eq!(table.pager.lock(), Lock::Unlocked);
+    }
+
+    #[test]
+    fn analyzer_reports_how_full_pages_are() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table =
+            Table::open_with_config(&mut storage_factory, "foobar", small_pages()).unwrap();
+        insert_rows(&mut table, 0..30);
+        let root = table.pager.page(table.root_page_num).unwrap();
+        let root = root.unwrap_internal();
+        let leaves = root.num_keys() + 1;
+        let max_cells = leaf::max_cells(usable_size(MIN_PAGE_SIZE));
+        let leaf_unused: usize = (0..leaves)
+            .map(|i| {
+                let child = root.child(i).unwrap();
+                table
+                    .pager
+                    .page(child)
+                    .unwrap()
+                    .unwrap_leaf()
+                    .unused_bytes()
+            })
+            .sum();
+
+        let report = analyze(&mut table).unwrap();
+        assert_eq!(report.page_size, MIN_PAGE_SIZE);
+        assert_eq!(report.page_count, leaves + 2);
+        assert_eq!(report.free_pages, 0);
+        assert_eq!(
+            report.levels,
+            vec![
+                LevelReport {
+                    internal_pages: 1,
+                    leaf_pages: 0,
+                    keys: root.num_keys() as u64,
+                    cells: 0,
+                    unused_bytes: root.unused_bytes() as u64,
+                },
+                LevelReport {
+                    internal_pages: 0,
+                    leaf_pages: leaves,
+                    keys: 0,
+                    cells: 30,
+                    unused_bytes: leaf_unused as u64,
+                },
+            ]
+        );
+        assert_eq!(report.max_cells, max_cells);
+        assert_eq!(report.leaf_fill, 30.0 / (leaves * max_cells) as f64);
+        assert_eq!(report.fan_out, leaves as f64);
+        assert_eq!(
+            report.unused_bytes_per_page,
+            (root.unused_bytes() + leaf_unused) as f64 / (leaves + 1) as f64
+        );
+
+        for command in [".analyze", ".analyze json"] {
+            let Ok(result) = do_meta_command(command, table) else {
+                panic!("{command} failed");
+            };
+            table = result;
+            assert_eq!(table.pager.lock(), Lock::Unlocked);
+        }
+    }
+
+    #[test]
+    fn analyzer_finds_no_unused_bytes_in_overfull_nodes() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "insert 1 foo bar");
+        // A damaged cell count, more than the page holds
+        let mut root = table.pager.page(table.root_page_num).unwrap().unwrap_leaf();
+        root.set_num_cells(root.max_cells() + 1);
+
+        let report = analyze(&mut table).unwrap();
+        assert_eq!(report.levels[0].unused_bytes, 0);
+    }
+
+    #[test]
+    fn corrupt_node_is_an_error() {
+        let mut storage_factory = SharedMemoryStorageFactory::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        run(&mut table, "insert 1 foo bar");
+        table.close().unwrap();
+
+        // Flip a bit in the row stored in the root
+        let mut storage = storage_factory.open("foobar").unwrap();
+        let mut page = vec![0u8; 4096];
+        storage.read(1, &mut page).unwrap();
+        page[100] ^= 1;
+        storage.write(1, &page).unwrap();
+
+        let logger = InMemoryLogger::new();
+        let mut table = Table::open(&mut storage_factory, "foobar").unwrap();
+        let error = execute_statement(&Statement::Select, &mut table, &logger).unwrap_err();
+        assert!(matches!(
+            error,
+            ExecuteError::Database(Error::CorruptPage(1))
+        ));
+    }
+
+    /// Fills a database file through `storage_factory` with rows out of order
+    /// and deletes some, hands the table to `check`, then vacuums and checks the
+    /// rest of the rows are there after reopening.
+    fn keeps_data_after_close<T: Storage, F: StorageFactory<T>>(
+        storage_factory: &mut F,
+        filename: &str,
+        check: impl FnOnce(&mut Table<T>),
+    ) {
+        let config = PagerConfig {
+            cache_capacity: 8,
+            ..PagerConfig::default()
+        };
+        let mut table = Table::open_with_config(storage_factory, filename, config.clone()).unwrap();
+        insert_rows(&mut table, (0..1000).map(|i| i * 7 % 1000));
+        run(&mut table, "delete where id >= 800");
+        check(&mut table);
+        run(&mut table, "vacuum");
+        table.close().unwrap();
+
+        let mut table = Table::open_with_config(storage_factory, filename, config).unwrap();
+        assert_eq!(check_tree(&mut table), (0..800).collect::<Vec<_>>());
+        table.close().unwrap();
+    }
+
+    #[test]
+    fn mmap_storage_keeps_data_after_close() {
+        let path = TempPath::new();
+        keeps_data_after_close(&mut MmapStorageFactory, path.filename(), |_| {});
+
+        let mut table = Table::open(&mut FileStorageFactory, path.filename()).unwrap();
+        assert_eq!(check_tree(&mut table).len(), 800);
+        table.close().unwrap();
+    }
+
+    #[test]
+    fn compressed_storage_keeps_data_after_close() {
+        let path = TempPath::new();
+        let mut storage_factory = CompressedStorageFactory::new(FileStorageFactory);
+        keeps_data_after_close(&mut storage_factory, path.filename(), |table| {
+            // Rows are mostly padding, so the file is a fraction of the pages' size.
+            let length = table.pager.num_pages as u64 * DEFAULT_PAGE_SIZE as u64;
+            assert!(std::fs::metadata(path.filename()).unwrap().len() < length / 3);
+        });
+    }
+
+    #[test]
+    fn encrypted_storage_keeps_data_after_close() {
+        let path = TempPath::new();
+        let mut storage_factory = EncryptedStorageFactory::new(FileStorageFactory, "secret");
+        keeps_data_after_close(&mut storage_factory, path.filename(), |_| {});
+
+        let contents = std::fs::read(path.filename()).unwrap();
+        assert!(!contents.windows(7).any(|window| window == b"person5"));
+        let mut wrong_factory = EncryptedStorageFactory::new(FileStorageFactory, "guess");
+        let result = Table::open(&mut wrong_factory, path.filename());
+        assert!(matches!(result, Err(Error::WrongKey)));
+    }
+
+    /// Times full table scans through a cache far smaller than the table, so
+    /// nearly every page is read from storage, with `FileStorage` and with
+    /// `MmapStorage`. Both copy each page into the cache, so this measures what
+    /// the mapping saves in system calls. Run it with
+    /// `cargo test --release scan_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn scan_benchmark() {
+        fn time_scans<T: Storage, F: StorageFactory<T>>(
+            storage_factory: &mut F,
+            filename: &str,
+        ) -> std::time::Duration {
+            let config = PagerConfig {
+                cache_capacity: 16,
+                ..PagerConfig::default()
+            };
+            let mut table = Table::open_with_config(storage_factory, filename, config).unwrap();
+            let start = Instant::now();
+            for _ in 0..20 {
+                assert_eq!(select_ids(&mut table).len(), 20000);
+            }
+            let elapsed = start.elapsed();
+            table.close().unwrap();
+            elapsed
+        }
+
+        let path = TempPath::new();
+        let filename = path.filename();
+        let mut table = Table::open(&mut FileStorageFactory, filename).unwrap();
+        run(&mut table, "begin");
+        insert_rows(&mut table, 0..20000);
+        run(&mut table, "commit");
+        table.close().unwrap();
+
+        let file = time_scans(&mut FileStorageFactory, filename);
+        let mmap = time_scans(&mut MmapStorageFactory, filename);
+        println!("20 scans of 20000 rows: FileStorage {file:?}, MmapStorage {mmap:?}");
+    }
 }
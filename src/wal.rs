@@ -0,0 +1,395 @@
+use crate::checksum;
+use crate::error::{Error, Result};
+use crate::pager::Synchronous;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::mem::size_of;
+
+// Write-Ahead Log Layout
+//
+// | header | frame | frame | ... |
+//
+// The header and every frame take up one slot of `FRAME_HEADER_SIZE + page_size`
+// bytes, so the log can be read and written one slot at a time.
+//
+// Frame Layout
+//
+// | frame header | page contents |
+const FRAME_HEADER_SIZE: usize = size_of::<FrameHeader>();
+
+/// Every write-ahead log starts with these bytes.
+const MAGIC: [u8; 8] = *b"cstackw\0";
+
+/// Log header stored in the first slot.
+#[derive(Debug, Serialize, Deserialize)]
+struct WalHeader {
+    magic: [u8; 8],
+    page_size: u32,
+    /// Changes every time the log starts over and seeds the checksums of its
+    /// frames, so frames left over from before don't check out.
+    salt: u32,
+}
+
+const WAL_HEADER_SIZE: usize = size_of::<WalHeader>();
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FrameHeader {
+    page_num: u32,
+    /// Number of pages in the database after the transaction for the frame that
+    /// commits it, or 0 for every other frame.
+    page_count: u32,
+    /// Checksum of the frame, continuing from the checksum of the frame before it,
+    /// so a frame only checks out if every frame before it does too.
+    checksum: u32,
+}
+
+/// Gets the name of the write-ahead log that goes with the database `filename`.
+pub fn wal_filename(filename: &str) -> String {
+    format!("{filename}-wal")
+}
+
+/// Write-ahead log that modified pages are appended to instead of overwriting
+/// them in the database file.
+///
+/// A transaction is committed once the frame marking its end is synced. Reads
+/// check the log for the newest copy of a page before falling back to the
+/// database file, and checkpointing copies committed pages back into the
+/// database so the log can start over.
+///
+/// Every connection keeps its own index of the log, which it brings up to date
+/// with what other connections committed at the start of each transaction, so
+/// readers keep going while a writer appends.
+pub struct Wal<T> {
+    storage: T,
+    page_size: usize,
+    /// Salt of the log as of when it was last read or started over.
+    salt: Option<u32>,
+    /// Number of frames in the log.
+    num_frames: usize,
+    /// Checksum of the last frame, which the next frame continues from.
+    checksum: u32,
+    /// Number of frames and checksum of the last frame as of the last commit.
+    last_commit: (usize, u32),
+    /// Newest committed frame of each page.
+    committed: HashMap<u32, usize>,
+    /// Frames of the transaction in progress, which are only used after a crash
+    /// once a later frame commits them.
+    pending: HashMap<u32, usize>,
+    /// Number of pages in the database as of the last commit.
+    page_count: Option<u32>,
+    synced: bool,
+}
+
+impl<T: Storage> Wal<T> {
+    /// Opens the log in `storage`, which is either started over with `start` or
+    /// indexed by `refresh` before it's used.
+    pub fn new(storage: T, page_size: usize) -> Wal<T> {
+        Wal {
+            storage,
+            page_size,
+            salt: None,
+            num_frames: 0,
+            checksum: checksum::INITIAL,
+            last_commit: (0, checksum::INITIAL),
+            committed: HashMap::new(),
+            pending: HashMap::new(),
+            page_count: None,
+            synced: true,
+        }
+    }
+
+    /// Empties the log, giving it a new salt so other connections that read it
+    /// before can tell it started over.
+    pub fn start(&mut self) -> Result<()> {
+        let header = WalHeader {
+            magic: MAGIC,
+            page_size: self.page_size as u32,
+            salt: self.salt.map_or(0, |salt| salt.wrapping_add(1)),
+        };
+        let mut slot = vec![0u8; FRAME_HEADER_SIZE + self.page_size];
+        bincode::serialize_into(slot.as_mut_slice(), &header).unwrap();
+        self.storage.write(0, &slot)?;
+        self.storage.truncate(slot.len() as u64)?;
+        self.synced = false;
+        self.reset(Some(header.salt));
+        Ok(())
+    }
+
+    /// Indexes the transactions other connections committed since the log was
+    /// last read, starting over if the log did.
+    pub fn refresh(&mut self) -> Result<()> {
+        let salt = self.read_salt()?;
+        if salt != self.salt {
+            self.reset(salt);
+        }
+        if salt.is_none() {
+            return Ok(());
+        }
+        let commits = read_commits(&mut self.storage, self.page_size, self.last_commit)?;
+        if let Some(page_count) = commits.page_count {
+            self.committed.extend(commits.frames);
+            self.page_count = Some(page_count);
+            self.last_commit = commits.last_commit;
+            (self.num_frames, self.checksum) = self.last_commit;
+        }
+        Ok(())
+    }
+
+    /// Gets whether another connection committed a transaction or started the
+    /// log over since it was last read.
+    pub fn is_behind(&mut self) -> Result<bool> {
+        if self.read_salt()? != self.salt {
+            return Ok(true);
+        }
+        Ok(self.salt.is_some()
+            && read_commits(&mut self.storage, self.page_size, self.last_commit)?
+                .page_count
+                .is_some())
+    }
+
+    /// Reads the salt from the header, unless the log hasn't been started.
+    fn read_salt(&mut self) -> Result<Option<u32>> {
+        Ok(read_header(&mut self.storage)?
+            .filter(|header| header.page_size as usize == self.page_size)
+            .map(|header| header.salt))
+    }
+
+    /// Forgets every frame, as if the log had just started over with `salt`.
+    fn reset(&mut self, salt: Option<u32>) {
+        self.salt = salt;
+        self.num_frames = 0;
+        self.checksum = salt.map_or(checksum::INITIAL, initial_checksum);
+        self.last_commit = (0, self.checksum);
+        self.committed.clear();
+        self.pending.clear();
+        self.page_count = None;
+    }
+
+    /// Gets the newest frame holding `page_num`, if the page is in the log.
+    pub fn find(&self, page_num: u32) -> Option<usize> {
+        self.pending
+            .get(&page_num)
+            .or_else(|| self.committed.get(&page_num))
+            .copied()
+    }
+
+    /// Reads the page contents of `frame` into `page`.
+    pub fn read(&mut self, frame: usize, page: &mut [u8]) -> Result<()> {
+        let mut buf = vec![0u8; FRAME_HEADER_SIZE + self.page_size];
+        let slot = self.storage.page(frame, &mut buf)?;
+        page.copy_from_slice(&slot[FRAME_HEADER_SIZE..]);
+        Ok(())
+    }
+
+    /// Gets whether frames were appended since the last commit.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Appends a frame holding `page`. Passing the number of pages in the database
+    /// as `page_count` makes it the frame that commits the transaction.
+    pub fn append(&mut self, page_num: u32, page: &[u8], page_count: Option<u32>) -> Result<()> {
+        let mut header = FrameHeader {
+            page_num,
+            page_count: page_count.unwrap_or(0),
+            checksum: 0,
+        };
+        header.checksum = frame_checksum(self.checksum, &header, page);
+        let mut slot = vec![0u8; FRAME_HEADER_SIZE + self.page_size];
+        bincode::serialize_into(slot.as_mut_slice(), &header).unwrap();
+        slot[FRAME_HEADER_SIZE..].copy_from_slice(page);
+
+        self.num_frames += 1;
+        self.storage.write(self.num_frames, &slot)?;
+        self.checksum = header.checksum;
+        self.pending.insert(page_num, self.num_frames);
+        self.synced = false;
+        if page_count.is_some() {
+            self.committed.extend(self.pending.drain());
+            self.page_count = page_count;
+            self.last_commit = (self.num_frames, self.checksum);
+        }
+        Ok(())
+    }
+
+    /// Drops every frame appended since the last commit.
+    ///
+    /// The frames are left in the log, where other connections may be reading,
+    /// until later ones overwrite them. None of them commits, so they're ignored.
+    pub fn rollback(&mut self) {
+        (self.num_frames, self.checksum) = self.last_commit;
+        self.pending.clear();
+    }
+
+    /// Makes every frame appended so far durable.
+    pub fn sync(&mut self) -> Result<()> {
+        if !self.synced {
+            self.storage.sync()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /// Copies every committed page into `database`. The log starts over if no
+    /// transaction is in progress, otherwise its frames are kept until the next
+    /// checkpoint.
+    ///
+    /// Unless `synchronous` is off, the log is synced before the database is
+    /// overwritten, and the database before the log starts over.
+    pub fn checkpoint(&mut self, database: &mut T, synchronous: Synchronous) -> Result<()> {
+        let Some(page_count) = self.page_count else {
+            return Ok(());
+        };
+        if synchronous != Synchronous::Off {
+            self.sync()?;
+        }
+        copy_frames(
+            &mut self.storage,
+            database,
+            self.page_size,
+            &self.committed,
+            page_count,
+        )?;
+        if synchronous != Synchronous::Off {
+            database.sync()?;
+        }
+        if self.has_pending() {
+            return Ok(());
+        }
+
+        // Losing the new header only means the frames are copied again on recovery.
+        self.start()?;
+        if synchronous == Synchronous::Full {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the log, which must be empty.
+    pub fn remove(self) -> Result<()> {
+        self.storage.remove()
+    }
+}
+
+/// Gets the page size of the database that `wal` belongs to, unless the log
+/// hasn't been started.
+pub fn page_size<T: Storage>(wal: &mut T) -> Result<Option<usize>> {
+    Ok(read_header(wal)?.map(|header| header.page_size as usize))
+}
+
+/// Copies every committed page left in `wal` into `database`, then empties the log.
+pub fn recover<T: Storage>(wal: &mut T, database: &mut T) -> Result<()> {
+    if wal.size()? == 0 {
+        return Ok(());
+    }
+
+    if let Some(header) = read_header(wal)? {
+        let page_size = header.page_size as usize;
+        let commits = read_commits(wal, page_size, (0, initial_checksum(header.salt)))?;
+        if let Some(page_count) = commits.page_count {
+            copy_frames(wal, database, page_size, &commits.frames, page_count)?;
+            database.sync()?;
+        }
+    }
+
+    wal.truncate(0)?;
+    wal.sync()
+}
+
+/// Reads the header of `wal`, unless it's missing or torn.
+fn read_header<T: Storage>(wal: &mut T) -> Result<Option<WalHeader>> {
+    if wal.size()? < WAL_HEADER_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut header = [0u8; WAL_HEADER_SIZE];
+    // A header that can't be read back was torn, the same as a bad checksum.
+    match wal.read(0, &mut header) {
+        Err(Error::Corrupt) => return Ok(None),
+        result => result?,
+    }
+    Ok(bincode::deserialize::<WalHeader>(&header)
+        .ok()
+        .filter(|header| header.magic == MAGIC))
+}
+
+/// Transactions read back from a log.
+struct Commits {
+    /// Newest frame of each page written by the transactions.
+    frames: HashMap<u32, usize>,
+    /// Number of frames and checksum of the last frame as of the last commit.
+    last_commit: (usize, u32),
+    /// Number of pages in the database after the last transaction, if any.
+    page_count: Option<u32>,
+}
+
+/// Reads the transactions committed to `wal` after `start`, the number of frames
+/// and the checksum of the last frame to continue from.
+///
+/// Frames are checked against their checksums, and everything from the first one
+/// that doesn't match, or that the storage can't read back, is ignored, as are
+/// frames after the last commit since their transaction never finished.
+fn read_commits<T: Storage>(wal: &mut T, page_size: usize, start: (usize, u32)) -> Result<Commits> {
+    let slot_size = FRAME_HEADER_SIZE + page_size;
+    let num_slots = wal.size()? as usize / slot_size;
+    let mut buf = vec![0u8; slot_size];
+    let mut checksum = start.1;
+    let mut pending = HashMap::new();
+    let mut commits = Commits {
+        frames: HashMap::new(),
+        last_commit: start,
+        page_count: None,
+    };
+    for frame in start.0 + 1..num_slots {
+        let slot = match wal.page(frame, &mut buf) {
+            Err(Error::Corrupt) => break,
+            slot => slot?,
+        };
+        let header: FrameHeader = bincode::deserialize(slot).unwrap();
+        if header.checksum != frame_checksum(checksum, &header, &slot[FRAME_HEADER_SIZE..]) {
+            break;
+        }
+        checksum = header.checksum;
+        pending.insert(header.page_num, frame);
+        if header.page_count > 0 {
+            commits.frames.extend(pending.drain());
+            commits.last_commit = (frame, checksum);
+            commits.page_count = Some(header.page_count);
+        }
+    }
+    Ok(commits)
+}
+
+/// Writes the pages in `frames` into `database` and cuts it down to `page_count`
+/// pages.
+fn copy_frames<T: Storage>(
+    wal: &mut T,
+    database: &mut T,
+    page_size: usize,
+    frames: &HashMap<u32, usize>,
+    page_count: u32,
+) -> Result<()> {
+    let mut frames: Vec<_> = frames
+        .iter()
+        .filter(|(&page_num, _)| page_num < page_count)
+        .collect();
+    frames.sort();
+    let mut buf = vec![0u8; FRAME_HEADER_SIZE + page_size];
+    for (&page_num, &frame) in frames {
+        let slot = wal.page(frame, &mut buf)?;
+        database.write(page_num as usize, &slot[FRAME_HEADER_SIZE..])?;
+    }
+    database.truncate(page_count as u64 * page_size as u64)
+}
+
+/// Checksum that the first frame after a header with `salt` continues from.
+fn initial_checksum(salt: u32) -> u32 {
+    checksum::update(checksum::INITIAL, &salt.to_le_bytes())
+}
+
+/// Checksum of a frame, continuing from the checksum of the frame before it.
+fn frame_checksum(previous: u32, header: &FrameHeader, page: &[u8]) -> u32 {
+    let checksum = checksum::update(previous, &header.page_num.to_le_bytes());
+    let checksum = checksum::update(checksum, &header.page_count.to_le_bytes());
+    checksum::update(checksum, page)
+}
@@ -1,35 +1,53 @@
 use std::env;
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
 
 struct Database {
     filename: PathBuf,
     cstack_path: String,
 }
 
+fn cstack_path() -> String {
+    env::var("CSTACK_PATH").unwrap_or_else(|_| env!("CARGO_BIN_EXE_cstack").to_string())
+}
+
 impl Database {
     fn new() -> Database {
-        let cstack_path =
-            env::var("CSTACK_PATH").expect("missing CSTACK_PATH environment variable");
-
         let filename = uuid::Uuid::new_v4().to_string();
         let mut path = std::path::PathBuf::from(&filename);
         path.set_extension("db");
 
         Database {
             filename: path,
-            cstack_path,
+            cstack_path: cstack_path(),
         }
     }
 
     fn run_script<T: AsRef<str>>(&self, commands: Vec<T>) -> Vec<String> {
-        let process = Command::new(&self.cstack_path)
+        self.run_script_with_args(&[], commands)
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(&self.cstack_path);
+        command
+            .args(args)
             .arg(&self.filename)
             .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
+            .stdout(Stdio::piped());
+        command
+    }
+
+    fn spawn(&self, args: &[&str]) -> Child {
+        self.command(args).spawn().unwrap()
+    }
+
+    fn run_script_with_args<T: AsRef<str>>(&self, args: &[&str], commands: Vec<T>) -> Vec<String> {
+        self.run_command(self.command(args), commands)
+    }
+
+    fn run_command<T: AsRef<str>>(&self, mut command: Command, commands: Vec<T>) -> Vec<String> {
+        let mut process = command.spawn().unwrap();
 
         let mut input = String::new();
         for command in commands {
@@ -37,10 +55,14 @@ impl Database {
             input.push('\n');
         }
 
-        process.stdin.unwrap().write_all(input.as_bytes()).unwrap();
+        // The process may exit before reading everything, e.g. if it can't open the file.
+        let result = process.stdin.take().unwrap().write_all(input.as_bytes());
+        if let Err(error) = result {
+            assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+        }
 
-        let mut string = String::new();
-        process.stdout.unwrap().read_to_string(&mut string).unwrap();
+        let output = process.wait_with_output().unwrap();
+        let string = String::from_utf8(output.stdout).unwrap();
         string.lines().map(|l| l.to_string()).collect()
     }
 }
@@ -63,7 +85,7 @@ fn prints_constants() {
             "COMMON_NODE_HEADER_SIZE: 8",
             "LEAF_NODE_HEADER_SIZE: 16",
             "LEAF_NODE_CELL_SIZE: 311",
-            "LEAF_NODE_SPACE_FOR_CELLS: 4080",
+            "LEAF_NODE_SPACE_FOR_CELLS: 4076",
             "LEAF_NODE_MAX_CELLS: 13",
             "db > ",
         ]
@@ -97,6 +119,51 @@ fn prints_structure_of_one_node_btree() {
     );
 }
 
+#[test]
+fn checks_integrity() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=30)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from("delete where id between 5 and 25"));
+    input.push(String::from(".check"));
+    input.push(String::from(".exit"));
+    let output = db.run_script(input);
+    assert_eq!(output[31..], vec!["db > ok", "db > "]);
+}
+
+#[test]
+fn analyzes_space() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=14)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from(".analyze"));
+    input.push(String::from(".analyze json"));
+    input.push(String::from(".exit"));
+    let output = db.run_script(input);
+    assert_eq!(
+        output[14..20],
+        vec![
+            "db > File: 16384 bytes, 4 pages of 4096 bytes, 0 free",
+            "Level 0: 1 internal (1 keys), 0 leaf (0 cells), 4068.0 unused bytes per page",
+            "Level 1: 0 internal (0 keys), 2 leaf (14 cells), 1899.0 unused bytes per page",
+            "Leaves: 53.8% full on average, out of 13 cells each",
+            "Internal nodes: 2.0 children on average",
+            "Unused: 2622.0 bytes per page on average",
+        ]
+    );
+
+    let json = output[20..output.len() - 1].join("\n");
+    let report: serde_json::Value =
+        serde_json::from_str(json.strip_prefix("db > ").unwrap()).unwrap();
+    assert_eq!(report["page_count"], 4);
+    assert_eq!(report["levels"][1]["leaf_pages"], 2);
+    assert_eq!(report["levels"][1]["cells"], 14);
+    assert_eq!(report["max_cells"], 13);
+    assert_eq!(report["fan_out"], 2.0);
+}
+
 #[test]
 fn prints_structure_of_three_node_btree() {
     let db = Database::new();
@@ -152,3 +219,480 @@ fn print_all_rows_in_a_multi_level_tree() {
     expected.push(String::from("db > "));
     assert_eq!(&output[15..], &expected);
 }
+
+#[test]
+fn prints_structure_after_splitting_internal_node() {
+    let db = Database::new();
+    // Ascending inserts leave 7 rows in each leaf, and the root fits 511 children.
+    let mut input: Vec<_> = (1..=4000)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from(".btree"));
+    input.push(String::from(".exit"));
+    let output = db.run_script(input);
+
+    assert_eq!(output[4000], "db > Tree:");
+    let top_levels: Vec<_> = output[4001..]
+        .iter()
+        .filter(|line| !line.starts_with("    "))
+        .collect();
+    assert_eq!(
+        top_levels,
+        vec![
+            "- internal (size 1)",
+            "  - internal (size 255)",
+            "  - key 1792",
+            "  - internal (size 314)",
+            "db > ",
+        ]
+    );
+    assert_eq!(output[4003], "    - leaf (size 7)");
+}
+
+#[test]
+fn deletes_rows() {
+    let db = Database::new();
+    let input = vec![
+        "insert 1 user1 person1@example.com",
+        "insert 2 user2 person2@example.com",
+        "insert 3 user3 person3@example.com",
+        "delete where id = 2",
+        "select",
+        "delete where id between 1 and 3",
+        "select",
+        ".exit",
+    ];
+    let output = db.run_script(input);
+    assert_eq!(
+        output,
+        vec![
+            "db > Executed.",
+            "db > Executed.",
+            "db > Executed.",
+            "db > Executed.",
+            "db > (1, user1, person1@example.com)",
+            "(3, user3, person3@example.com)",
+            "Executed.",
+            "db > Executed.",
+            "db > Executed.",
+            "db > ",
+        ]
+    );
+}
+
+#[test]
+fn updates_rows() {
+    let db = Database::new();
+    let input = vec![
+        "insert 1 user1 person1@example.com",
+        "update 1 someone someone@example.com",
+        "update 2 user2 person2@example.com",
+        "select",
+        ".exit",
+    ];
+    let output = db.run_script(input);
+    assert_eq!(
+        output,
+        vec![
+            "db > Executed.",
+            "db > Executed.",
+            "db > Error: Row not found.",
+            "db > (1, someone, someone@example.com)",
+            "Executed.",
+            "db > ",
+        ]
+    );
+}
+
+#[test]
+fn vacuum_shrinks_file() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=100)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from("delete where id > 10"));
+    input.push(String::from(".exit"));
+    db.run_script(input);
+    let size_before = std::fs::metadata(&db.filename).unwrap().len();
+
+    let output = db.run_script(vec!["vacuum", "select", ".exit"]);
+    let size_after = std::fs::metadata(&db.filename).unwrap().len();
+
+    assert_eq!(output[0], "db > Executed.");
+    assert_eq!(output.len(), 1 + 10 + 2);
+    // Just the header page and a root leaf holding all 10 rows
+    assert_eq!(size_after, 2 * 4096);
+    assert!(size_after < size_before);
+
+    let mut temporary = db.filename.clone().into_os_string();
+    temporary.push("-tmp");
+    assert!(!PathBuf::from(temporary).exists());
+}
+
+#[test]
+fn rejects_files_that_are_not_databases() {
+    let db = Database::new();
+    std::fs::write(&db.filename, "just some text\n".repeat(1000)).unwrap();
+    let output = db.run_script(vec![".exit"]);
+    assert_eq!(output, vec!["Error: file is not a database."]);
+    assert!(!journal_path(&db.filename).exists());
+}
+
+fn journal_path(filename: &Path) -> PathBuf {
+    let mut journal = filename.as_os_str().to_owned();
+    journal.push("-journal");
+    PathBuf::from(journal)
+}
+
+#[test]
+fn removes_journal_on_exit() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=100)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from(".exit"));
+    db.run_script(input);
+    assert!(!journal_path(&db.filename).exists());
+
+    let output = db.run_script(vec!["select", ".exit"]);
+    assert_eq!(output.len(), 100 + 2);
+}
+
+fn wal_path(filename: &Path) -> PathBuf {
+    let mut wal = filename.as_os_str().to_owned();
+    wal.push("-wal");
+    PathBuf::from(wal)
+}
+
+#[test]
+fn wal_mode_checkpoints() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=100)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from("checkpoint"));
+    input.push(String::from("delete where id > 10"));
+    input.push(String::from(".exit"));
+    let output = db.run_script_with_args(&["--wal"], input);
+    assert_eq!(output[100], "db > Executed.");
+    assert!(!wal_path(&db.filename).exists());
+    assert!(!journal_path(&db.filename).exists());
+
+    let output = db.run_script(vec!["select", ".exit"]);
+    assert_eq!(output.len(), 10 + 2);
+}
+
+#[test]
+fn mmap_storage() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=100)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from("delete where id > 10"));
+    input.push(String::from(".exit"));
+    let output = db.run_script_with_args(&["--mmap"], input);
+    assert_eq!(output[100], "db > Executed.");
+
+    let output = db.run_script(vec!["select", ".exit"]);
+    assert_eq!(output.len(), 10 + 2);
+}
+
+#[test]
+fn compressed_storage() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=200)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from(".exit"));
+    db.run_script_with_args(&["--compress"], input);
+    assert!(std::fs::metadata(&db.filename).unwrap().len() < 16 * 4096);
+
+    let output = db.run_script_with_args(&["--compress"], vec!["select", ".exit"]);
+    assert_eq!(output.len(), 200 + 2);
+    // Without the flag, the container doesn't look like a database.
+    let output = db.run_script(vec!["select", ".exit"]);
+    assert_eq!(output, vec!["Error: file is not a database."]);
+}
+
+#[test]
+fn encrypted_storage() {
+    let db = Database::new();
+    let run_with_key = |key: &str, commands: Vec<&str>| {
+        let mut command = db.command(&["--encrypt"]);
+        command.env("CSTACK_KEY", key);
+        db.run_command(command, commands)
+    };
+    run_with_key(
+        "secret",
+        vec!["insert 1 user1 person1@example.com", ".exit"],
+    );
+    let contents = std::fs::read(&db.filename).unwrap();
+    assert!(!contents.windows(7).any(|window| window == b"person1"));
+
+    let output = run_with_key("secret", vec!["select", ".exit"]);
+    assert_eq!(
+        output,
+        vec!["db > (1, user1, person1@example.com)", "Executed.", "db > "]
+    );
+    let output = run_with_key("guess", vec!["select", ".exit"]);
+    assert_eq!(output, vec!["Error: wrong encryption key."]);
+}
+
+#[test]
+fn storage_backends_combine() {
+    let db = Database::new();
+    let run_with_key = |commands: Vec<String>| {
+        let mut command = db.command(&["--mmap", "--encrypt", "--compress"]);
+        command.env("CSTACK_KEY", "secret");
+        db.run_command(command, commands)
+    };
+    let mut input: Vec<_> = (1..=200)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from(".exit"));
+    run_with_key(input);
+    let contents = std::fs::read(&db.filename).unwrap();
+    assert!(contents.len() < 16 * 4096);
+    assert!(!contents.windows(7).any(|window| window == b"person1"));
+
+    let output = run_with_key(vec![String::from("select"), String::from(".exit")]);
+    assert_eq!(output.len(), 200 + 2);
+}
+
+#[test]
+fn in_memory_database_rejects_storage_flags() {
+    let output = Command::new(cstack_path())
+        .args([":memory:", "--compress"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Error: --mmap, --compress and --encrypt only apply to database files.\n"
+    );
+}
+
+#[test]
+fn creates_database_with_page_size() {
+    let db = Database::new();
+    let output = db.run_script_with_args(&["--page-size", "1000"], vec![".exit"]);
+    assert_eq!(output, vec!["Error: unsupported page size 1000."]);
+
+    let output = db.run_script_with_args(
+        &["--page-size", "16384"],
+        vec!["insert 1 user1 person1@example.com", ".exit"],
+    );
+    assert_eq!(output, vec!["db > Executed.", "db > "]);
+    // The page size is kept in the header, so it no longer has to be given.
+    let output = db.run_script(vec![".analyze", ".exit"]);
+    assert_eq!(
+        output[0],
+        "db > File: 32768 bytes, 2 pages of 16384 bytes, 0 free"
+    );
+}
+
+#[test]
+fn inspects_pages() {
+    let db = Database::new();
+    let mut input: Vec<_> = (1..=15)
+        .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+        .collect();
+    input.push(String::from(".exit"));
+    db.run_script(input);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cstack-inspect"))
+        .arg(&db.filename)
+        .args(["0", "2", "7"])
+        .output()
+        .unwrap();
+    let output = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    for line in [
+        "Page 0",
+        "  magic: \"cstack format 1\"",
+        "  page count: 4",
+        "  0000  63 73 74 61 63 6b 20 66  6f 72 6d 61 74 20 31 00  |cstack format 1.|",
+        "Page 2",
+        "  common header: type Leaf, root false, parent 1",
+        "  leaf header: 8 of 13 cells, next leaf 0",
+        "  cell 7: key 15, row (15, user15, person15@example.com)",
+        "Page 7 is past the end of the file, which has 4 pages.",
+    ] {
+        assert!(lines.contains(&line), "{line}");
+    }
+    assert!(lines.iter().all(|line| !line.contains("expected")));
+}
+
+#[test]
+fn transactions() {
+    let db = Database::new();
+    let input = vec![
+        "insert 1 user1 person1@example.com",
+        "begin",
+        "insert 2 user2 person2@example.com",
+        "rollback",
+        "begin",
+        "insert 3 user3 person3@example.com",
+        "begin",
+        "commit",
+        "commit",
+        "select",
+        ".exit",
+    ];
+    let output = db.run_script(input);
+    assert_eq!(
+        output,
+        vec![
+            "db > Executed.",
+            "db > Executed.",
+            "db*> Executed.",
+            "db*> Executed.",
+            "db > Executed.",
+            "db*> Executed.",
+            "db*> Error: Cannot start a transaction within a transaction.",
+            "db*> Executed.",
+            "db > Error: No transaction is active.",
+            "db > (1, user1, person1@example.com)",
+            "(3, user3, person3@example.com)",
+            "Executed.",
+            "db > ",
+        ]
+    );
+}
+
+#[test]
+fn savepoints() {
+    let db = Database::new();
+    let input = vec![
+        "savepoint a",
+        "insert 1 user1 person1@example.com",
+        "savepoint b",
+        "insert 2 user2 person2@example.com",
+        "rollback to b",
+        "release c",
+        "release a",
+        "select",
+        ".exit",
+    ];
+    let output = db.run_script(input);
+    assert_eq!(
+        output,
+        vec![
+            "db > Executed.",
+            "db*> Executed.",
+            "db*> Executed.",
+            "db*> Executed.",
+            "db*> Executed.",
+            "db*> Error: No such savepoint.",
+            "db*> Executed.",
+            "db > (1, user1, person1@example.com)",
+            "Executed.",
+            "db > ",
+        ]
+    );
+}
+
+#[test]
+fn pragma_synchronous() {
+    let db = Database::new();
+    let input = vec![
+        "pragma synchronous",
+        "pragma synchronous = normal",
+        "insert 1 user1 person1@example.com",
+        "pragma synchronous",
+        ".exit",
+    ];
+    let output = db.run_script(input);
+    assert_eq!(
+        output,
+        vec![
+            "db > full",
+            "Executed.",
+            "db > Executed.",
+            "db > Executed.",
+            "db > normal",
+            "Executed.",
+            "db > ",
+        ]
+    );
+}
+
+#[test]
+fn concurrent_writers_are_locked_out() {
+    let db = Database::new();
+    db.run_script(vec![".exit"]);
+
+    let mut writer = db.spawn(&[]);
+    let mut stdin = writer.stdin.take().unwrap();
+    let mut stdout = BufReader::new(writer.stdout.take().unwrap());
+    writeln!(stdin, "begin").unwrap();
+    writeln!(stdin, "insert 1 user1 person1@example.com").unwrap();
+    let mut line = String::new();
+    for expected in ["db > Executed.\n", "db*> Executed.\n"] {
+        line.clear();
+        stdout.read_line(&mut line).unwrap();
+        assert_eq!(line, expected);
+    }
+
+    let input = vec![
+        "pragma busy_timeout = 50",
+        "insert 2 user2 person2@example.com",
+        "select",
+        ".exit",
+    ];
+    let output = db.run_script(input);
+    assert_eq!(
+        output,
+        vec![
+            "db > Executed.",
+            "db > Error: database is locked.",
+            "db > Executed.",
+            "db > ",
+        ]
+    );
+
+    writeln!(stdin, "commit").unwrap();
+    writeln!(stdin, ".exit").unwrap();
+    drop(stdin);
+    writer.wait().unwrap();
+
+    let output = db.run_script(vec!["select", ".exit"]);
+    assert_eq!(
+        output,
+        vec!["db > (1, user1, person1@example.com)", "Executed.", "db > "]
+    );
+    assert!(!journal_path(&db.filename).exists());
+}
+
+#[test]
+fn in_memory_database() {
+    let input = "insert 1 user1 person1@example.com\nselect\n.exit\n";
+    let mut process = Command::new(cstack_path())
+        .arg(":memory:")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    process
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = process.wait_with_output().unwrap();
+    let output: Vec<_> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(
+        output,
+        vec![
+            "db > Executed.",
+            "db > (1, user1, person1@example.com)",
+            "Executed.",
+            "db > ",
+        ]
+    );
+    assert!(!Path::new(":memory:").exists());
+    assert!(!journal_path(Path::new(":memory:")).exists());
+}